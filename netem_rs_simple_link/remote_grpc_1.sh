@@ -9,33 +9,23 @@ _print_help() {
 }
 
 up() {
-    sudo ip link add veth0 type veth peer name veth1
-    sudo ip netns add vnet0
-    sudo ifconfig veth0 hw ether aa:00:00:00:00:00
-    sudo ip link set veth0 netns vnet0
-    sudo ip -n vnet0 link set veth0 up
-    sudo ip link set veth1 up
-    sudo ip -n vnet0 addr add 10.0.0.1/24 dev veth0
+    cargo build --release
 
-    # off the rx check
-    sudo ip netns exec vnet0 ethtool --offload veth0 rx off tx off
+    # the namespace behind the node, see the host array of remote_env.toml
+    sudo ./target/release/testenv up remote_env.toml --runtime host1 || exit 1
 
-    cargo build --release
+    # the meta service the nodes register with, on the first node
+    ./target/release/meta &
 
-    sudo ./target/release/remote --listen-addr 0.0.0.0:10000 --meta-address 127.0.0.1:5688
-    ./target/release/create_actor1
+    sudo NETEM="$NETEM" SHAPER="$SHAPER" ./target/release/remote --listen-addr 0.0.0.0:10000 --meta-address "${META_ADDRESS:-127.0.0.1:5688}"
+    ./target/release/actor create --runtime 10.0.0.44:10000 --if-name veth1 --mac aa:00:00:00:00:00
 }
 
 down() {
-    # down env
-    sudo ip -n vnet0 link set veth0 down
-    sudo ip link set veth1 down
-
-    # rm interface
-    sudo ip link del veth1
+    sudo ./target/release/testenv down remote_env.toml --runtime host1
 
-    # rm netns
-    sudo ip netns del vnet0
+    # stop the meta service
+    pkill -x meta
 }
 
 
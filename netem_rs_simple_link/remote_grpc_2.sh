@@ -9,33 +9,17 @@ _print_help() {
 }
 
 up() {
-    sudo ip link add veth0 type veth peer name veth1
-    sudo ip netns add vnet0
-    sudo ifconfig veth0 hw ether aa:00:00:00:00:01
-    sudo ip link set veth0 netns vnet0
-    sudo ip -n vnet0 link set veth0 up
-    sudo ip link set veth1 up
-    sudo ip -n vnet0 addr add 10.0.0.2/24 dev veth0
-
-    # off the rx check
-    sudo ip netns exec vnet0 ethtool --offload veth0 rx off tx off
-
     cargo build --release
 
-    sudo ./target/release/remote --listen-addr 0.0.0.0:10000 --meta-address 127.0.0.1:5688
-    ./target/release/create_actor2
+    # the namespace behind the node, see the host array of remote_env.toml
+    sudo ./target/release/testenv up remote_env.toml --runtime host2 || exit 1
+
+    sudo NETEM="$NETEM" SHAPER="$SHAPER" ./target/release/remote --listen-addr 0.0.0.0:10000 --meta-address "${META_ADDRESS:-10.0.0.44:5688}"
+    ./target/release/actor create --runtime 10.0.0.45:10000 --if-name veth1 --mac aa:00:00:00:00:01
 }
 
 down() {
-    # down env
-    sudo ip -n vnet0 link set veth0 down
-    sudo ip link set veth1 down
-
-    # rm interface
-    sudo ip link del veth1
-
-    # rm netns
-    sudo ip netns del vnet0
+    sudo ./target/release/testenv down remote_env.toml --runtime host2
 }
 
 
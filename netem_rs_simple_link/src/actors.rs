@@ -0,0 +1,235 @@
+//! Management of the actors of a runtime, for the `actor` command: they are
+//! created through the `ActorService` of the runtime, and listed and
+//! deleted through the `ControlService` that `remote` serves next to it.
+
+use anyhow::{anyhow, Context};
+use hwaddr::HwAddr;
+use netem_rs::proto::{actor_service_client::ActorServiceClient, CreateActorRequest};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    control::proto::{
+        control_service_client::ControlServiceClient, ActorStats, DeleteActorRequest,
+        GetActorStatsRequest, GetLinkRequest, LinkImpairments, LinkStats, SetLinkRequest,
+    },
+    topology::NodeConfig,
+};
+
+/// Ports of the `ActorService` and the `ControlService` of a runtime, as
+/// the scripts start it.
+pub const RUNTIME_PORT: u16 = 10000;
+pub const CONTROL_PORT: u16 = 10001;
+
+/// The URL of the service at `addr`, written as `host`, `host:port` or a
+/// URL, on `default_port` if `addr` has none.
+pub fn endpoint(addr: &str, default_port: u16) -> String {
+    let (scheme, rest) = match addr.split_once("://") {
+        Some((scheme, rest)) => (scheme, rest),
+        None => ("http", addr),
+    };
+    // IPv6 hosts are in brackets, and their colons are not a port.
+    let has_port = match rest.rsplit_once(':') {
+        Some((host, port)) => !port.is_empty() && (!host.contains(':') || host.ends_with(']')),
+        None => false,
+    };
+    if has_port {
+        format!("{scheme}://{rest}")
+    } else {
+        format!("{scheme}://{rest}:{default_port}")
+    }
+}
+
+/// The `ControlService` of the runtime at `runtime`: on the same host, on
+/// `CONTROL_PORT`.
+pub fn control_endpoint(runtime: &str) -> String {
+    let url = endpoint(runtime, RUNTIME_PORT);
+    let (host, _) = url.rsplit_once(':').unwrap();
+    format!("{host}:{CONTROL_PORT}")
+}
+
+/// The request creating the actor of the port of `node`.
+pub fn create_request(node: &NodeConfig) -> anyhow::Result<CreateActorRequest> {
+    let mac: HwAddr = node
+        .mac_addr
+        .parse()
+        .map_err(|_| anyhow!("invalid mac {}", node.mac_addr))?;
+    Ok(CreateActorRequest {
+        if_name: node.if_name.clone(),
+        queue_id: node.queue_id,
+        port_type: node.port_type.clone(),
+        mac_addr: mac.octets().to_vec(),
+    })
+}
+
+/// Create the actor of the port of `node` on the runtime at `runtime`.
+pub async fn create_actor(runtime: &str, node: &NodeConfig) -> anyhow::Result<()> {
+    let request = create_request(node)?;
+    let url = endpoint(runtime, RUNTIME_PORT);
+    let mut client = ActorServiceClient::connect(url.clone())
+        .await
+        .with_context(|| format!("failed to connect to {url}"))?;
+    client
+        .create_actor(request)
+        .await
+        .with_context(|| format!("failed to create the actor of {} on {url}", node.if_name))?;
+    Ok(())
+}
+
+async fn control_client(
+    control: &str,
+) -> anyhow::Result<ControlServiceClient<tonic::transport::Channel>> {
+    let url = endpoint(control, CONTROL_PORT);
+    ControlServiceClient::connect(url.clone())
+        .await
+        .with_context(|| format!("failed to connect to {url}"))
+}
+
+/// The statistics of every actor of the runtime whose `ControlService` is
+/// at `control`.
+pub async fn actor_stats(control: &str) -> anyhow::Result<Vec<ActorStats>> {
+    let response = control_client(control)
+        .await?
+        .get_actor_stats(GetActorStatsRequest {})
+        .await?;
+    Ok(response.into_inner().actors)
+}
+
+pub async fn delete_actor(control: &str, id: u64) -> anyhow::Result<()> {
+    control_client(control)
+        .await?
+        .delete_actor(DeleteActorRequest { id })
+        .await
+        .with_context(|| format!("failed to delete actor {id}"))?;
+    Ok(())
+}
+
+fn link_request(link: Option<(HwAddr, HwAddr)>) -> (Vec<u8>, Vec<u8>) {
+    match link {
+        Some((source, destination)) => (source.octets().to_vec(), destination.octets().to_vec()),
+        None => (Vec::new(), Vec::new()),
+    }
+}
+
+fn link_name(link: Option<(HwAddr, HwAddr)>) -> String {
+    match link {
+        Some((source, destination)) => format!("the link {source} -> {destination}"),
+        None => "the default link".to_string(),
+    }
+}
+
+/// The impairments of the link from a source to a destination, or of the
+/// links without impairments of their own for `None`, in the runtime whose
+/// `ControlService` is at `control`.
+pub async fn get_link(
+    control: &str,
+    link: Option<(HwAddr, HwAddr)>,
+) -> anyhow::Result<LinkImpairments> {
+    let (source, destination) = link_request(link);
+    let response = control_client(control)
+        .await?
+        .get_link(GetLinkRequest {
+            source,
+            destination,
+        })
+        .await
+        .with_context(|| format!("failed to get {}", link_name(link)))?;
+    Ok(response.into_inner().impairments.unwrap_or_default())
+}
+
+pub async fn set_link(
+    control: &str,
+    link: Option<(HwAddr, HwAddr)>,
+    impairments: LinkImpairments,
+) -> anyhow::Result<()> {
+    let (source, destination) = link_request(link);
+    control_client(control)
+        .await?
+        .set_link(SetLinkRequest {
+            source,
+            destination,
+            impairments: Some(impairments),
+        })
+        .await
+        .with_context(|| format!("failed to set {}", link_name(link)))?;
+    Ok(())
+}
+
+/// A MAC as sent by the control service, written like `aa:00:00:00:00:01`.
+pub fn format_mac(bytes: &[u8]) -> String {
+    match <[u8; 6]>::try_from(bytes) {
+        Ok(octets) => HwAddr::from(octets).to_string(),
+        Err(_) => format!("{bytes:02x?}"),
+    }
+}
+
+macro_rules! counters {
+    ($map:ident, $stats:expr, $($field:ident),* $(,)?) => {
+        $(
+            $map.insert(stringify!($field).to_string(), json!($stats.$field));
+        )*
+    };
+}
+
+fn link_json(link: &LinkStats) -> Value {
+    let mut map = Map::new();
+    map.insert("source".to_string(), json!(format_mac(&link.source)));
+    map.insert(
+        "destination".to_string(),
+        json!(format_mac(&link.destination)),
+    );
+    counters!(
+        map,
+        link,
+        enqueued,
+        dropped,
+        duplicated,
+        corrupted,
+        reordered,
+        lost,
+        loss_bursts,
+        max_loss_burst,
+        down_drops,
+        oversize_drops,
+        sent,
+        sent_bytes,
+        qdisc_dropped,
+        qdisc_marked,
+        backlog,
+        backlog_bytes,
+    );
+    Value::Object(map)
+}
+
+/// The statistics of an actor as a JSON object, with the counters named
+/// like in `proto/control.proto` and the MACs written out.
+pub fn actor_json(stats: &ActorStats) -> Value {
+    let mut map = Map::new();
+    map.insert("id".to_string(), json!(stats.id));
+    let sources: Vec<_> = stats.sources.iter().map(|mac| format_mac(mac)).collect();
+    map.insert("sources".to_string(), json!(sources));
+    counters!(
+        map,
+        stats,
+        rx_frames,
+        rx_bytes,
+        tx_frames,
+        tx_bytes,
+        drops,
+        broadcast,
+        unknown_destination,
+        too_short,
+        bad_ethertype,
+        truncated,
+        vlan_drops,
+        blocked,
+        bad_checksum,
+        no_route,
+        ttl_expired,
+        unresolved,
+        too_big,
+        icmp_errors,
+    );
+    let links: Vec<_> = stats.links.iter().map(link_json).collect();
+    map.insert("links".to_string(), Value::Array(links));
+    Value::Object(map)
+}
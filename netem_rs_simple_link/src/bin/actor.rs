@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail};
+use netem_rs_simple_link::{
+    actors::{self, actor_json, format_mac},
+    control::proto::ActorStats,
+    topology::{NodeConfig, Topology},
+};
+use serde_json::json;
+
+const USAGE: &str = "\
+usage: actor <command> [options] [--json]
+
+commands:
+  create --runtime ADDR [--if-name veth1] [--queue-id 0] [--port-type xdp] --mac MAC
+  create --runtime ADDR --file TOPOLOGY [--node NAME]
+  list [--runtime ADDR | --control ADDR]
+  describe ID [--runtime ADDR | --control ADDR]
+  delete ID [--runtime ADDR | --control ADDR]
+
+ADDR is host or host:port, the runtime on port 10000 and its control
+service on port 10001 by default.";
+
+/// The command line: the command, its positional arguments and its
+/// `--name value` options.
+struct Args {
+    command: String,
+    positional: Vec<String>,
+    options: HashMap<String, String>,
+    json: bool,
+}
+
+impl Args {
+    fn parse() -> anyhow::Result<Self> {
+        let mut args = std::env::args().skip(1);
+        let command = args.next().ok_or_else(|| anyhow!("no command"))?;
+        let mut positional = Vec::new();
+        let mut options = HashMap::new();
+        let mut json = false;
+        while let Some(arg) = args.next() {
+            match arg.strip_prefix("--") {
+                Some("json") => json = true,
+                Some(name) => {
+                    let value = args.next().ok_or_else(|| anyhow!("{arg} needs a value"))?;
+                    options.insert(name.to_string(), value);
+                }
+                None => positional.push(arg),
+            }
+        }
+        Ok(Args {
+            command,
+            positional,
+            options,
+            json,
+        })
+    }
+
+    fn option(&self, name: &str) -> Option<&str> {
+        self.options.get(name).map(String::as_str)
+    }
+
+    fn required(&self, name: &str) -> anyhow::Result<&str> {
+        self.option(name)
+            .ok_or_else(|| anyhow!("{} needs --{name}", self.command))
+    }
+
+    /// The control service to ask, given directly or as the runtime next
+    /// to it.
+    fn control(&self) -> String {
+        match (self.option("control"), self.option("runtime")) {
+            (Some(control), _) => control.to_string(),
+            (None, Some(runtime)) => actors::control_endpoint(runtime),
+            (None, None) => "127.0.0.1".to_string(),
+        }
+    }
+
+    fn id(&self) -> anyhow::Result<u64> {
+        let id = self
+            .positional
+            .first()
+            .ok_or_else(|| anyhow!("{} needs an actor id", self.command))?;
+        id.parse().map_err(|_| anyhow!("invalid actor id {id}"))
+    }
+}
+
+/// The ports to create, from the topology file or from the options.
+fn nodes(args: &Args) -> anyhow::Result<Vec<(String, NodeConfig)>> {
+    if let Some(path) = args.option("file") {
+        let mut topology = Topology::load(path)?;
+        return match args.option("node") {
+            Some(name) => {
+                let node = topology
+                    .nodes
+                    .remove(name)
+                    .ok_or_else(|| anyhow!("unknown node {name} in {path}"))?;
+                Ok(vec![(name.to_string(), node)])
+            }
+            None => Ok(topology.nodes.into_iter().collect()),
+        };
+    }
+    let queue_id = match args.option("queue-id") {
+        Some(id) => id.parse().map_err(|_| anyhow!("invalid queue id {id}"))?,
+        None => 0,
+    };
+    let node = NodeConfig {
+        port_type: args.option("port-type").unwrap_or("xdp").to_string(),
+        if_name: args.option("if-name").unwrap_or("veth1").to_string(),
+        queue_id,
+        mac_addr: args.required("mac")?.to_string(),
+        runtime: None,
+    };
+    Ok(vec![(node.if_name.clone(), node)])
+}
+
+async fn create(args: &Args) -> anyhow::Result<()> {
+    let runtime = args.required("runtime")?;
+    let nodes = nodes(args)?;
+    if nodes.is_empty() {
+        bail!("no node to create");
+    }
+    let mut created = Vec::new();
+    for (name, node) in &nodes {
+        actors::create_actor(runtime, node).await?;
+        if !args.json {
+            println!(
+                "created {name}: {} queue {} ({}) as {}",
+                node.if_name, node.queue_id, node.port_type, node.mac_addr
+            );
+        }
+        created.push(json!({
+            "name": name,
+            "if_name": node.if_name,
+            "queue_id": node.queue_id,
+            "port_type": node.port_type,
+            "mac_addr": node.mac_addr,
+        }));
+    }
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&created)?);
+    }
+    Ok(())
+}
+
+fn print_table(actors: &[ActorStats]) {
+    println!(
+        "{:>6}  {:<18}  {:>10}  {:>10}  {:>8}  {:>5}",
+        "ID", "SOURCES", "RX", "TX", "DROPS", "LINKS"
+    );
+    for actor in actors {
+        let sources: Vec<_> = actor.sources.iter().map(|mac| format_mac(mac)).collect();
+        let sources = if sources.is_empty() {
+            "-".to_string()
+        } else {
+            sources.join(",")
+        };
+        println!(
+            "{:>6}  {:<18}  {:>10}  {:>10}  {:>8}  {:>5}",
+            actor.id,
+            sources,
+            actor.rx_frames,
+            actor.tx_frames,
+            actor.drops,
+            actor.links.len()
+        );
+    }
+}
+
+fn print_actor(actor: &ActorStats) {
+    let json = actor_json(actor);
+    let sources: Vec<_> = actor.sources.iter().map(|mac| format_mac(mac)).collect();
+    println!("sources: {}", sources.join(", "));
+    for (name, value) in json.as_object().unwrap() {
+        if name != "links" && name != "sources" {
+            println!("{name}: {value}");
+        }
+    }
+    for link in json["links"].as_array().unwrap() {
+        let link = link.as_object().unwrap();
+        println!(
+            "link {} -> {}:",
+            link["source"].as_str().unwrap(),
+            link["destination"].as_str().unwrap()
+        );
+        for (name, value) in link {
+            if name != "source" && name != "destination" {
+                println!("  {name}: {value}");
+            }
+        }
+    }
+}
+
+async fn list(args: &Args) -> anyhow::Result<()> {
+    let actors = actors::actor_stats(&args.control()).await?;
+    if args.json {
+        let actors: Vec<_> = actors.iter().map(actor_json).collect();
+        println!("{}", serde_json::to_string_pretty(&actors)?);
+    } else {
+        print_table(&actors);
+    }
+    Ok(())
+}
+
+async fn describe(args: &Args) -> anyhow::Result<()> {
+    let id = args.id()?;
+    let actor = actors::actor_stats(&args.control())
+        .await?
+        .into_iter()
+        .find(|actor| actor.id == id)
+        .ok_or_else(|| anyhow!("no actor {id}"))?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&actor_json(&actor))?);
+    } else {
+        print_actor(&actor);
+    }
+    Ok(())
+}
+
+async fn delete(args: &Args) -> anyhow::Result<()> {
+    let id = args.id()?;
+    actors::delete_actor(&args.control(), id).await?;
+    if args.json {
+        println!("{}", json!({ "deleted": id }));
+    } else {
+        println!("deleted actor {id}");
+    }
+    Ok(())
+}
+
+async fn run() -> anyhow::Result<()> {
+    let args = Args::parse().map_err(|e| anyhow!("{e}\n\n{USAGE}"))?;
+    match args.command.as_str() {
+        "create" => create(&args).await,
+        "list" => list(&args).await,
+        "describe" => describe(&args).await,
+        "delete" => delete(&args).await,
+        "help" | "-h" | "--help" => {
+            println!("{USAGE}");
+            Ok(())
+        }
+        command => bail!("unknown command {command}\n\n{USAGE}"),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    if let Err(e) = run().await {
+        eprintln!("error: {e:#}");
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,46 @@
+use anyhow::anyhow;
+use netem_rs_simple_link::{deploy, topology::Topology};
+
+const USAGE: &str = "\
+usage: deploy <up|check|down> TOPOLOGY
+
+  up     create the actors of the nodes on their runtimes and set the links,
+         removing them again if any of it fails
+  check  check that the runtimes run their actors and have the links set
+  down   delete every actor of the runtimes and reset the links";
+
+async fn run() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let (command, path) = match (args.next(), args.next()) {
+        (Some(command), Some(path)) => (command, path),
+        _ => return Err(anyhow!("{USAGE}")),
+    };
+    let topology = Topology::load(&path)?;
+    let plan = deploy::plan(&topology)?;
+    let nodes: usize = plan.iter().map(|runtime| runtime.nodes.len()).sum();
+    match command.as_str() {
+        "up" => {
+            deploy::up(&plan).await?;
+            println!("deployed {nodes} nodes on {} runtimes", plan.len());
+        }
+        "check" => {
+            deploy::check(&plan).await?;
+            println!("{nodes} nodes on {} runtimes are up", plan.len());
+        }
+        "down" => {
+            deploy::down(&plan).await?;
+            println!("tore down {} runtimes", plan.len());
+        }
+        command => return Err(anyhow!("unknown command {command}\n\n{USAGE}")),
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    if let Err(e) = run().await {
+        eprintln!("error: {e:#}");
+        std::process::exit(1);
+    }
+}
@@ -1,70 +1,72 @@
-use log::error;
-use netem_rs::{Actor, ActorContext, DataView, LocalRunTime};
-use packet::ether::Packet;
-use smallvec::smallvec;
+use std::{ffi::OsString, os::unix::process::CommandExt, process::Command};
 
-#[derive(Clone)]
-struct EmptyDataView;
+use anyhow::Context;
+use netem_rs::LocalRunTime;
+use netem_rs_simple_link::{
+    forward::{init_forward_config, ForwardActor, ForwardConfig},
+    router::{init_router_config, RouterActor, RouterConfig},
+    topology::{topology_path, Scenario, Topology},
+};
 
-impl DataView for EmptyDataView {
-    fn new() -> Self {
-        Self
-    }
-}
+/// The topology file, in the runtime started on the file of its nodes.
+const EXPERIMENT: &str = "EXPERIMENT";
 
-struct ForwardActor {
-    context: ActorContext<EmptyDataView>,
-}
-
-impl ForwardActor {
-    fn new(context: ActorContext<EmptyDataView>) -> Self {
-        Self { context }
+/// Run again with `-t` giving the node tables of the topology file at
+/// `path`, which the runtime reads, and `EXPERIMENT` giving the topology.
+fn exec_on_nodes(path: &str) -> anyhow::Result<()> {
+    let topology = Topology::load(path)?;
+    let nodes = std::env::temp_dir().join(format!("netem_rs_nodes_{}.toml", std::process::id()));
+    std::fs::write(&nodes, topology.nodes_toml()?)
+        .with_context(|| format!("failed to write {}", nodes.display()))?;
+    let mut args: Vec<OsString> = std::env::args_os().skip(1).collect();
+    if let Some(i) = args.iter().position(|arg| arg == "-t") {
+        args[i + 1] = nodes.into_os_string();
     }
+    let error = Command::new(std::env::current_exe()?)
+        .args(args)
+        .env(EXPERIMENT, path)
+        .exec();
+    Err(error.into())
 }
 
-impl Actor for ForwardActor {
-    type C = EmptyDataView;
-
-    fn new(context: ActorContext<Self::C>) -> Self {
-        Self::new(context)
-    }
-
-    async fn run(&mut self) -> anyhow::Result<()> {
-        let self_port_id = self.context.receive_handle.port_id();
-        loop {
-            let frames = self.context.receive_handle.receive_frames().await?;
-            for frame in frames {
-                let packet = Packet::new(frame.data_ref()).unwrap();
-                if packet.destination().is_broadcast() {
-                    self.context
-                        .port_table
-                        .for_each_port(|&port_id, send_handle| {
-                            if port_id != self_port_id {
-                                send_handle.send_raw_data(frame.data_ref().to_vec())
-                            } else {
-                                Ok(())
-                            }
-                        })
-                        .await?;
-                } else {
-                    if let Some(handle) = self
-                        .context
-                        .port_table
-                        .get_send_handle(packet.destination())
-                        .await
-                    {
-                        handle.send_frame(smallvec![frame])?;
-                    } else {
-                        error!("no send handle for {:?}", packet);
-                    }
-                }
+fn configs(path: Option<String>) -> anyhow::Result<(ForwardConfig, RouterConfig)> {
+    let mut config = ForwardConfig::from_env()?;
+    let mut router = RouterConfig::default();
+    if let Some(path) = path {
+        let topology = Topology::load(path)?;
+        config.nodes = topology.node_macs()?;
+        config.links = topology.link_params()?;
+        config.static_macs = topology.static_macs()?;
+        config.vlans = topology.vlan_config()?;
+        router = topology.router_config()?;
+        let mut events = topology.events.clone();
+        if let Ok(path) = std::env::var("SCENARIO") {
+            if !path.is_empty() {
+                events.extend(Scenario::load(path)?.events);
             }
         }
+        config.changes = topology.link_changes(&events)?;
     }
+    Ok((config, router))
 }
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
-    LocalRunTime::start::<ForwardActor>().await;
+    let experiment = std::env::var(EXPERIMENT).ok();
+    if experiment.is_none() {
+        if let Some(path) = topology_path() {
+            exec_on_nodes(&path).unwrap();
+        }
+    }
+    let (config, router) = configs(experiment).unwrap();
+    init_forward_config(config).unwrap();
+    // The nodes are routed when the topology gives the router interfaces,
+    // and bridged otherwise.
+    if router.is_enabled() {
+        init_router_config(router).unwrap();
+        LocalRunTime::start::<RouterActor>().await;
+    } else {
+        LocalRunTime::start::<ForwardActor>().await;
+    }
 }
@@ -0,0 +1,18 @@
+use std::time::Duration;
+
+use netem_rs_simple_link::{meta, netem::parse_duration};
+
+/// How long a node stays registered without a heartbeat, 5 heartbeats.
+const DEFAULT_TTL: Duration = Duration::from_secs(10);
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let meta_addr = std::env::var("META_ADDR").unwrap_or_else(|_| "0.0.0.0:5688".to_string());
+    let ttl = match std::env::var("META_TTL") {
+        Ok(ttl) => parse_duration(&ttl).unwrap(),
+        Err(_) => DEFAULT_TTL,
+    };
+    assert!(!ttl.is_zero(), "META_TTL must not be 0");
+    meta::serve(meta_addr.parse().unwrap(), ttl).await.unwrap();
+}
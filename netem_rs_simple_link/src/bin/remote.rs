@@ -3,114 +3,121 @@
 
 use std::collections::HashMap;
 
-use log::{error, trace};
-use netem_rs::{Actor, ActorContext, DataView, HostAddr, MetaClient, NodeInfo, RemtoeRuntime};
-use packet::ether::Packet;
+use hwaddr::HwAddr;
+use log::{error, info, warn};
+use netem_rs::{DataView, NodeInfo, RemtoeRuntime};
+use netem_rs_simple_link::{
+    control::{self, ControlView},
+    forward::{init_forward_config, ForwardActor, ForwardConfig},
+    meta::{self, GrpcMetaClient, MembershipChange},
+    topology::Topology,
+};
+use tokio::sync::broadcast::{error::RecvError, Receiver};
 
-#[derive(Clone)]
-struct EmptyDataView;
+/// The ports of the peers of this node, keyed by their address. A peer's
+/// port is the one registered with the MAC of its Ethernet interface, so
+/// peers without one, as in gRPC mode, have none.
+type Peers = HashMap<(String, u16), HwAddr>;
 
-impl DataView for EmptyDataView {
-    fn new() -> Self {
-        Self
+/// Put the port of a node that joined in the bridge.
+fn join(control: &ControlView, peers: &mut Peers, node: &NodeInfo) {
+    if !meta::has_port(node) {
+        warn!(
+            "{}:{} has no port in the runtime, restart this node to reach it",
+            node.addr.host, node.addr.port
+        );
     }
-}
-
-struct ForwardActor {
-    context: ActorContext<EmptyDataView>,
-}
-
-impl ForwardActor {
-    fn new(context: ActorContext<EmptyDataView>) -> Self {
-        Self { context }
+    let Some(mac) = node.eth_mac_addr else {
+        return;
+    };
+    let key = (node.addr.host.clone(), node.addr.port);
+    // A node that registered again may come back with another interface.
+    if let Some(old) = peers.insert(key, mac).filter(|&old| old != mac) {
+        control.remove_port(Some(old), None);
     }
+    control.restore_port(mac);
 }
 
-impl Actor for ForwardActor {
-    type C = EmptyDataView;
-
-    fn new(context: ActorContext<Self::C>) -> Self {
-        Self::new(context)
+/// Take the port of a node that left out of the bridge.
+fn leave(control: &ControlView, peers: &mut Peers, node: &NodeInfo) {
+    let key = (node.addr.host.clone(), node.addr.port);
+    if let Some(mac) = peers.remove(&key).or(node.eth_mac_addr) {
+        control.remove_port(Some(mac), None);
     }
+}
 
-    async fn run(&mut self) -> anyhow::Result<()> {
-        let self_port_id = self.context.receive_handle.port_id();
-        loop {
-            let frames = self.context.receive_handle.receive_frames().await?;
-            for frame in frames {
-                let packet = Packet::new(frame.data_ref()).unwrap();
-                if packet.destination().is_broadcast() {
-                    trace!("broadcast");
-                    self.context
-                        .port_table
-                        .for_each_port(|&port_id, send_handle| {
-                            if port_id != self_port_id {
-                                send_handle.send_raw_data(frame.data_ref().to_vec())
-                            } else {
-                                Ok(())
+/// Follow the nodes joining and leaving the cluster. The port of a node that
+/// joins is put back in the bridge, and the port of a node that leaves taken
+/// out of it. A node that joins for the first time has no port to put back. The addresses learned are forgotten when a node leaves, so that
+/// frames for them are flooded to the remaining nodes until they are
+/// learned again. When changes were missed, the nodes are read again from
+/// the meta service.
+async fn follow_membership(mut membership: Receiver<MembershipChange>) {
+    let control = ControlView::new();
+    let mut peers = Peers::new();
+    loop {
+        match membership.recv().await {
+            Ok(MembershipChange::Joined(node)) => {
+                info!("{}:{} joined", node.addr.host, node.addr.port);
+                join(&control, &mut peers, &node);
+            }
+            Ok(MembershipChange::Left(node)) => {
+                info!("{}:{} left", node.addr.host, node.addr.port);
+                leave(&control, &mut peers, &node);
+                control.mac_table().flush();
+            }
+            Err(RecvError::Lagged(missed)) => {
+                warn!("missed {missed} membership changes, reading the nodes again");
+                match meta::peers().await {
+                    Ok(nodes) => {
+                        let mut current = Peers::new();
+                        for node in &nodes {
+                            join(&control, &mut current, node);
+                        }
+                        for (key, mac) in &peers {
+                            if current.get(key) != Some(mac) {
+                                control.remove_port(Some(*mac), None);
                             }
-                        })
-                        .await?;
-                } else {
-                    if let Some(handle) = self
-                        .context
-                        .port_table
-                        .get_send_handle(packet.destination())
-                        .await
-                    {
-                        handle.send_frame(smallvec::smallvec![frame])?;
-                    } else {
-                        error!("no send handle for {:?}", packet);
+                        }
+                        peers = current;
                     }
+                    Err(e) => error!("failed to read the nodes of the cluster: {e:#}"),
                 }
+                control.mac_table().flush();
             }
+            Err(RecvError::Closed) => return,
         }
     }
 }
 
-// MockMetaClient is a mock implementation of MetaClient which return the predefine info.
-pub struct MockMetaClient {
-    inner: HashMap<HostAddr, Vec<NodeInfo>>,
-}
-
-impl MetaClient for MockMetaClient {
-    fn connet(_meta_addr: HostAddr) -> Self {
-        MockMetaClient {
-            inner: HashMap::from([
-                (
-                    HostAddr {
-                        host: "10.0.0.44".to_string(),
-                        port: 10000,
-                    },
-                    vec![],
-                ),
-                (
-                    HostAddr {
-                        host: "10.0.0.45".to_string(),
-                        port: 10000,
-                    },
-                    vec![NodeInfo {
-                        addr: HostAddr {
-                            host: "10.0.0.44".to_string(),
-                            port: 10000,
-                        },
-                        eth_mac_addr: Some("9c:69:b4:61:c0:b1".parse().unwrap()),
-                        xdp_subnet_id: 1,
-                    }],
-                ),
-            ]),
+/// The configuration from the environment, with the VLAN modes of the ports
+/// of the topology file given by `TOPOLOGY`, if any.
+fn config() -> anyhow::Result<ForwardConfig> {
+    let mut config = ForwardConfig::from_env()?;
+    if let Ok(path) = std::env::var("TOPOLOGY") {
+        if !path.is_empty() {
+            let topology = Topology::load(path)?;
+            config.vlans = topology.vlan_config()?;
+            config.vlan_ports = topology.node_macs()?;
         }
     }
-
-    async fn register(&self, addr: HostAddr) -> Vec<NodeInfo> {
-        self.inner.get(&addr).unwrap().clone()
-    }
+    Ok(config)
 }
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
-    RemtoeRuntime::start::<ForwardActor, MockMetaClient>()
+    init_forward_config(config().unwrap()).unwrap();
+    let control_addr =
+        std::env::var("CONTROL_ADDR").unwrap_or_else(|_| "0.0.0.0:10001".to_string());
+    let control_addr = control_addr.parse().unwrap();
+    tokio::spawn(async move {
+        if let Err(e) = control::serve(control_addr).await {
+            error!("control service failed: {e:#}");
+        }
+    });
+    tokio::spawn(follow_membership(meta::membership()));
+    RemtoeRuntime::start::<ForwardActor, GrpcMetaClient>()
         .await
         .unwrap();
 }
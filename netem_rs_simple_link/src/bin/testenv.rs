@@ -0,0 +1,69 @@
+use anyhow::anyhow;
+use netem_rs_simple_link::{
+    testenv::{self, HostState},
+    topology::Topology,
+};
+
+const USAGE: &str = "\
+usage: testenv <up|down|status> TOPOLOGY [--runtime NAME]
+
+  up      create the hosts of the nodes, or configure them again if they are
+          up, deleting their leftovers first and everything created if it fails
+  down    delete the hosts of the nodes
+  status  show what is there of each host
+
+With --runtime, only the hosts of the nodes of that runtime.";
+
+async fn run() -> anyhow::Result<()> {
+    let mut command = None;
+    let mut path = None;
+    let mut runtime = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--runtime" {
+            runtime = Some(
+                args.next()
+                    .ok_or_else(|| anyhow!("--runtime needs a name"))?,
+            );
+        } else if command.is_none() {
+            command = Some(arg);
+        } else {
+            path = Some(arg);
+        }
+    }
+    let (Some(command), Some(path)) = (command, path) else {
+        return Err(anyhow!("{USAGE}"));
+    };
+    let topology = Topology::load(&path)?;
+    let hosts = topology.hosts(runtime.as_deref())?;
+    match command.as_str() {
+        "up" => testenv::up(&hosts).await?,
+        "down" => testenv::down(&hosts).await?,
+        "status" => {
+            for (host, state) in hosts.iter().zip(testenv::status(&hosts).await?) {
+                let state = match state {
+                    HostState::Absent => "absent".to_string(),
+                    HostState::Up => "up".to_string(),
+                    HostState::Partial(leftovers) => {
+                        format!("partial: {}", leftovers.join(", "))
+                    }
+                };
+                println!(
+                    "{}\t{} <-> {}/{}\t{state}",
+                    host.node, host.port, host.netns, host.if_name
+                );
+            }
+        }
+        command => return Err(anyhow!("unknown command {command}\n\n{USAGE}")),
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    if let Err(e) = run().await {
+        eprintln!("error: {e:#}");
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,321 @@
+//! The forwarding database of the learning bridge.
+//!
+//! Ports are known by the MAC address the runtime registered them with, the
+//! one of the node attached to them. The table maps the addresses learned
+//! from the source of the frames, such as hosts behind a node, to the port
+//! they came in from. When the bridge is VLAN-aware, addresses are learned
+//! separately in each VLAN, except for the static ones that hold in all of
+//! them.
+//!
+//! Actors do not lock the table on the path of a frame. Each one keeps a
+//! `MacCache`, a copy of the entries the table publishes after every change,
+//! which it takes again when the version of the table changed, and writes
+//! the addresses it learned to the table once per batch of frames.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use hwaddr::HwAddr;
+use tokio::time::Instant;
+
+use crate::vlan::Vid;
+
+/// Default time after which a learned address is forgotten, as in Linux
+/// bridges.
+pub const DEFAULT_AGING: Duration = Duration::from_secs(300);
+
+/// Learned entries are written at most this often while they do not move,
+/// to keep the writes, and the copies of the table they publish, rare.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+type Entries = HashMap<(Vid, HwAddr), MacEntry>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MacEntry {
+    pub port: HwAddr,
+    /// When a frame from the address was last seen, `None` for a static
+    /// entry that never ages.
+    pub last_seen: Option<Instant>,
+}
+
+pub struct MacTable {
+    aging: Duration,
+    /// The port of the static addresses.
+    static_entries: HashMap<HwAddr, HwAddr>,
+    /// The learned entries, keyed by VLAN and address.
+    entries: Mutex<Entries>,
+    /// A copy of `entries` as of the last change, and the number of changes
+    /// so far.
+    published: Mutex<Arc<Entries>>,
+    version: AtomicU64,
+}
+
+impl MacTable {
+    pub fn new(aging: Duration, static_entries: &HashMap<HwAddr, HwAddr>) -> Self {
+        Self {
+            aging,
+            static_entries: static_entries.clone(),
+            entries: Mutex::default(),
+            published: Mutex::default(),
+            version: AtomicU64::new(0),
+        }
+    }
+
+    /// Changes whenever the learned entries change.
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Acquire)
+    }
+
+    fn snapshot(&self) -> Arc<Entries> {
+        self.published.lock().unwrap().clone()
+    }
+
+    /// Publish a copy of `entries`, which must be the locked entries of the
+    /// table.
+    fn publish(&self, entries: &Entries) {
+        *self.published.lock().unwrap() = Arc::new(entries.clone());
+        self.version.fetch_add(1, Ordering::Release);
+    }
+
+    /// Record that each address is behind its port in its VLAN.
+    fn learn_all(&self, learned: &[((Vid, HwAddr), HwAddr)], now: Instant) {
+        let mut entries = self.entries.lock().unwrap();
+        for &(key, port) in learned {
+            let entry = MacEntry {
+                port,
+                last_seen: Some(now),
+            };
+            entries.insert(key, entry);
+        }
+        self.publish(&entries);
+    }
+
+    /// Record that `mac` is behind `port` in `vlan`. Static entries are
+    /// kept.
+    pub fn learn(&self, vlan: Vid, mac: HwAddr, port: HwAddr, now: Instant) {
+        if is_multicast(mac) || self.static_entries.contains_key(&mac) {
+            return;
+        }
+        self.learn_all(&[((vlan, mac), port)], now);
+    }
+
+    /// The port of `entry`, unless it aged out.
+    fn live_port(&self, entry: &MacEntry, now: Instant) -> Option<HwAddr> {
+        match entry.last_seen {
+            Some(seen) if now.duration_since(seen) >= self.aging => None,
+            _ => Some(entry.port),
+        }
+    }
+
+    /// The port behind which `mac` is in `vlan`, unless it aged out.
+    pub fn lookup(&self, vlan: Vid, mac: HwAddr, now: Instant) -> Option<HwAddr> {
+        if let Some(&port) = self.static_entries.get(&mac) {
+            return Some(port);
+        }
+        let entries = self.entries.lock().unwrap();
+        self.live_port(entries.get(&(vlan, mac))?, now)
+    }
+
+    /// Forget the learned entries that aged out.
+    pub fn expire(&self, now: Instant) {
+        let aging = self.aging;
+        let mut entries = self.entries.lock().unwrap();
+        let len = entries.len();
+        entries.retain(|_, entry| {
+            entry
+                .last_seen
+                .map_or(true, |seen| now.duration_since(seen) < aging)
+        });
+        if entries.len() != len {
+            self.publish(&entries);
+        }
+    }
+
+    /// Forget every learned entry, as when the spanning tree changed and
+    /// addresses may be behind other ports.
+    pub fn flush(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.clear();
+        self.publish(&entries);
+    }
+
+    /// Forget the addresses learned behind `port`, as when it is removed.
+    pub fn remove_port(&self, port: HwAddr) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| entry.port != port);
+        self.publish(&entries);
+    }
+
+    /// Every entry with its VLAN, the static ones in VLAN 0.
+    pub fn entries(&self) -> Vec<(Vid, HwAddr, MacEntry)> {
+        let static_entries = self.static_entries.iter().map(|(&mac, &port)| {
+            let entry = MacEntry {
+                port,
+                last_seen: None,
+            };
+            (0, mac, entry)
+        });
+        let entries = self.entries.lock().unwrap();
+        static_entries
+            .chain(
+                entries
+                    .iter()
+                    .map(|(&(vlan, mac), &entry)| (vlan, mac, entry)),
+            )
+            .collect()
+    }
+}
+
+/// The view of a `MacTable` of one actor: a copy of its entries, and the
+/// addresses learned since that are not written to the table yet.
+pub struct MacCache {
+    version: u64,
+    entries: Arc<Entries>,
+    learned: Vec<((Vid, HwAddr), HwAddr)>,
+}
+
+impl MacCache {
+    pub fn new(table: &MacTable) -> Self {
+        Self {
+            version: table.version(),
+            entries: table.snapshot(),
+            learned: Vec::new(),
+        }
+    }
+
+    /// Take a copy of the entries of `table` again if they changed, which
+    /// costs an atomic load when they did not.
+    pub fn refresh(&mut self, table: &MacTable) {
+        let version = table.version();
+        if version != self.version {
+            self.version = version;
+            self.entries = table.snapshot();
+        }
+    }
+
+    /// Record that `mac` is behind `port` in `vlan`, to be written by
+    /// `commit`, unless the copy already has it there and fresh. Static
+    /// entries are kept.
+    pub fn learn(&mut self, table: &MacTable, vlan: Vid, mac: HwAddr, port: HwAddr, now: Instant) {
+        if is_multicast(mac) || table.static_entries.contains_key(&mac) {
+            return;
+        }
+        let key = (vlan, mac);
+        let fresh = self.entries.get(&key).is_some_and(|entry| {
+            entry.port == port
+                && entry
+                    .last_seen
+                    .is_some_and(|seen| now.duration_since(seen) < REFRESH_INTERVAL)
+        });
+        if fresh || self.learned.iter().any(|&(learned, _)| learned == key) {
+            return;
+        }
+        self.learned.push((key, port));
+    }
+
+    /// Write the addresses learned since the last call to `table`.
+    pub fn commit(&mut self, table: &MacTable, now: Instant) {
+        if !self.learned.is_empty() {
+            table.learn_all(&self.learned, now);
+            self.learned.clear();
+        }
+    }
+
+    /// The port behind which `mac` is in `vlan` in the copy of `table`,
+    /// unless it aged out.
+    pub fn lookup(&self, table: &MacTable, vlan: Vid, mac: HwAddr, now: Instant) -> Option<HwAddr> {
+        if let Some(&port) = table.static_entries.get(&mac) {
+            return Some(port);
+        }
+        table.live_port(self.entries.get(&(vlan, mac))?, now)
+    }
+}
+
+/// Whether `mac` is a group address, broadcast included.
+pub fn is_multicast(mac: HwAddr) -> bool {
+    mac.octets()[0] & 1 != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mac(last: u8) -> HwAddr {
+        HwAddr::from([2, 0, 0, 0, 0, last])
+    }
+
+    fn table() -> MacTable {
+        MacTable::new(DEFAULT_AGING, &HashMap::new())
+    }
+
+    #[test]
+    fn cache_learns_once_per_batch() {
+        let table = table();
+        let mut cache = MacCache::new(&table);
+        let now = Instant::now();
+        cache.learn(&table, 0, mac(3), mac(1), now);
+        cache.learn(&table, 0, mac(3), mac(1), now);
+        assert_eq!(table.lookup(0, mac(3), now), None);
+        let version = table.version();
+        cache.commit(&table, now);
+        assert_eq!(table.version(), version + 1);
+        assert_eq!(table.lookup(0, mac(3), now), Some(mac(1)));
+        // Not in the copy of the cache until it refreshes.
+        assert_eq!(cache.lookup(&table, 0, mac(3), now), None);
+        cache.refresh(&table);
+        assert_eq!(cache.lookup(&table, 0, mac(3), now), Some(mac(1)));
+        assert_eq!(cache.lookup(&table, 1, mac(3), now), None);
+    }
+
+    #[test]
+    fn fresh_entries_are_not_written_again() {
+        let table = table();
+        let mut cache = MacCache::new(&table);
+        let now = Instant::now();
+        cache.learn(&table, 0, mac(3), mac(1), now);
+        cache.commit(&table, now);
+        cache.refresh(&table);
+        let version = table.version();
+        cache.learn(&table, 0, mac(3), mac(1), now + Duration::from_millis(500));
+        cache.commit(&table, now);
+        assert_eq!(table.version(), version);
+        // A move is written right away.
+        cache.learn(&table, 0, mac(3), mac(2), now + Duration::from_millis(500));
+        cache.commit(&table, now);
+        assert_eq!(table.lookup(0, mac(3), now), Some(mac(2)));
+    }
+
+    #[test]
+    fn aging_and_removal() {
+        let table = table();
+        let now = Instant::now();
+        table.learn(0, mac(3), mac(1), now);
+        table.learn(0, mac(2), mac(2), now);
+        let later = now + DEFAULT_AGING;
+        assert_eq!(table.lookup(0, mac(3), later), None);
+        table.remove_port(mac(1));
+        let mut cache = MacCache::new(&table);
+        cache.refresh(&table);
+        assert_eq!(cache.lookup(&table, 0, mac(3), now), None);
+        assert_eq!(cache.lookup(&table, 0, mac(2), now), Some(mac(2)));
+        table.expire(later);
+        assert!(table.entries().is_empty());
+    }
+
+    #[test]
+    fn static_entries() {
+        let table = MacTable::new(DEFAULT_AGING, &HashMap::from([(mac(3), mac(1))]));
+        let mut cache = MacCache::new(&table);
+        let now = Instant::now();
+        cache.learn(&table, 0, mac(3), mac(2), now);
+        cache.commit(&table, now);
+        cache.refresh(&table);
+        assert_eq!(cache.lookup(&table, 5, mac(3), now), Some(mac(1)));
+    }
+}
@@ -0,0 +1,437 @@
+//! Control of the links and actors while the runtime runs.
+//!
+//! Every `ControlView` shares the state of the process. The control service
+//! writes the parameters the links should have and the actors to stop, and
+//! the actors count what they do in it. On their hot path, actors only touch
+//! atomics: they load a version number that changes with the parameters,
+//! take the lock only when it changed, and bump the counters of their port.
+//!
+//! The runtime keeps the ports of the deleted actors in its port table, so
+//! the control state also publishes the ports taken out of the bridge, which
+//! the actors stop sending to.
+
+mod service;
+
+pub mod proto {
+    tonic::include_proto!("control");
+}
+
+use std::{
+    any::Any,
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, OnceLock,
+    },
+};
+
+use hwaddr::HwAddr;
+use netem_rs::DataView;
+use tokio::time::Instant;
+
+pub use service::{serve, to_impairments, ControlServiceImpl};
+
+use crate::{
+    bridge::MacTable,
+    ether::Malformed,
+    forward::{forward_config, Egress},
+    link::{EgressShapers, LinkParams},
+    loss::LossStats,
+    multicast::GroupTable,
+    neighbor::NeighborTable,
+    netem::NetemStats,
+    shaper::ShaperStats,
+    stp::{SpanningTree, Stp},
+};
+
+/// Parameters set through the control service, which take precedence over
+/// the configuration.
+#[derive(Clone, Debug, Default)]
+pub struct LinkOverrides {
+    /// Parameters of the links that have none of their own.
+    pub default: Option<LinkParams>,
+    pub links: HashMap<(HwAddr, HwAddr), LinkParams>,
+}
+
+/// Counters of the port of an actor, updated as frames go through.
+#[derive(Debug, Default)]
+pub struct PortCounters {
+    /// Frames received from the port.
+    pub rx_frames: AtomicU64,
+    pub rx_bytes: AtomicU64,
+    /// Frames sent to the other ports, a broadcast counting once per port.
+    pub tx_frames: AtomicU64,
+    pub tx_bytes: AtomicU64,
+    /// Frames dropped by the links of the actor or for lack of a send handle.
+    pub drops: AtomicU64,
+    /// Broadcast frames received from the port.
+    pub broadcast: AtomicU64,
+    /// Frames received for a destination without a known port, which are
+    /// flooded.
+    pub unknown_destination: AtomicU64,
+    /// Malformed frames received from the port, which are dropped, by
+    /// reason.
+    pub too_short: AtomicU64,
+    pub bad_ethertype: AtomicU64,
+    pub truncated: AtomicU64,
+    /// Frames received in a VLAN the port is not in, or for a port that is
+    /// not in their VLAN.
+    pub vlan_drops: AtomicU64,
+    /// Frames received while the port does not forward, or for a port that
+    /// does not, as the spanning tree decided.
+    pub blocked: AtomicU64,
+    /// Packets the router dropped: with a bad IPv4 header checksum, without
+    /// a route, whose TTL or hop limit ran out, whose next hop did not
+    /// answer ARP or NDP, or larger than the MTU of the interface or the
+    /// link out.
+    pub bad_checksum: AtomicU64,
+    pub no_route: AtomicU64,
+    pub ttl_expired: AtomicU64,
+    pub unresolved: AtomicU64,
+    pub too_big: AtomicU64,
+    /// ICMP and ICMPv6 errors the router sent back for the packets it
+    /// dropped.
+    pub icmp_errors: AtomicU64,
+}
+
+impl PortCounters {
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(counter: &AtomicU64) -> u64 {
+        counter.load(Ordering::Relaxed)
+    }
+
+    /// The counter of the frames dropped for `reason`.
+    pub fn malformed(&self, reason: Malformed) -> &AtomicU64 {
+        match reason {
+            Malformed::TooShort => &self.too_short,
+            Malformed::BadEthertype => &self.bad_ethertype,
+            Malformed::Truncated => &self.truncated,
+        }
+    }
+}
+
+/// What an actor shares with the control service.
+#[derive(Debug, Default)]
+pub struct ActorState {
+    pub counters: PortCounters,
+    deleted: AtomicBool,
+    /// Published from time to time, as it is too large to keep up to date.
+    details: Mutex<ActorDetails>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct ActorDetails {
+    sources: Vec<HwAddr>,
+    links: Vec<LinkStats>,
+}
+
+impl ActorState {
+    pub fn is_deleted(&self) -> bool {
+        self.deleted.load(Ordering::Acquire)
+    }
+
+    /// Publish the source addresses seen by the actor and the statistics of
+    /// its links.
+    pub fn publish(&self, sources: Vec<HwAddr>, links: Vec<LinkStats>) {
+        *self.details.lock().unwrap() = ActorDetails { sources, links };
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ActorStats {
+    pub id: u64,
+    /// Source addresses of the frames received by the actor, the nodes
+    /// behind its port.
+    pub sources: Vec<HwAddr>,
+    pub rx_frames: u64,
+    pub rx_bytes: u64,
+    pub tx_frames: u64,
+    pub tx_bytes: u64,
+    pub drops: u64,
+    pub broadcast: u64,
+    pub unknown_destination: u64,
+    pub too_short: u64,
+    pub bad_ethertype: u64,
+    pub truncated: u64,
+    pub vlan_drops: u64,
+    pub blocked: u64,
+    pub bad_checksum: u64,
+    pub no_route: u64,
+    pub ttl_expired: u64,
+    pub unresolved: u64,
+    pub too_big: u64,
+    pub icmp_errors: u64,
+    pub links: Vec<LinkStats>,
+}
+
+#[derive(Clone, Debug)]
+pub struct LinkStats {
+    pub source: HwAddr,
+    pub destination: HwAddr,
+    pub netem: NetemStats,
+    pub loss: LossStats,
+    pub shaper: Option<ShaperStats>,
+    pub down_drops: u64,
+    pub oversize_drops: u64,
+}
+
+/// Ports by the id the runtime gave them, whose type only the runtime
+/// knows.
+#[derive(Clone, Default)]
+pub struct PortIds(Vec<Arc<dyn Any + Send + Sync>>);
+
+impl PortIds {
+    pub fn push(&mut self, port_id: Arc<dyn Any + Send + Sync>) {
+        self.0.push(port_id);
+    }
+
+    pub fn contains<P: Any + PartialEq>(&self, port_id: &P) -> bool {
+        self.0
+            .iter()
+            .any(|id| id.downcast_ref::<P>() == Some(port_id))
+    }
+}
+
+/// The ports taken out of the bridge, known by the address they are
+/// registered with and by their id in the runtime.
+#[derive(Clone, Default)]
+pub struct RemovedPorts {
+    macs: HashSet<HwAddr>,
+    ids: PortIds,
+}
+
+impl RemovedPorts {
+    pub fn contains(&self, port: HwAddr) -> bool {
+        self.macs.contains(&port)
+    }
+
+    /// Whether the port known to the runtime as `port_id` was removed.
+    pub fn contains_id<P: Any + PartialEq>(&self, port_id: &P) -> bool {
+        self.ids.contains(port_id)
+    }
+}
+
+/// The port of an actor, as the runtime knows it.
+struct ActorPort {
+    id: Arc<dyn Any + Send + Sync>,
+    /// Found out from the first frame of the node attached to it.
+    mac: Option<HwAddr>,
+}
+
+struct ControlState {
+    /// Bumped on every change of `overrides` and `removed`.
+    version: AtomicU64,
+    next_id: AtomicU64,
+    overrides: Mutex<LinkOverrides>,
+    actors: Mutex<BTreeMap<u64, Arc<ActorState>>>,
+    ports: Mutex<HashMap<u64, ActorPort>>,
+    removed: Mutex<Arc<RemovedPorts>>,
+    mac_table: MacTable,
+    groups: GroupTable,
+    /// `None` when the spanning tree protocol is off.
+    stp: Option<Mutex<Stp>>,
+    neighbors: NeighborTable,
+    shapers: EgressShapers<HwAddr, Egress>,
+}
+
+#[derive(Clone)]
+pub struct ControlView {
+    state: Arc<ControlState>,
+}
+
+static CONTROL: OnceLock<ControlView> = OnceLock::new();
+
+impl DataView for ControlView {
+    fn new() -> Self {
+        CONTROL
+            .get_or_init(|| {
+                let config = forward_config();
+                let state = ControlState {
+                    version: AtomicU64::new(0),
+                    next_id: AtomicU64::new(0),
+                    overrides: Mutex::default(),
+                    actors: Mutex::default(),
+                    ports: Mutex::default(),
+                    removed: Mutex::default(),
+                    mac_table: MacTable::new(config.mac_aging, &config.static_macs),
+                    groups: GroupTable::new(),
+                    stp: config.stp.map(|bridge| Mutex::new(Stp::new(bridge))),
+                    neighbors: NeighborTable::new(),
+                    shapers: EgressShapers::default(),
+                };
+                ControlView {
+                    state: Arc::new(state),
+                }
+            })
+            .clone()
+    }
+}
+
+impl ControlView {
+    /// Changes whenever the overrides or the removed ports change.
+    pub fn version(&self) -> u64 {
+        self.state.version.load(Ordering::Acquire)
+    }
+
+    pub fn overrides(&self) -> LinkOverrides {
+        self.state.overrides.lock().unwrap().clone()
+    }
+
+    pub fn removed_ports(&self) -> Arc<RemovedPorts> {
+        self.state.removed.lock().unwrap().clone()
+    }
+
+    /// Take the port `mac`, known to the runtime as `port_id`, out of the
+    /// bridge, and forget what was learned behind it.
+    pub fn remove_port(&self, mac: Option<HwAddr>, port_id: Option<Arc<dyn Any + Send + Sync>>) {
+        {
+            let mut removed = self.state.removed.lock().unwrap();
+            let removed = Arc::make_mut(&mut removed);
+            removed.macs.extend(mac);
+            if let Some(port_id) = port_id {
+                removed.ids.push(port_id);
+            }
+        }
+        self.state.version.fetch_add(1, Ordering::Release);
+        if let Some(mac) = mac {
+            self.mac_table().remove_port(mac);
+            self.groups().remove_port(mac);
+        }
+    }
+
+    /// Put the port `mac` back in the bridge after it was removed.
+    pub fn restore_port(&self, mac: HwAddr) {
+        let restored = Arc::make_mut(&mut self.state.removed.lock().unwrap())
+            .macs
+            .remove(&mac);
+        if restored {
+            self.state.version.fetch_add(1, Ordering::Release);
+        }
+    }
+
+    /// Override the parameters of the link from `source` to `destination`,
+    /// or the default parameters without a link.
+    pub fn set_link(&self, link: Option<(HwAddr, HwAddr)>, params: LinkParams) {
+        let mut overrides = self.state.overrides.lock().unwrap();
+        match link {
+            Some(link) => {
+                overrides.links.insert(link, params);
+            }
+            None => overrides.default = Some(params),
+        }
+        self.state.version.fetch_add(1, Ordering::Release);
+    }
+
+    /// The forwarding database shared by every port.
+    pub fn mac_table(&self) -> &MacTable {
+        &self.state.mac_table
+    }
+
+    /// The multicast groups learned by IGMP and MLD snooping.
+    pub fn groups(&self) -> &GroupTable {
+        &self.state.groups
+    }
+
+    /// The neighbors the router resolved.
+    pub fn neighbors(&self) -> &NeighborTable {
+        &self.state.neighbors
+    }
+
+    /// The shapers of the egress ports, shared by the links of every actor.
+    pub(crate) fn shapers(&self) -> &EgressShapers<HwAddr, Egress> {
+        &self.state.shapers
+    }
+
+    /// The spanning tree state of the bridge, if the protocol runs. It must
+    /// not be held across an await point.
+    pub fn stp(&self) -> Option<MutexGuard<'_, Stp>> {
+        self.state.stp.as_ref().map(|stp| stp.lock().unwrap())
+    }
+
+    pub fn spanning_tree(&self) -> Option<SpanningTree> {
+        self.stp().map(|stp| stp.tree())
+    }
+
+    /// Register a new actor, whose port is known to the runtime as
+    /// `port_id`, returning its id and its state.
+    pub fn register<P: Any + Send + Sync>(&self, port_id: P) -> (u64, Arc<ActorState>) {
+        let id = self.state.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let state = Arc::new(ActorState::default());
+        self.state.actors.lock().unwrap().insert(id, state.clone());
+        let port = ActorPort {
+            id: Arc::new(port_id),
+            mac: None,
+        };
+        self.state.ports.lock().unwrap().insert(id, port);
+        (id, state)
+    }
+
+    /// Record the address the port of the actor `id` is registered with.
+    pub fn set_port_mac(&self, id: u64, mac: HwAddr) {
+        if let Some(port) = self.state.ports.lock().unwrap().get_mut(&id) {
+            port.mac = Some(mac);
+        }
+    }
+
+    pub fn unregister(&self, id: u64) {
+        self.state.actors.lock().unwrap().remove(&id);
+        self.state.ports.lock().unwrap().remove(&id);
+        let changed = self
+            .stp()
+            .is_some_and(|mut stp| stp.remove_port(id, Instant::now()));
+        if changed {
+            self.mac_table().flush();
+        }
+    }
+
+    pub fn actor_stats(&self) -> Vec<ActorStats> {
+        let actors = self.state.actors.lock().unwrap();
+        actors
+            .iter()
+            .map(|(&id, state)| {
+                let counters = &state.counters;
+                let details = state.details.lock().unwrap().clone();
+                ActorStats {
+                    id,
+                    sources: details.sources,
+                    rx_frames: PortCounters::get(&counters.rx_frames),
+                    rx_bytes: PortCounters::get(&counters.rx_bytes),
+                    tx_frames: PortCounters::get(&counters.tx_frames),
+                    tx_bytes: PortCounters::get(&counters.tx_bytes),
+                    drops: PortCounters::get(&counters.drops),
+                    broadcast: PortCounters::get(&counters.broadcast),
+                    unknown_destination: PortCounters::get(&counters.unknown_destination),
+                    too_short: PortCounters::get(&counters.too_short),
+                    bad_ethertype: PortCounters::get(&counters.bad_ethertype),
+                    truncated: PortCounters::get(&counters.truncated),
+                    vlan_drops: PortCounters::get(&counters.vlan_drops),
+                    blocked: PortCounters::get(&counters.blocked),
+                    bad_checksum: PortCounters::get(&counters.bad_checksum),
+                    no_route: PortCounters::get(&counters.no_route),
+                    ttl_expired: PortCounters::get(&counters.ttl_expired),
+                    unresolved: PortCounters::get(&counters.unresolved),
+                    too_big: PortCounters::get(&counters.too_big),
+                    icmp_errors: PortCounters::get(&counters.icmp_errors),
+                    links: details.links,
+                }
+            })
+            .collect()
+    }
+
+    /// Take the port of the actor out of the bridge and ask the actor to
+    /// stop, returning whether it was running.
+    pub fn delete(&self, id: u64) -> bool {
+        let Some(state) = self.state.actors.lock().unwrap().get(&id).cloned() else {
+            return false;
+        };
+        state.deleted.store(true, Ordering::Release);
+        if let Some(port) = self.state.ports.lock().unwrap().get(&id) {
+            self.remove_port(port.mac, Some(port.id.clone()));
+        }
+        self.unregister(id);
+        true
+    }
+}
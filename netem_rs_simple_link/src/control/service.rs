@@ -0,0 +1,268 @@
+use std::net::SocketAddr;
+
+use hwaddr::HwAddr;
+use log::info;
+use netem_rs::DataView;
+use tokio::time::Instant;
+use tonic::{transport::Server, Request, Response, Status};
+
+use super::{
+    proto::{
+        self, control_service_server::ControlServiceServer, DeleteActorRequest,
+        DeleteActorResponse, GetActorStatsRequest, GetActorStatsResponse, GetLinkRequest,
+        GetLinkResponse, GetSpanningTreeRequest, GetSpanningTreeResponse, LinkImpairments,
+        SetLinkRequest, SetLinkResponse, SpanningTreePort,
+    },
+    ActorStats, ControlView, LinkStats,
+};
+use crate::{
+    forward::{forward_config, ForwardConfig},
+    ip::MIN_IPV4_MTU,
+    link::LinkParams,
+    stp::{SpanningTree, TreePort},
+};
+
+pub struct ControlServiceImpl {
+    control: ControlView,
+    config: &'static ForwardConfig,
+}
+
+impl ControlServiceImpl {
+    pub fn new() -> Self {
+        Self {
+            control: ControlView::new(),
+            config: forward_config(),
+        }
+    }
+}
+
+impl Default for ControlServiceImpl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Serve the control service on `addr` until it fails.
+pub async fn serve(addr: SocketAddr) -> anyhow::Result<()> {
+    info!("control service listening on {addr}");
+    Server::builder()
+        .add_service(ControlServiceServer::new(ControlServiceImpl::new()))
+        .serve(addr)
+        .await?;
+    Ok(())
+}
+
+fn parse_mac(bytes: &[u8]) -> anyhow::Result<HwAddr> {
+    let octets: [u8; 6] = bytes
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("a MAC address is 6 bytes"))?;
+    Ok(HwAddr::from(octets))
+}
+
+/// The link from `source` to `destination`, `None` for the defaults when
+/// both are empty.
+fn parse_link(source: &[u8], destination: &[u8]) -> anyhow::Result<Option<(HwAddr, HwAddr)>> {
+    if source.is_empty() && destination.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some((parse_mac(source)?, parse_mac(destination)?)))
+}
+
+/// The impairments of `params`, as `SetLink` takes them.
+pub fn to_impairments(params: &LinkParams) -> LinkImpairments {
+    LinkImpairments {
+        netem: params.netem.to_string(),
+        shaper: params
+            .shaper
+            .as_ref()
+            .map(ToString::to_string)
+            .unwrap_or_default(),
+        down: params.down,
+        mtu: params.mtu.unwrap_or(0) as u32,
+        icmp_from: params.icmp_from.iter().map(ToString::to_string).collect(),
+    }
+}
+
+fn from_impairments(impairments: LinkImpairments) -> anyhow::Result<LinkParams> {
+    let shaper = if impairments.shaper.is_empty() {
+        None
+    } else {
+        Some(impairments.shaper.parse()?)
+    };
+    let mtu = (impairments.mtu != 0).then_some(impairments.mtu as usize);
+    if mtu.is_some_and(|mtu| mtu < MIN_IPV4_MTU) {
+        anyhow::bail!("mtu below {MIN_IPV4_MTU}");
+    }
+    let icmp_from = impairments
+        .icmp_from
+        .iter()
+        .map(|address| {
+            address
+                .parse()
+                .map_err(|_| anyhow::anyhow!("invalid icmp_from address {address}"))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    Ok(LinkParams {
+        netem: impairments.netem.parse()?,
+        shaper,
+        down: impairments.down,
+        mtu,
+        icmp_from,
+    })
+}
+
+fn to_proto_actor(stats: ActorStats) -> proto::ActorStats {
+    proto::ActorStats {
+        id: stats.id,
+        sources: stats
+            .sources
+            .iter()
+            .map(|mac| mac.octets().to_vec())
+            .collect(),
+        rx_frames: stats.rx_frames,
+        rx_bytes: stats.rx_bytes,
+        links: stats.links.iter().map(to_proto_link).collect(),
+        tx_frames: stats.tx_frames,
+        tx_bytes: stats.tx_bytes,
+        drops: stats.drops,
+        broadcast: stats.broadcast,
+        unknown_destination: stats.unknown_destination,
+        too_short: stats.too_short,
+        bad_ethertype: stats.bad_ethertype,
+        truncated: stats.truncated,
+        vlan_drops: stats.vlan_drops,
+        blocked: stats.blocked,
+        bad_checksum: stats.bad_checksum,
+        no_route: stats.no_route,
+        ttl_expired: stats.ttl_expired,
+        unresolved: stats.unresolved,
+        too_big: stats.too_big,
+        icmp_errors: stats.icmp_errors,
+    }
+}
+
+fn to_proto_tree(tree: SpanningTree) -> GetSpanningTreeResponse {
+    GetSpanningTreeResponse {
+        bridge_id: tree.bridge.to_string(),
+        root_id: tree.root.to_string(),
+        root_cost: tree.root_cost,
+        root_port: tree.root_port.unwrap_or(0),
+        ports: tree.ports.iter().map(to_proto_tree_port).collect(),
+    }
+}
+
+fn to_proto_tree_port(port: &TreePort) -> SpanningTreePort {
+    SpanningTreePort {
+        actor: port.actor,
+        port_id: port.id.into(),
+        mac: port
+            .mac
+            .map(|mac| mac.octets().to_vec())
+            .unwrap_or_default(),
+        role: port.role.to_string(),
+        state: port.state.to_string(),
+        designated_bridge: port.designated_bridge.to_string(),
+        designated_port: port.designated_port.into(),
+    }
+}
+
+fn to_proto_link(stats: &LinkStats) -> proto::LinkStats {
+    let shaper = stats.shaper.unwrap_or_default();
+    proto::LinkStats {
+        source: stats.source.octets().to_vec(),
+        destination: stats.destination.octets().to_vec(),
+        enqueued: stats.netem.enqueued,
+        dropped: stats.netem.dropped,
+        duplicated: stats.netem.duplicated,
+        corrupted: stats.netem.corrupted,
+        reordered: stats.netem.reordered,
+        lost: stats.loss.lost,
+        loss_bursts: stats.loss.bursts,
+        max_loss_burst: stats.loss.max_burst,
+        down_drops: stats.down_drops,
+        oversize_drops: stats.oversize_drops,
+        sent: shaper.sent,
+        sent_bytes: shaper.sent_bytes,
+        qdisc_dropped: shaper.qdisc.dropped,
+        qdisc_marked: shaper.qdisc.marked,
+        backlog: shaper.qdisc.backlog as u64,
+        backlog_bytes: shaper.qdisc.backlog_bytes as u64,
+    }
+}
+
+#[tonic::async_trait]
+impl proto::control_service_server::ControlService for ControlServiceImpl {
+    async fn get_link(
+        &self,
+        request: Request<GetLinkRequest>,
+    ) -> Result<Response<GetLinkResponse>, Status> {
+        let request = request.into_inner();
+        let link = parse_link(&request.source, &request.destination)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let overrides = self.control.overrides();
+        let changed = self.config.changed_at(Instant::now());
+        let params = match link {
+            Some((source, destination)) => {
+                self.config
+                    .link_params(&overrides, &changed, source, destination)
+            }
+            None => overrides.default.as_ref().unwrap_or(&self.config.default),
+        };
+        Ok(Response::new(GetLinkResponse {
+            impairments: Some(to_impairments(params)),
+        }))
+    }
+
+    async fn set_link(
+        &self,
+        request: Request<SetLinkRequest>,
+    ) -> Result<Response<SetLinkResponse>, Status> {
+        let request = request.into_inner();
+        let link = parse_link(&request.source, &request.destination)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let impairments = request
+            .impairments
+            .ok_or_else(|| Status::invalid_argument("missing impairments"))?;
+        let params =
+            from_impairments(impairments).map_err(|e| Status::invalid_argument(e.to_string()))?;
+        info!("set link {link:?} to {params:?}");
+        self.control.set_link(link, params);
+        Ok(Response::new(SetLinkResponse {}))
+    }
+
+    async fn get_actor_stats(
+        &self,
+        _request: Request<GetActorStatsRequest>,
+    ) -> Result<Response<GetActorStatsResponse>, Status> {
+        let actors = self
+            .control
+            .actor_stats()
+            .into_iter()
+            .map(to_proto_actor)
+            .collect();
+        Ok(Response::new(GetActorStatsResponse { actors }))
+    }
+
+    async fn delete_actor(
+        &self,
+        request: Request<DeleteActorRequest>,
+    ) -> Result<Response<DeleteActorResponse>, Status> {
+        let id = request.into_inner().id;
+        if !self.control.delete(id) {
+            return Err(Status::not_found(format!("no actor {id}")));
+        }
+        info!("delete actor {id}");
+        Ok(Response::new(DeleteActorResponse {}))
+    }
+
+    async fn get_spanning_tree(
+        &self,
+        _request: Request<GetSpanningTreeRequest>,
+    ) -> Result<Response<GetSpanningTreeResponse>, Status> {
+        let tree = self
+            .control
+            .spanning_tree()
+            .ok_or_else(|| Status::failed_precondition("STP is off"))?;
+        Ok(Response::new(to_proto_tree(tree)))
+    }
+}
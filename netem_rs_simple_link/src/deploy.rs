@@ -0,0 +1,269 @@
+//! Deployment of a topology on the runtimes of remote mode, for the
+//! `deploy` command.
+//!
+//! The actor of every node is created on its runtime through the
+//! `ActorService`, and each direction of the links is set through the
+//! `ControlService` of the runtime of its source, whose actor impairs the
+//! frames it receives. The control service does not say which port an actor
+//! is on, so the actors of a deployment are told apart by their ids: those
+//! that appear after creating them.
+
+use std::{collections::HashSet, time::Duration};
+
+use anyhow::{anyhow, bail, Context};
+use hwaddr::HwAddr;
+use log::{error, info, warn};
+use tokio::time::Instant;
+
+use crate::{
+    actors,
+    control::{proto::LinkImpairments, to_impairments},
+    topology::{NodeConfig, Topology},
+};
+
+/// How long actors take to run once created.
+const ACTOR_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What to deploy on a runtime.
+pub struct RuntimePlan {
+    pub name: String,
+    pub address: String,
+    pub control: String,
+    pub nodes: Vec<(String, NodeConfig)>,
+    /// The links from the nodes of the runtime, as their source and
+    /// destination MACs.
+    pub links: Vec<(HwAddr, HwAddr, LinkImpairments)>,
+}
+
+/// What to deploy on every runtime of `topology`, checked before anything
+/// is deployed.
+pub fn plan(topology: &Topology) -> anyhow::Result<Vec<RuntimePlan>> {
+    if !topology.static_macs.is_empty()
+        || !topology.ports.is_empty()
+        || !topology.interfaces.is_empty()
+        || !topology.routes.is_empty()
+        || !topology.events.is_empty()
+    {
+        warn!("remote mode takes no static_mac, port, interface, route or event, ignored");
+    }
+    let mut links: Vec<_> = topology.link_params()?.into_iter().collect();
+    links.sort_by_key(|((source, destination), _)| (source.octets(), destination.octets()));
+    let mut plan = Vec::new();
+    for (name, node_names) in topology.runtime_nodes()? {
+        let runtime = &topology.runtimes[name];
+        let mut nodes = Vec::new();
+        let mut macs = HashSet::new();
+        for node_name in node_names {
+            let node = &topology.nodes[node_name];
+            actors::create_request(node).with_context(|| format!("invalid node {node_name}"))?;
+            macs.insert(topology.node_mac(node_name)?);
+            nodes.push((node_name.to_string(), node.clone()));
+        }
+        let links = links
+            .iter()
+            .filter(|((source, _), _)| macs.contains(source))
+            .map(|((source, destination), params)| (*source, *destination, to_impairments(params)))
+            .collect();
+        plan.push(RuntimePlan {
+            name: name.to_string(),
+            control: runtime
+                .control
+                .clone()
+                .unwrap_or_else(|| actors::control_endpoint(&runtime.address)),
+            address: runtime.address.clone(),
+            nodes,
+            links,
+        });
+    }
+    Ok(plan)
+}
+
+async fn actor_ids(control: &str) -> anyhow::Result<HashSet<u64>> {
+    Ok(actors::actor_stats(control)
+        .await?
+        .iter()
+        .map(|actor| actor.id)
+        .collect())
+}
+
+/// The ids of the actors that run on the runtime besides `before`, once
+/// there are `count` of them or after `ACTOR_TIMEOUT`.
+async fn new_actors(
+    control: &str,
+    before: &HashSet<u64>,
+    count: usize,
+) -> anyhow::Result<HashSet<u64>> {
+    let deadline = Instant::now() + ACTOR_TIMEOUT;
+    loop {
+        let ids: HashSet<_> = actor_ids(control)
+            .await?
+            .difference(before)
+            .copied()
+            .collect();
+        if ids.len() >= count || Instant::now() >= deadline {
+            return Ok(ids);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Create the actors and set the links of `runtime`, counting the actors
+/// created in `created`.
+async fn apply(runtime: &RuntimePlan, created: &mut usize) -> anyhow::Result<()> {
+    for (name, node) in &runtime.nodes {
+        actors::create_actor(&runtime.address, node)
+            .await
+            .with_context(|| format!("failed to create {name}"))?;
+        *created += 1;
+        info!("created {name} on {}", runtime.name);
+    }
+    for (source, destination, impairments) in &runtime.links {
+        actors::set_link(
+            &runtime.control,
+            Some((*source, *destination)),
+            impairments.clone(),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// The links of `runtime` whose impairments are not the planned ones.
+async fn check_links(runtime: &RuntimePlan) -> anyhow::Result<Vec<String>> {
+    let mut problems = Vec::new();
+    for (source, destination, impairments) in &runtime.links {
+        let actual = actors::get_link(&runtime.control, Some((*source, *destination))).await?;
+        if actual != *impairments {
+            problems.push(format!(
+                "link {source} -> {destination} on {} is {actual:?}, not {impairments:?}",
+                runtime.name
+            ));
+        }
+    }
+    Ok(problems)
+}
+
+/// Delete the `created` actors of `runtime` that are not in `keep`, or all
+/// of them without `keep`, and give its links the impairments of the
+/// runtime by default.
+async fn remove(runtime: &RuntimePlan, keep: Option<(&HashSet<u64>, usize)>) -> anyhow::Result<()> {
+    let ids = match keep {
+        // The actors just created may not run yet.
+        Some((before, created)) => new_actors(&runtime.control, before, created).await?,
+        None => actor_ids(&runtime.control).await?,
+    };
+    for id in ids {
+        actors::delete_actor(&runtime.control, id).await?;
+    }
+    let default = actors::get_link(&runtime.control, None).await?;
+    for (source, destination, _) in &runtime.links {
+        actors::set_link(
+            &runtime.control,
+            Some((*source, *destination)),
+            default.clone(),
+        )
+        .await?;
+    }
+    info!("removed the deployment from {}", runtime.name);
+    Ok(())
+}
+
+/// Undo `up` on the runtimes it changed, the last first.
+async fn roll_back(applied: Vec<(&RuntimePlan, HashSet<u64>, usize)>) {
+    for (runtime, before, created) in applied.into_iter().rev() {
+        warn!("rolling back {}", runtime.name);
+        if let Err(e) = remove(runtime, Some((&before, created))).await {
+            error!("failed to roll back {}: {e:#}", runtime.name);
+        }
+    }
+}
+
+/// Create the actors and set the links of every runtime of `plan`, then
+/// check that the actors run and the links are set. Everything deployed is
+/// removed again when any of it fails.
+pub async fn up(plan: &[RuntimePlan]) -> anyhow::Result<()> {
+    let mut applied = Vec::new();
+    let mut result = Ok(());
+    for runtime in plan {
+        let before = match actor_ids(&runtime.control).await {
+            Ok(before) => before,
+            Err(e) => {
+                result = Err(e.context(format!("runtime {} is unreachable", runtime.name)));
+                break;
+            }
+        };
+        applied.push((runtime, before, 0));
+        let (_, _, created) = applied.last_mut().unwrap();
+        if let Err(e) = apply(runtime, created).await {
+            result = Err(e.context(format!("failed to deploy on {}", runtime.name)));
+            break;
+        }
+    }
+    if result.is_ok() {
+        result = verify(&applied).await;
+    }
+    if result.is_err() {
+        roll_back(applied).await;
+    }
+    result
+}
+
+/// Check the runtimes `up` deployed on, against the actors they ran before.
+async fn verify(applied: &[(&RuntimePlan, HashSet<u64>, usize)]) -> anyhow::Result<()> {
+    let mut problems = Vec::new();
+    for (runtime, before, _) in applied {
+        let count = runtime.nodes.len();
+        let running = new_actors(&runtime.control, before, count).await?.len();
+        if running < count {
+            problems.push(format!(
+                "{running} of the {count} actors run on {}",
+                runtime.name
+            ));
+        }
+        problems.extend(check_links(runtime).await?);
+    }
+    if !problems.is_empty() {
+        bail!("{}", problems.join("\n"));
+    }
+    Ok(())
+}
+
+/// Check that every runtime of `plan` runs as many actors as it has nodes
+/// and that its links have the planned impairments.
+pub async fn check(plan: &[RuntimePlan]) -> anyhow::Result<()> {
+    let mut problems = Vec::new();
+    for runtime in plan {
+        let running = actor_ids(&runtime.control)
+            .await
+            .with_context(|| format!("runtime {} is unreachable", runtime.name))?
+            .len();
+        let count = runtime.nodes.len();
+        if running < count {
+            problems.push(format!(
+                "{running} actors run on {} for {count} nodes",
+                runtime.name
+            ));
+        }
+        problems.extend(check_links(runtime).await?);
+    }
+    if !problems.is_empty() {
+        bail!("{}", problems.join("\n"));
+    }
+    Ok(())
+}
+
+/// Delete every actor of the runtimes of `plan` and reset their links,
+/// going on with the other runtimes when one fails.
+pub async fn down(plan: &[RuntimePlan]) -> anyhow::Result<()> {
+    let mut failed = Vec::new();
+    for runtime in plan {
+        if let Err(e) = remove(runtime, None).await {
+            error!("failed to tear down {}: {e:#}", runtime.name);
+            failed.push(runtime.name.as_str());
+        }
+    }
+    if !failed.is_empty() {
+        return Err(anyhow!("failed to tear down {}", failed.join(", ")));
+    }
+    Ok(())
+}
@@ -0,0 +1,191 @@
+//! Explicit Congestion Notification (RFC 3168) marking of Ethernet frames.
+
+pub(crate) const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+pub(crate) const ETHERTYPE_IPV4: u16 = 0x0800;
+pub(crate) const ETHERTYPE_IPV6: u16 = 0x86dd;
+
+const NOT_ECT: u8 = 0b00;
+const CE: u8 = 0b11;
+
+/// Offset and ethertype of the payload of an Ethernet frame, after any
+/// 802.1Q or 802.1ad tags.
+pub fn ether_payload(frame: &[u8]) -> Option<(usize, u16)> {
+    let mut offset = 12;
+    loop {
+        let bytes = frame.get(offset..offset + 2)?;
+        let ethertype = u16::from_be_bytes([bytes[0], bytes[1]]);
+        if ethertype != ETHERTYPE_VLAN && ethertype != ETHERTYPE_QINQ {
+            return Some((offset + 2, ethertype));
+        }
+        offset += 4;
+    }
+}
+
+/// Set the Congestion Experienced codepoint of the IPv4 or IPv6 packet in
+/// `frame`, fixing up the IPv4 header checksum. Returns false, leaving the
+/// frame untouched, if it is not ECN capable and has to be dropped instead.
+pub fn set_ce(frame: &mut [u8]) -> bool {
+    let Some((offset, ethertype)) = ether_payload(frame) else {
+        return false;
+    };
+    match ethertype {
+        ETHERTYPE_IPV4 => {
+            let Some(header) = frame.get_mut(offset..offset + 20) else {
+                return false;
+            };
+            if header[0] >> 4 != 4 {
+                return false;
+            }
+            let tos = header[1];
+            match tos & 0b11 {
+                NOT_ECT => false,
+                CE => true,
+                _ => {
+                    let new_tos = tos | CE;
+                    header[1] = new_tos;
+                    // Incremental update of RFC 1624 for the word holding the
+                    // version, IHL and TOS.
+                    let old_word = u16::from_be_bytes([header[0], tos]);
+                    let new_word = u16::from_be_bytes([header[0], new_tos]);
+                    let checksum = u16::from_be_bytes([header[10], header[11]]);
+                    let sum = u32::from(!checksum) + u32::from(!old_word) + u32::from(new_word);
+                    let sum = (sum & 0xffff) + (sum >> 16);
+                    let sum = (sum & 0xffff) + (sum >> 16);
+                    header[10..12].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+                    true
+                }
+            }
+        }
+        ETHERTYPE_IPV6 => {
+            let Some(header) = frame.get_mut(offset..offset + 40) else {
+                return false;
+            };
+            if header[0] >> 4 != 6 {
+                return false;
+            }
+            // The ECN field is the low two bits of the traffic class, which
+            // spans the first two bytes.
+            match (header[1] >> 4) & 0b11 {
+                NOT_ECT => false,
+                CE => true,
+                _ => {
+                    header[1] |= CE << 4;
+                    true
+                }
+            }
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    /// The Internet checksum of `data`, 0 over a header with a valid one.
+    fn checksum(data: &[u8]) -> u16 {
+        let mut sum = data
+            .chunks(2)
+            .map(|word| u32::from(word[0]) << 8 | u32::from(word.get(1).copied().unwrap_or(0)))
+            .sum::<u32>();
+        while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        !(sum as u16)
+    }
+
+    fn set_ipv4_checksum(header: &mut [u8]) {
+        header[10..12].fill(0);
+        let sum = checksum(header);
+        header[10..12].copy_from_slice(&sum.to_be_bytes());
+    }
+
+    /// An Ethernet frame of `len` bytes with a UDP packet from `source`,
+    /// whose TOS byte is `tos`.
+    pub(crate) fn ipv4_frame(source: Ipv4Addr, tos: u8, len: usize) -> Vec<u8> {
+        let mut frame = vec![0u8; len.max(42)];
+        frame[12..14].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        let header = &mut frame[14..34];
+        header[0] = 0x45;
+        header[1] = tos;
+        header[2..4].copy_from_slice(&((len.max(42) - 14) as u16).to_be_bytes());
+        header[8] = 64;
+        header[9] = 17;
+        header[12..16].copy_from_slice(&source.octets());
+        header[16..20].copy_from_slice(&[10, 0, 0, 2]);
+        set_ipv4_checksum(header);
+        frame[34..38].copy_from_slice(&[0x30, 0x39, 0x00, 0x35]);
+        frame
+    }
+
+    fn ipv6_frame(traffic_class: u8) -> Vec<u8> {
+        let mut frame = vec![0u8; 54];
+        frame[12..14].copy_from_slice(&ETHERTYPE_IPV6.to_be_bytes());
+        frame[14] = 0x60 | traffic_class >> 4;
+        frame[15] = traffic_class << 4;
+        frame
+    }
+
+    #[test]
+    fn ipv4_checksum_update() {
+        // Every TOS and enough headers to go through the carries of the
+        // incremental update of RFC 1624.
+        for id in (0..=u16::MAX).step_by(251) {
+            for tos in [0b01, 0b10, 0xfd, 0xfe, 0x29, 0x2a] {
+                let mut frame = ipv4_frame(Ipv4Addr::new(10, 0, 0, 1), tos, 64);
+                frame[18..20].copy_from_slice(&id.to_be_bytes());
+                set_ipv4_checksum(&mut frame[14..34]);
+                assert!(set_ce(&mut frame));
+                assert_eq!(frame[15], tos | CE);
+                assert_eq!(checksum(&frame[14..34]), 0, "tos {tos:#x} id {id}");
+                let mut expected = frame[14..34].to_vec();
+                set_ipv4_checksum(&mut expected);
+                assert_eq!(frame[14..34], expected);
+            }
+        }
+    }
+
+    #[test]
+    fn ipv4_codepoints() {
+        let source = Ipv4Addr::new(10, 0, 0, 1);
+        let not_ect = ipv4_frame(source, 0xb8, 64);
+        let mut frame = not_ect.clone();
+        assert!(!set_ce(&mut frame));
+        assert_eq!(frame, not_ect);
+
+        let ce = ipv4_frame(source, 0xbb, 64);
+        let mut frame = ce.clone();
+        assert!(set_ce(&mut frame));
+        assert_eq!(frame, ce);
+
+        // Cut in the middle of the header.
+        let mut frame = ipv4_frame(source, 0b10, 64);
+        frame.truncate(30);
+        assert!(!set_ce(&mut frame));
+    }
+
+    #[test]
+    fn vlan_tagged() {
+        let mut frame = ipv4_frame(Ipv4Addr::new(10, 0, 0, 1), 0b10, 64);
+        frame.splice(12..12, [0x81, 0x00, 0x00, 0x0a]);
+        assert_eq!(ether_payload(&frame), Some((18, ETHERTYPE_IPV4)));
+        assert!(set_ce(&mut frame));
+        assert_eq!(frame[19] & 0b11, CE);
+        assert_eq!(checksum(&frame[18..38]), 0);
+    }
+
+    #[test]
+    fn ipv6_traffic_class() {
+        let mut frame = ipv6_frame(0xb9);
+        assert!(set_ce(&mut frame));
+        assert_eq!(frame[14], 0x6b);
+        assert_eq!(frame[15], 0xb0);
+
+        let mut frame = ipv6_frame(0xb8);
+        assert!(!set_ce(&mut frame));
+        assert_eq!(frame, ipv6_frame(0xb8));
+    }
+}
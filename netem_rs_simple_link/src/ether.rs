@@ -0,0 +1,238 @@
+//! Validation of the Ethernet frames received from the ports.
+//!
+//! A frame that a NIC or a peer hands over may be cut short or carry
+//! garbage. Such frames are dropped and counted by reason instead of being
+//! forwarded.
+
+use std::fmt;
+
+use hwaddr::HwAddr;
+use packet::ether::Packet;
+
+use crate::{
+    ecn::{ether_payload, ETHERTYPE_IPV4, ETHERTYPE_IPV6},
+    neighbor::ETHERTYPE_ARP,
+};
+
+pub const ETHER_HEADER_LEN: usize = 14;
+
+const ARP_LEN: usize = 28;
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+
+/// Largest length of an 802.3 frame, whose type field is the length of its
+/// payload. Type fields start at 0x0600.
+const MAX_LENGTH_FIELD: u16 = 1500;
+const MIN_ETHERTYPE: u16 = 0x0600;
+
+/// Why a frame was dropped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Malformed {
+    /// Shorter than its Ethernet header and VLAN tags.
+    TooShort,
+    /// A type field that is neither a length nor an ethertype.
+    BadEthertype,
+    /// Shorter than its 802.3 length field or its ARP, IPv4 or IPv6 header
+    /// says.
+    Truncated,
+}
+
+impl fmt::Display for Malformed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            Malformed::TooShort => "too short",
+            Malformed::BadEthertype => "bad ethertype",
+            Malformed::Truncated => "truncated payload",
+        };
+        f.write_str(reason)
+    }
+}
+
+/// The MAC at `offset` in `frame`, 0 for its destination and 6 for its
+/// source.
+pub fn mac_at(frame: &[u8], offset: usize) -> HwAddr {
+    HwAddr::from(<[u8; 6]>::try_from(&frame[offset..offset + 6]).unwrap())
+}
+
+/// The Ethernet header of `frame`, if the frame is well-formed.
+pub fn parse(frame: &[u8]) -> Result<Packet<&[u8]>, Malformed> {
+    validate(frame)?;
+    Packet::new(frame).map_err(|_| Malformed::TooShort)
+}
+
+pub fn validate(frame: &[u8]) -> Result<(), Malformed> {
+    if frame.len() < ETHER_HEADER_LEN {
+        return Err(Malformed::TooShort);
+    }
+    // Only a VLAN tag can be cut short here.
+    let (offset, ethertype) = ether_payload(frame).ok_or(Malformed::TooShort)?;
+    let payload = &frame[offset..];
+    // Frames are padded to the minimum size, so payloads may be longer than
+    // their headers say but not shorter.
+    let complete = match ethertype {
+        0..=MAX_LENGTH_FIELD => payload.len() >= usize::from(ethertype),
+        _ if ethertype < MIN_ETHERTYPE => return Err(Malformed::BadEthertype),
+        ETHERTYPE_ARP => payload.len() >= ARP_LEN,
+        ETHERTYPE_IPV4 => {
+            let header_len = payload.first().map_or(0, |b| usize::from(b & 0x0f) * 4);
+            let total_len = payload
+                .get(2..4)
+                .map_or(0, |b| usize::from(u16::from_be_bytes([b[0], b[1]])));
+            header_len >= IPV4_HEADER_LEN && total_len >= header_len && payload.len() >= total_len
+        }
+        ETHERTYPE_IPV6 => payload.get(4..6).is_some_and(|b| {
+            let payload_len = usize::from(u16::from_be_bytes([b[0], b[1]]));
+            payload.len() >= IPV6_HEADER_LEN + payload_len
+        }),
+        _ => true,
+    };
+    if complete {
+        Ok(())
+    } else {
+        Err(Malformed::Truncated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ecn::ETHERTYPE_VLAN;
+
+    /// A frame with `tags` VLAN tags, `ethertype` and `payload`.
+    fn frame(tags: usize, ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0; 12];
+        for _ in 0..tags {
+            frame.extend_from_slice(&ETHERTYPE_VLAN.to_be_bytes());
+            frame.extend_from_slice(&[0, 10]);
+        }
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// An IPv4 header of `header_len` bytes for a packet of `total_len`
+    /// bytes, followed by `len - header_len` bytes.
+    fn ipv4(header_len: u8, total_len: u16, len: usize) -> Vec<u8> {
+        let mut packet = vec![0; len];
+        packet[0] = 0x40 | (header_len / 4);
+        packet[2..4].copy_from_slice(&total_len.to_be_bytes());
+        packet
+    }
+
+    /// An IPv6 header with a payload length of `payload_len`, followed by
+    /// `len - 40` bytes.
+    fn ipv6(payload_len: u16, len: usize) -> Vec<u8> {
+        let mut packet = vec![0; len];
+        packet[0] = 0x60;
+        packet[4..6].copy_from_slice(&payload_len.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn validate_frames() {
+        use Malformed::*;
+        let cases = [
+            ("empty", vec![], Err(TooShort)),
+            ("13 bytes", vec![0; 13], Err(TooShort)),
+            ("bare header", frame(0, 0x88b5, &[]), Ok(())),
+            (
+                "cut vlan tag",
+                frame(1, 0x88b5, &[])[..16].to_vec(),
+                Err(TooShort),
+            ),
+            ("bare tagged header", frame(1, 0x88b5, &[]), Ok(())),
+            ("bare double tagged header", frame(2, 0x88b5, &[]), Ok(())),
+            ("type 0x05ff", frame(0, 0x05ff, &[0; 46]), Err(BadEthertype)),
+            ("type 0x0600", frame(0, 0x0600, &[]), Ok(())),
+            ("802.3 length", frame(0, 46, &[0; 46]), Ok(())),
+            (
+                "short 802.3 payload",
+                frame(0, 46, &[0; 45]),
+                Err(Truncated),
+            ),
+            ("arp", frame(0, ETHERTYPE_ARP, &[0; 28]), Ok(())),
+            (
+                "short arp",
+                frame(0, ETHERTYPE_ARP, &[0; 27]),
+                Err(Truncated),
+            ),
+            ("tagged arp", frame(1, ETHERTYPE_ARP, &[0; 28]), Ok(())),
+            (
+                "short tagged arp",
+                frame(1, ETHERTYPE_ARP, &[0; 27]),
+                Err(Truncated),
+            ),
+            ("ipv4", frame(0, ETHERTYPE_IPV4, &ipv4(20, 20, 20)), Ok(())),
+            (
+                "padded ipv4",
+                frame(0, ETHERTYPE_IPV4, &ipv4(20, 20, 46)),
+                Ok(()),
+            ),
+            (
+                "ipv4 options",
+                frame(0, ETHERTYPE_IPV4, &ipv4(24, 24, 24)),
+                Ok(()),
+            ),
+            (
+                "short ipv4 header",
+                frame(0, ETHERTYPE_IPV4, &ipv4(16, 20, 20)),
+                Err(Truncated),
+            ),
+            (
+                "total length under header",
+                frame(0, ETHERTYPE_IPV4, &ipv4(24, 20, 24)),
+                Err(Truncated),
+            ),
+            (
+                "short ipv4",
+                frame(0, ETHERTYPE_IPV4, &ipv4(20, 40, 39)),
+                Err(Truncated),
+            ),
+            (
+                "cut ipv4 header",
+                frame(0, ETHERTYPE_IPV4, &[0x45]),
+                Err(Truncated),
+            ),
+            (
+                "tagged ipv4",
+                frame(1, ETHERTYPE_IPV4, &ipv4(20, 40, 40)),
+                Ok(()),
+            ),
+            (
+                "short tagged ipv4",
+                frame(1, ETHERTYPE_IPV4, &ipv4(20, 40, 39)),
+                Err(Truncated),
+            ),
+            ("ipv6", frame(0, ETHERTYPE_IPV6, &ipv6(0, 40)), Ok(())),
+            (
+                "ipv6 payload",
+                frame(0, ETHERTYPE_IPV6, &ipv6(8, 48)),
+                Ok(()),
+            ),
+            (
+                "short ipv6",
+                frame(0, ETHERTYPE_IPV6, &ipv6(8, 47)),
+                Err(Truncated),
+            ),
+            (
+                "cut ipv6 header",
+                frame(0, ETHERTYPE_IPV6, &[0x60, 0, 0, 0, 0]),
+                Err(Truncated),
+            ),
+            (
+                "tagged ipv6",
+                frame(1, ETHERTYPE_IPV6, &ipv6(8, 48)),
+                Ok(()),
+            ),
+            (
+                "short tagged ipv6",
+                frame(1, ETHERTYPE_IPV6, &ipv6(8, 47)),
+                Err(Truncated),
+            ),
+        ];
+        for (name, frame, expected) in cases {
+            assert_eq!(validate(&frame), expected, "{name}");
+            assert_eq!(parse(&frame).err(), expected.err(), "{name}");
+        }
+    }
+}
@@ -0,0 +1,1170 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail};
+use hwaddr::HwAddr;
+use log::{debug, error, info, trace};
+use netem_rs::{Actor, ActorContext, DataView, Frame};
+use smallvec::SmallVec;
+use tokio::time::{sleep_until, Instant};
+
+use crate::{
+    bridge::{is_multicast, MacCache, DEFAULT_AGING},
+    control::{
+        ActorState, ControlView, LinkOverrides, LinkStats, PortCounters, PortIds, RemovedPorts,
+    },
+    ecn::{ether_payload, ETHERTYPE_IPV4, ETHERTYPE_IPV6},
+    ether::{self, mac_at},
+    ip::{
+        dont_fragment, icmp_error, icmp_frame, icmpv6_frame, source_address, IcmpError,
+        IcmpLimiter, DEFAULT_TTL,
+    },
+    link::{Link, LinkParams},
+    multicast::{group_events, is_control_group},
+    netem::{parse_duration, NetemConfig},
+    stp::{Bpdu, BridgeId, PortState, BPDU_GROUP, DEFAULT_PRIORITY},
+    topology::LinkChange,
+    vlan::{self, Vid, VlanConfig},
+};
+
+/// Configuration shared by every `ForwardActor`.
+#[derive(Debug)]
+pub struct ForwardConfig {
+    /// Parameters of the links between nodes that have no link of their own.
+    pub default: LinkParams,
+    /// MAC address of every node of the topology. Empty when the nodes are
+    /// not known up front, as in remote mode.
+    pub nodes: Vec<HwAddr>,
+    /// Parameters of each direction of the declared links, keyed by the
+    /// source and destination node.
+    pub links: HashMap<(HwAddr, HwAddr), LinkParams>,
+    /// Changes of the links over time, sorted by time.
+    pub changes: Vec<LinkChange>,
+    /// Start of the run, the origin of the times of `changes`.
+    pub start: Instant,
+    /// Time after which the bridge forgets a learned address.
+    pub mac_aging: Duration,
+    /// Addresses that are always behind the port of a node, keyed by
+    /// address.
+    pub static_macs: HashMap<HwAddr, HwAddr>,
+    /// Whether multicast frames only go to the ports that joined their
+    /// group, as learned by IGMP and MLD snooping, instead of every port.
+    pub snooping: bool,
+    /// VLAN modes of the ports. The bridge ignores VLANs when empty.
+    pub vlans: VlanConfig,
+    /// MAC address of every port the frames of a VLAN are flooded to in
+    /// remote mode, where `nodes` is empty.
+    pub vlan_ports: Vec<HwAddr>,
+    /// Id of the bridge in the spanning tree, `None` to not run the
+    /// spanning tree protocol.
+    pub stp: Option<BridgeId>,
+}
+
+impl Default for ForwardConfig {
+    fn default() -> Self {
+        Self {
+            default: LinkParams::default(),
+            nodes: Vec::new(),
+            links: HashMap::new(),
+            changes: Vec::new(),
+            start: Instant::now(),
+            mac_aging: DEFAULT_AGING,
+            static_macs: HashMap::new(),
+            snooping: false,
+            vlans: VlanConfig::default(),
+            vlan_ports: Vec::new(),
+            stp: None,
+        }
+    }
+}
+
+impl ForwardConfig {
+    /// Read the default impairments from the `NETEM` environment variable,
+    /// written like the options of `tc qdisc add ... netem`, and the default
+    /// rate shaping from `SHAPER`, written like the options of `tc tbf`.
+    /// `MAC_AGING` sets the aging time of the bridge, and `MCAST_SNOOPING`
+    /// turns on IGMP and MLD snooping when set to `1`, `true`, `yes` or `on`.
+    /// `STP` turns on the spanning tree protocol the same way, with a bridge
+    /// id made of `STP_PRIORITY` (32768 by default) and `BRIDGE_MAC`, a
+    /// random locally administered address by default.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let netem = match std::env::var("NETEM") {
+            Ok(opts) => opts.parse()?,
+            Err(_) => NetemConfig::default(),
+        };
+        let shaper = match std::env::var("SHAPER") {
+            Ok(opts) if !opts.is_empty() => Some(opts.parse()?),
+            _ => None,
+        };
+        let mac_aging = match std::env::var("MAC_AGING") {
+            Ok(aging) => parse_duration(&aging)?,
+            Err(_) => DEFAULT_AGING,
+        };
+        let snooping = env_flag("MCAST_SNOOPING");
+        let stp = if env_flag("STP") {
+            Some(bridge_id_from_env()?)
+        } else {
+            None
+        };
+        Ok(Self {
+            default: LinkParams {
+                netem,
+                shaper,
+                ..Default::default()
+            },
+            mac_aging,
+            snooping,
+            stp,
+            ..Default::default()
+        })
+    }
+
+    /// Parameters of the link from `source` to `destination`. Those set
+    /// through the control service come first, then those of the scenario
+    /// changes applied so far, then the declared ones and the defaults.
+    pub fn link_params<'a>(
+        &'a self,
+        overrides: &'a LinkOverrides,
+        changed: &'a HashMap<(HwAddr, HwAddr), LinkParams>,
+        source: HwAddr,
+        destination: HwAddr,
+    ) -> &'a LinkParams {
+        let key = (source, destination);
+        overrides
+            .links
+            .get(&key)
+            .or_else(|| changed.get(&key))
+            .or_else(|| self.links.get(&key))
+            .or(overrides.default.as_ref())
+            .unwrap_or(&self.default)
+    }
+
+    /// Parameters set by the changes of the scenario that are due at `now`,
+    /// the latest one of each link winning.
+    pub fn changed_at(&self, now: Instant) -> HashMap<(HwAddr, HwAddr), LinkParams> {
+        self.changes
+            .iter()
+            .take_while(|change| self.start + change.at <= now)
+            .map(|change| ((change.source, change.destination), change.params.clone()))
+            .collect()
+    }
+}
+
+/// Whether the variable `name` is set to `1`, `true`, `yes` or `on`.
+fn env_flag(name: &str) -> bool {
+    std::env::var(name).is_ok_and(|value| matches!(value.as_str(), "1" | "true" | "yes" | "on"))
+}
+
+fn bridge_id_from_env() -> anyhow::Result<BridgeId> {
+    let priority = match std::env::var("STP_PRIORITY") {
+        Ok(priority) => priority
+            .parse()
+            .map_err(|_| anyhow!("invalid STP_PRIORITY {priority}"))?,
+        Err(_) => DEFAULT_PRIORITY,
+    };
+    // The low 12 bits of the priority are the VLAN of the tree in 802.1D.
+    if priority % 4096 != 0 {
+        bail!("STP_PRIORITY {priority} is not a multiple of 4096");
+    }
+    let mac = match std::env::var("BRIDGE_MAC") {
+        Ok(mac) => mac
+            .parse()
+            .map_err(|_| anyhow!("invalid BRIDGE_MAC {mac}"))?,
+        Err(_) => {
+            let mut octets: [u8; 6] = rand::random();
+            // Unicast and locally administered.
+            octets[0] = (octets[0] & 0xfc) | 0x02;
+            HwAddr::from(octets)
+        }
+    };
+    Ok(BridgeId::new(priority, mac))
+}
+
+static FORWARD_CONFIG: OnceLock<ForwardConfig> = OnceLock::new();
+
+/// The configuration set by `init_forward_config`, or the default one.
+pub fn forward_config() -> &'static ForwardConfig {
+    FORWARD_CONFIG.get_or_init(ForwardConfig::default)
+}
+
+/// Must be called before the runtime starts so that every `ForwardActor`
+/// picks it up.
+pub fn init_forward_config(config: ForwardConfig) -> anyhow::Result<()> {
+    FORWARD_CONFIG
+        .set(config)
+        .map_err(|_| anyhow::anyhow!("forward config is already set"))
+}
+
+/// A frame on its way to `destination`. It is copied out of the UMEM while it
+/// waits in the impairment stage, so that long delays do not starve the
+/// receive ring of frames. The copy is shared by the links a flooded frame
+/// goes through and by the duplicates of netem, and only copied again when
+/// one of them modifies it or sends it while another still holds it.
+#[derive(Clone)]
+pub(crate) struct Egress {
+    destination: HwAddr,
+    data: Arc<Vec<u8>>,
+}
+
+impl Egress {
+    /// The data of the frame, copied only if it is still shared.
+    fn into_data(self) -> Vec<u8> {
+        Arc::try_unwrap(self.data).unwrap_or_else(|data| data.as_ref().clone())
+    }
+}
+
+impl AsRef<[u8]> for Egress {
+    fn as_ref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl AsMut<[u8]> for Egress {
+    fn as_mut(&mut self) -> &mut [u8] {
+        Arc::make_mut(&mut self.data).as_mut_slice()
+    }
+}
+
+/// How often the statistics of the links are logged.
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often an idle actor looks for changes made through the control
+/// service, and how often it publishes the statistics of its links.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Most source addresses an actor keeps track of.
+const MAX_SOURCES: usize = 64;
+
+/// Most links an actor keeps. Links are from the port of the actor once it
+/// is known, but from the source of each frame before.
+const MAX_LINKS: usize = 256;
+
+/// Frames of the current batch going straight to a port, with their total
+/// size.
+#[derive(Default)]
+struct PortBatch {
+    frames: SmallVec<[Frame; 32]>,
+    bytes: u64,
+}
+
+pub struct ForwardActor {
+    pub(crate) context: ActorContext<ControlView>,
+    config: &'static ForwardConfig,
+    pub(crate) control: ControlView,
+    pub(crate) id: u64,
+    /// The address the port of the actor is registered with, once the node
+    /// attached to it sent a frame.
+    pub(crate) port: Option<HwAddr>,
+    pub(crate) state: Arc<ActorState>,
+    /// Version of the control state that `overrides` and `removed` come
+    /// from.
+    control_version: u64,
+    overrides: LinkOverrides,
+    removed: Arc<RemovedPorts>,
+    links: HashMap<(HwAddr, HwAddr), Link<Egress>>,
+    /// Parameters set by the changes applied so far, for the links that are
+    /// not created yet.
+    changed: HashMap<(HwAddr, HwAddr), LinkParams>,
+    /// Index of the next change to apply.
+    next_change: usize,
+    sources: HashSet<HwAddr>,
+    /// The forwarding database as of the current batch.
+    macs: MacCache,
+    /// Destinations looked up in the port table during the current batch,
+    /// with whether a port is registered with them.
+    resolved: Vec<(HwAddr, bool)>,
+    /// Frames to send to every other port, in one pass over the ports.
+    flood: Vec<Arc<Vec<u8>>>,
+    /// Frames of the current batch going straight to a port, by port.
+    batches: HashMap<HwAddr, PortBatch>,
+    /// Frames dropped by the links as of the last poll, and by the links
+    /// forgotten to make room for others.
+    link_drops: u64,
+    evicted_drops: u64,
+    /// State of the port in the spanning tree, and the ports that do not
+    /// forward by address and by id, as of the last poll.
+    stp_state: PortState,
+    blocked: Vec<HwAddr>,
+    blocked_ids: PortIds,
+    stp_deadline: Option<Instant>,
+    pub(crate) icmp_limiter: IcmpLimiter,
+    last_report: Instant,
+    next_poll: Instant,
+}
+
+impl ForwardActor {
+    pub(crate) fn new(context: ActorContext<ControlView>) -> Self {
+        // Every view shares the state of the process, the one of the context
+        // included.
+        let control = ControlView::new();
+        let (id, state) = control.register(context.receive_handle.port_id());
+        // Ports start discarding when the spanning tree protocol runs.
+        let stp_state = match control.stp() {
+            Some(mut stp) => {
+                stp.add_port(id, context.receive_handle.port_id(), Instant::now());
+                stp.state(id)
+            }
+            None => PortState::Forwarding,
+        };
+        let macs = MacCache::new(control.mac_table());
+        Self {
+            context,
+            config: forward_config(),
+            id,
+            state,
+            port: None,
+            control_version: control.version(),
+            overrides: control.overrides(),
+            removed: control.removed_ports(),
+            control,
+            links: HashMap::new(),
+            changed: HashMap::new(),
+            next_change: 0,
+            sources: HashSet::new(),
+            macs,
+            resolved: Vec::new(),
+            flood: Vec::new(),
+            batches: HashMap::new(),
+            link_drops: 0,
+            evicted_drops: 0,
+            stp_state,
+            blocked: Vec::new(),
+            blocked_ids: PortIds::default(),
+            stp_deadline: None,
+            icmp_limiter: IcmpLimiter::new(Instant::now()),
+            last_report: Instant::now(),
+            next_poll: Instant::now(),
+        }
+    }
+
+    pub(crate) fn link(&mut self, source: HwAddr, destination: HwAddr) -> &mut Link<Egress> {
+        if self.links.len() >= MAX_LINKS && !self.links.contains_key(&(source, destination)) {
+            self.evict_links();
+        }
+        let config = self.config;
+        let (overrides, changed, control) = (&self.overrides, &self.changed, &self.control);
+        self.links.entry((source, destination)).or_insert_with(|| {
+            let params = config.link_params(overrides, changed, source, destination);
+            let shaper = params
+                .shaper
+                .as_ref()
+                .map(|shaper| control.shapers().get(destination, shaper, Instant::now()));
+            Link::new(params.clone(), shaper)
+        })
+    }
+
+    /// Make room for a link by forgetting the links without frames in flight
+    /// from other sources than the port of the actor, or every link without
+    /// frames in flight if that is not enough.
+    fn evict_links(&mut self) {
+        let port = self.port;
+        let mut evicted = 0;
+        self.links.retain(|&(source, _), link| {
+            let keep = Some(source) == port || link.next_deadline().is_some();
+            if !keep {
+                evicted += link_drops(link);
+            }
+            keep
+        });
+        if self.links.len() >= MAX_LINKS {
+            self.links.retain(|_, link| {
+                let keep = link.next_deadline().is_some();
+                if !keep {
+                    evicted += link_drops(link);
+                }
+                keep
+            });
+        }
+        trace!(
+            "actor {}: {} links left after eviction",
+            self.id,
+            self.links.len()
+        );
+        self.evicted_drops += evicted;
+    }
+
+    /// Give every link the parameters it should have now.
+    fn update_links(&mut self, now: Instant) {
+        for (&(source, destination), link) in &mut self.links {
+            let params =
+                self.config
+                    .link_params(&self.overrides, &self.changed, source, destination);
+            if params != link.params() {
+                let shaper = params
+                    .shaper
+                    .as_ref()
+                    .map(|shaper| self.control.shapers().get(destination, shaper, now));
+                link.set_params(params.clone(), shaper, now);
+            }
+        }
+    }
+
+    /// Pick up the changes made through the control service, returning
+    /// whether the actor should keep running.
+    fn poll_control(&mut self, now: Instant) -> bool {
+        if self.state.is_deleted() {
+            return false;
+        }
+        let version = self.control.version();
+        if version != self.control_version {
+            self.control_version = version;
+            self.overrides = self.control.overrides();
+            self.removed = self.control.removed_ports();
+            self.update_links(now);
+        }
+        if now >= self.next_poll {
+            self.next_poll = now + POLL_INTERVAL;
+            self.publish();
+        }
+        true
+    }
+
+    /// Publish the statistics of the links, and count their new drops.
+    fn publish(&mut self) {
+        let links: Vec<_> = self
+            .links
+            .iter()
+            .map(|(&(source, destination), link)| LinkStats {
+                source,
+                destination,
+                netem: link.netem().stats(),
+                loss: link.netem().loss_stats(),
+                shaper: link.shaper_stats(),
+                down_drops: link.down_drops(),
+                oversize_drops: link.oversize_drops(),
+            })
+            .collect();
+        let drops = self.evicted_drops + self.links.values().map(link_drops).sum::<u64>();
+        PortCounters::add(
+            &self.state.counters.drops,
+            u64::saturating_sub(drops, self.link_drops),
+        );
+        self.link_drops = drops;
+        self.state
+            .publish(self.sources.iter().copied().collect(), links);
+    }
+
+    /// Apply the changes of the scenario that are due.
+    fn apply_changes(&mut self, now: Instant) {
+        let config = self.config;
+        while let Some(change) = config.changes.get(self.next_change) {
+            if config.start + change.at > now {
+                break;
+            }
+            self.next_change += 1;
+            let key = (change.source, change.destination);
+            info!(
+                "{} -> {}: changed at {:?}{}",
+                change.source,
+                change.destination,
+                change.at,
+                if change.params.down { ", down" } else { "" }
+            );
+            self.changed.insert(key, change.params.clone());
+            self.update_links(now);
+        }
+    }
+
+    /// Find out the address the port of the actor is registered with, from
+    /// the source of a frame received from it.
+    pub(crate) async fn find_port(&mut self, source: HwAddr) {
+        if self.port.is_none()
+            && self
+                .context
+                .port_table
+                .get_send_handle(source)
+                .await
+                .is_some()
+        {
+            // The node attached to the port sends with the address the port
+            // is registered with.
+            trace!("actor {} is the port of {source}", self.id);
+            self.port = Some(source);
+            self.control.set_port_mac(self.id, source);
+            if let Some(mut stp) = self.control.stp() {
+                stp.set_mac(self.id, source);
+            }
+        }
+    }
+
+    /// Take a BPDU received from the port of the actor into account.
+    fn receive_bpdu(&self, frame: &[u8], now: Instant) {
+        let Some(bpdu) = Bpdu::parse(frame) else {
+            trace!("actor {}: not a configuration BPDU", self.id);
+            return;
+        };
+        let flush = self
+            .control
+            .stp()
+            .is_some_and(|mut stp| stp.receive(self.id, &bpdu, now));
+        if flush {
+            self.control.mac_table().flush();
+        }
+    }
+
+    /// Move the spanning tree on, pick up the state of the ports and send
+    /// a BPDU out of the port of the actor if it is time to.
+    async fn poll_stp(&mut self, now: Instant) -> anyhow::Result<()> {
+        let (flush, hello, bridge) = {
+            let Some(mut stp) = self.control.stp() else {
+                return Ok(());
+            };
+            let flush = stp.update(now);
+            let hello = stp.hello(self.id, now);
+            self.stp_state = stp.state(self.id);
+            self.blocked = stp.blocked_macs();
+            self.blocked_ids = stp.blocked_port_ids();
+            self.stp_deadline = stp.next_deadline(self.id);
+            (flush, hello, stp.bridge())
+        };
+        if flush {
+            self.control.mac_table().flush();
+        }
+        let Some(bpdu) = hello else {
+            return Ok(());
+        };
+        let frame = bpdu.to_frame(bridge.mac());
+        let self_port_id = self.context.receive_handle.port_id();
+        self.context
+            .port_table
+            .for_each_port(|&port_id, send_handle| {
+                if port_id != self_port_id {
+                    return Ok(());
+                }
+                send_handle.send_raw_data(frame.clone())
+            })
+            .await
+    }
+
+    /// Whether the spanning tree lets frames out through `port`.
+    fn forwards(&self, port: HwAddr) -> bool {
+        !self.blocked.contains(&port)
+    }
+
+    /// The VLAN of a frame received from the port of the actor, `None` if
+    /// the port does not admit it.
+    fn ingress_vlan(&self, source: HwAddr, frame: &[u8]) -> Option<Vid> {
+        let vlans = &self.config.vlans;
+        if !vlans.is_enabled() {
+            return Some(0);
+        }
+        vlans
+            .mode(self.port.unwrap_or(source))
+            .ingress(vlan::tag(frame))
+    }
+
+    /// The VLAN tag a frame of `vlan` goes out through `port` with, `None`
+    /// if the port is not in the VLAN. Tags are kept as they are when the
+    /// bridge is not VLAN-aware.
+    fn egress_tag(&self, vlan: Vid, port: HwAddr, frame: &[u8]) -> Option<Option<Vid>> {
+        let vlans = &self.config.vlans;
+        if !vlans.is_enabled() {
+            return Some(vlan::tag(frame));
+        }
+        let mode = vlans.mode(port);
+        mode.carries(vlan).then(|| mode.egress_tag(vlan))
+    }
+
+    /// Learn that `source` is behind the port of the actor in `vlan`.
+    fn learn(&mut self, vlan: Vid, source: HwAddr, now: Instant) {
+        if let Some(port) = self.port {
+            self.macs
+                .learn(self.control.mac_table(), vlan, source, port, now);
+        }
+    }
+
+    /// Learn the groups a multicast frame joins or leaves for the port of
+    /// the actor.
+    fn snoop(&self, vlan: Vid, frame: &[u8], now: Instant) {
+        let Some(port) = self.port else {
+            return;
+        };
+        for event in group_events(frame) {
+            trace!("{port}: {event:?} in vlan {vlan}");
+            self.control.groups().update(vlan, event, port, now);
+        }
+    }
+
+    /// The ports that joined the group of `destination` in `vlan`, `None` to
+    /// flood the frame.
+    fn group_ports(&self, vlan: Vid, destination: HwAddr, now: Instant) -> Option<Vec<HwAddr>> {
+        if !self.config.snooping || destination.is_broadcast() || is_control_group(destination) {
+            return None;
+        }
+        self.control.groups().ports(vlan, destination, now)
+    }
+
+    /// The port to send a frame of `vlan` for `destination` to, `None` to
+    /// flood it.
+    async fn egress_port(
+        &mut self,
+        vlan: Vid,
+        destination: HwAddr,
+        now: Instant,
+    ) -> Option<HwAddr> {
+        if is_multicast(destination) {
+            return None;
+        }
+        if let Some(port) = self
+            .macs
+            .lookup(self.control.mac_table(), vlan, destination, now)
+        {
+            return Some(port);
+        }
+        // Ports are registered with the address of their node, which is
+        // looked up once per batch.
+        if let Some(&(_, found)) = self.resolved.iter().find(|(mac, _)| *mac == destination) {
+            return found.then_some(destination);
+        }
+        let found = self
+            .context
+            .port_table
+            .get_send_handle(destination)
+            .await
+            .is_some();
+        self.resolved.push((destination, found));
+        found.then_some(destination)
+    }
+
+    /// Pass a copy of the frame through the link towards `destination`.
+    pub(crate) async fn forward_data(
+        &mut self,
+        source: HwAddr,
+        destination: HwAddr,
+        data: Arc<Vec<u8>>,
+        now: Instant,
+    ) -> anyhow::Result<()> {
+        let link = self.link(source, destination);
+        let egress = Egress { destination, data };
+        if link.is_passthrough() {
+            self.send(egress).await
+        } else {
+            link.enqueue(egress, now);
+            Ok(())
+        }
+    }
+
+    /// Whether the link from `source` to `destination` lets `frame` through
+    /// as it is, or `None` if the frame does not fit in the MTU of the link.
+    /// A frame that does not is dropped, and its sender told with an ICMP
+    /// error when the link has an address to send it from.
+    async fn admit(
+        &mut self,
+        source: HwAddr,
+        destination: HwAddr,
+        frame: &[u8],
+        now: Instant,
+    ) -> anyhow::Result<Option<bool>> {
+        let link = self.link(source, destination);
+        if link.fits(frame) {
+            return Ok(Some(link.is_passthrough()));
+        }
+        let params = link.params();
+        let mtu = params.mtu.unwrap_or_default();
+        trace!(
+            "{source} -> {destination}: dropped a {} bytes frame over the mtu {mtu}",
+            frame.len()
+        );
+        let Some(reply) = too_big_reply(frame, mtu, &params.icmp_from) else {
+            return Ok(None);
+        };
+        if self.icmp_limiter.allow(now) {
+            self.send_to(self.port.unwrap_or(source), reply).await?;
+        }
+        Ok(None)
+    }
+
+    /// Send a frame of the emulator itself out through `port`.
+    pub(crate) async fn send_to(&self, port: HwAddr, frame: Vec<u8>) -> anyhow::Result<()> {
+        let counters = &self.state.counters;
+        if let Some(handle) = self.context.port_table.get_send_handle(port).await {
+            PortCounters::add(&counters.tx_frames, 1);
+            PortCounters::add(&counters.tx_bytes, frame.len() as u64);
+            handle.send_raw_data(frame)?;
+        } else {
+            PortCounters::add(&counters.drops, 1);
+            error!("no send handle for {port}");
+        }
+        Ok(())
+    }
+
+    /// Send a frame, or queue it with the frames to flood if it is for
+    /// every port.
+    async fn send(&mut self, egress: Egress) -> anyhow::Result<()> {
+        if egress.destination.is_broadcast() {
+            self.flood.push(egress.data);
+            return Ok(());
+        }
+        // Flooded frames leave before the ones that came after them.
+        self.flush_flood().await?;
+        let counters = &self.state.counters;
+        if self.removed.contains(egress.destination) {
+            trace!("{} was removed", egress.destination);
+            PortCounters::add(&counters.drops, 1);
+            return Ok(());
+        }
+        if let Some(handle) = self
+            .context
+            .port_table
+            .get_send_handle(egress.destination)
+            .await
+        {
+            let data = egress.into_data();
+            PortCounters::add(&counters.tx_frames, 1);
+            PortCounters::add(&counters.tx_bytes, data.len() as u64);
+            handle.send_raw_data(data)?;
+        } else {
+            PortCounters::add(&counters.drops, 1);
+            error!("no send handle for {}", egress.destination);
+        }
+        Ok(())
+    }
+
+    /// Send the queued frames to every port but the one of the actor, each
+    /// port getting them in a row. The send handles take ownership of what
+    /// they send, so every port but one gets a copy of the buffers, and the
+    /// first port found takes the buffers themselves in a second pass over
+    /// the ports.
+    async fn flush_flood(&mut self) -> anyhow::Result<()> {
+        if self.flood.is_empty() {
+            return Ok(());
+        }
+        if self.config.vlans.is_enabled() {
+            return self.flush_vlan_flood().await;
+        }
+        let self_port_id = self.context.receive_handle.port_id();
+        let counters = &self.state.counters;
+        let (removed, blocked) = (&self.removed, &self.blocked_ids);
+        let mut flood = std::mem::take(&mut self.flood);
+        let len: u64 = flood.iter().map(|data| data.len() as u64).sum();
+        trace!("flood {} frames", flood.len());
+        let owner = Mutex::new(None);
+        self.context
+            .port_table
+            .for_each_port(|&port_id, send_handle| {
+                if port_id == self_port_id
+                    || removed.contains_id(&port_id)
+                    || blocked.contains(&port_id)
+                {
+                    return Ok(());
+                }
+                PortCounters::add(&counters.tx_frames, flood.len() as u64);
+                PortCounters::add(&counters.tx_bytes, len);
+                let mut owner = owner.lock().unwrap();
+                if owner.is_none() {
+                    *owner = Some(port_id);
+                    return Ok(());
+                }
+                flood
+                    .iter()
+                    .try_for_each(|data| send_handle.send_raw_data(data.as_ref().clone()))
+            })
+            .await?;
+        if let Some(owner) = owner.into_inner().unwrap() {
+            // Not copied unless a link still holds them.
+            let owned: Vec<_> = flood.drain(..).map(Arc::unwrap_or_clone).collect();
+            let owned = Mutex::new(owned);
+            self.context
+                .port_table
+                .for_each_port(|&port_id, send_handle| {
+                    if port_id != owner {
+                        return Ok(());
+                    }
+                    std::mem::take(&mut *owned.lock().unwrap())
+                        .into_iter()
+                        .try_for_each(|data| send_handle.send_raw_data(data))
+                })
+                .await?;
+        }
+        // Keep the allocation for the next batch.
+        self.flood = flood;
+        self.flood.clear();
+        Ok(())
+    }
+
+    /// Add a frame of `len` bytes to the batch of `port`.
+    fn batch(&mut self, port: HwAddr, frame: Frame, len: u64) {
+        let batch = self.batches.entry(port).or_default();
+        batch.frames.push(frame);
+        batch.bytes += len;
+    }
+
+    /// Send the frames batched for each port in one call per port, after
+    /// the frames flooded before them.
+    async fn send_batches(&mut self) -> anyhow::Result<()> {
+        if self.batches.is_empty() {
+            return Ok(());
+        }
+        self.flush_flood().await?;
+        let counters = &self.state.counters;
+        for (port, batch) in self.batches.drain() {
+            let count = batch.frames.len() as u64;
+            if let Some(handle) = self.context.port_table.get_send_handle(port).await {
+                PortCounters::add(&counters.tx_frames, count);
+                PortCounters::add(&counters.tx_bytes, batch.bytes);
+                handle.send_frame(batch.frames)?;
+            } else {
+                PortCounters::add(&counters.drops, count);
+                error!("no send handle for {port}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Send each queued frame to the ports of its VLAN but the one of the
+    /// actor, tagged as each port wants it. Ports without a send handle,
+    /// whose node did not join yet, are skipped.
+    async fn flush_vlan_flood(&mut self) -> anyhow::Result<()> {
+        let flood = std::mem::take(&mut self.flood);
+        let config = self.config;
+        let counters = &self.state.counters;
+        trace!("flood {} frames in their vlan", flood.len());
+        for data in &flood {
+            let source = mac_at(data, 6);
+            let Some(vlan) = self.ingress_vlan(source, data) else {
+                PortCounters::add(&counters.vlan_drops, 1);
+                continue;
+            };
+            let from = self.port.unwrap_or(source);
+            let mut copies = Vec::new();
+            for (port, tag) in config.vlans.members(&config.vlan_ports, vlan) {
+                if port == from || !self.forwards(port) || self.removed.contains(port) {
+                    continue;
+                }
+                let Some(handle) = self.context.port_table.get_send_handle(port).await else {
+                    continue;
+                };
+                let data = tagged_copy(&mut copies, data, tag);
+                PortCounters::add(&counters.tx_frames, 1);
+                PortCounters::add(&counters.tx_bytes, data.len() as u64);
+                handle.send_raw_data(data.as_ref().clone())?;
+            }
+        }
+        // Keep the allocation for the next batch.
+        self.flood = flood;
+        self.flood.clear();
+        Ok(())
+    }
+
+    /// Log the losses of every lossy link next to the rate its model should
+    /// give, along with the length of the loss bursts. Also forget the
+    /// addresses that aged out.
+    fn report(&mut self, now: Instant) {
+        if now.duration_since(self.last_report) < REPORT_INTERVAL {
+            return;
+        }
+        self.last_report = now;
+        self.control.mac_table().expire(now);
+        self.control.groups().expire(now);
+        self.control.neighbors().expire(now);
+        for ((source, destination), link) in &self.links {
+            let model = &link.params().netem.loss;
+            if model.is_lossless() {
+                continue;
+            }
+            let stats = link.netem().loss_stats();
+            info!(
+                "{source} -> {destination}: lost {}/{} frames ({:.3}%, expected {:.3}%), {} bursts of {:.2} frames on average (max {}), {:.1} frames between bursts",
+                stats.lost,
+                stats.frames,
+                stats.loss_rate() * 100.0,
+                model.expected_loss_rate() * 100.0,
+                stats.bursts,
+                stats.mean_burst_length(),
+                stats.max_burst,
+                stats.mean_gap_length(),
+            );
+        }
+    }
+
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        let change = self
+            .config
+            .changes
+            .get(self.next_change)
+            .map(|change| self.config.start + change.at);
+        self.links
+            .values()
+            .filter_map(Link::next_deadline)
+            .chain(change)
+            .chain(self.stp_deadline)
+            .chain([self.next_poll])
+            .min()
+    }
+
+    /// Do what is due besides receiving frames: pick up the changes of the
+    /// control service and the scenario, release the frames the links are
+    /// done with and report. Returns whether the actor should keep running.
+    pub(crate) async fn tick(&mut self, now: Instant) -> anyhow::Result<bool> {
+        if !self.poll_control(now) {
+            info!("actor {} deleted", self.id);
+            self.control.unregister(self.id);
+            return Ok(false);
+        }
+        self.apply_changes(now);
+        self.poll_stp(now).await?;
+        self.release(now).await?;
+        self.report(now);
+        Ok(true)
+    }
+
+    async fn release(&mut self, now: Instant) -> anyhow::Result<()> {
+        let mut ready = Vec::new();
+        for link in self.links.values_mut() {
+            while let Some(egress) = link.dequeue(now) {
+                ready.push(egress);
+            }
+        }
+        for egress in ready {
+            self.send(egress).await?;
+        }
+        self.flush_flood().await
+    }
+}
+
+/// The frames `link` dropped. The shapers are shared with other links, each
+/// link counting the drops it saw.
+fn link_drops(link: &Link<Egress>) -> u64 {
+    link.netem().stats().dropped + link.shaper_drops() + link.down_drops() + link.oversize_drops()
+}
+
+/// The ICMP error telling the sender of `frame` that it does not fit in
+/// `mtu`, from the address of `addresses` of its family, or `None` if the
+/// frame is dropped silently.
+fn too_big_reply(frame: &[u8], mtu: usize, addresses: &[IpAddr]) -> Option<Vec<u8>> {
+    let (offset, ethertype) = ether_payload(frame)?;
+    let packet = &frame[offset..];
+    let may_fragment = match ethertype {
+        ETHERTYPE_IPV4 => !dont_fragment(packet),
+        ETHERTYPE_IPV6 => false,
+        _ => return None,
+    };
+    let destination = mac_at(frame, 0);
+    if may_fragment || is_multicast(destination) {
+        return None;
+    }
+    let message = icmp_error(IcmpError::TooBig(mtu), packet)?;
+    let sender = source_address(packet)?;
+    // From the MAC the frame was sent to, that of the router that would
+    // have sent the error in a real network.
+    let macs = (mac_at(frame, 6), destination);
+    let reply = match sender {
+        IpAddr::V4(sender) => {
+            let source = addresses.iter().find_map(|address| match address {
+                IpAddr::V4(address) => Some(*address),
+                IpAddr::V6(_) => None,
+            })?;
+            icmp_frame(macs, source, sender, &message)
+        }
+        IpAddr::V6(sender) => {
+            let source = addresses.iter().find_map(|address| match address {
+                IpAddr::V6(address) => Some(*address),
+                IpAddr::V4(_) => None,
+            })?;
+            icmpv6_frame(macs, source, sender, DEFAULT_TTL, &message)
+        }
+    };
+    // Back in the VLAN the frame came from.
+    Some(match vlan::tag(frame) {
+        Some(vid) => vlan::retag(&reply, Some(vid)),
+        None => reply,
+    })
+}
+
+/// A copy of `frame` with the VLAN tag `tag`, shared by the ports it leaves
+/// through with that tag.
+fn tagged_copy(
+    copies: &mut Vec<(Option<Vid>, Arc<Vec<u8>>)>,
+    frame: &[u8],
+    tag: Option<Vid>,
+) -> Arc<Vec<u8>> {
+    if let Some((_, data)) = copies.iter().find(|(copy_tag, _)| *copy_tag == tag) {
+        return data.clone();
+    }
+    let data = if vlan::tag(frame) == tag {
+        frame.to_vec()
+    } else {
+        vlan::retag(frame, tag)
+    };
+    let data = Arc::new(data);
+    copies.push((tag, data.clone()));
+    data
+}
+
+impl Actor for ForwardActor {
+    type C = ControlView;
+
+    fn new(context: ActorContext<Self::C>) -> Self {
+        Self::new(context)
+    }
+
+    async fn run(&mut self) -> anyhow::Result<()> {
+        // The counters are borrowed while `self` is.
+        let state = self.state.clone();
+        let broadcast = HwAddr::from([0xff; 6]);
+        loop {
+            let deadline = self.next_deadline();
+            tokio::select! {
+                frames = self.context.receive_handle.receive_frames() => {
+                    let now = Instant::now();
+                    self.macs.refresh(self.control.mac_table());
+                    self.resolved.clear();
+                    for frame in frames? {
+                        let data = frame.data_ref();
+                        let counters = &state.counters;
+                        let len = data.as_ref().len() as u64;
+                        PortCounters::add(&counters.rx_frames, 1);
+                        PortCounters::add(&counters.rx_bytes, len);
+                        let packet = match ether::parse(data.as_ref()) {
+                            Ok(packet) => packet,
+                            Err(reason) => {
+                                debug!("actor {}: dropped a {len} bytes frame, {reason}", self.id);
+                                PortCounters::add(counters.malformed(reason), 1);
+                                continue;
+                            }
+                        };
+                        let source = packet.source();
+                        let destination = packet.destination();
+                        if destination.is_broadcast() {
+                            PortCounters::add(&counters.broadcast, 1);
+                        }
+                        if self.sources.len() < MAX_SOURCES {
+                            self.sources.insert(source);
+                        }
+                        self.find_port(source).await;
+                        if self.config.stp.is_some() && destination.octets() == BPDU_GROUP {
+                            self.receive_bpdu(data.as_ref(), now);
+                            continue;
+                        }
+                        if self.stp_state == PortState::Discarding {
+                            PortCounters::add(&counters.blocked, 1);
+                            continue;
+                        }
+                        let Some(vlan) = self.ingress_vlan(source, data.as_ref()) else {
+                            trace!("{source} sent a frame outside the vlans of its port");
+                            PortCounters::add(&counters.vlan_drops, 1);
+                            continue;
+                        };
+                        self.learn(vlan, source, now);
+                        if self.stp_state != PortState::Forwarding {
+                            PortCounters::add(&counters.blocked, 1);
+                            continue;
+                        }
+                        if self.config.snooping && is_multicast(destination) {
+                            self.snoop(vlan, data.as_ref(), now);
+                        }
+                        let egress_port = self.egress_port(vlan, destination, now).await;
+                        if egress_port.is_some() && egress_port == self.port {
+                            trace!("{destination} is behind the ingress port");
+                            continue;
+                        }
+                        // Links are between the nodes of the ports, whatever
+                        // host behind them sent the frame.
+                        let from = self.port.unwrap_or(source);
+                        let config = self.config;
+                        let Some(port) = egress_port else {
+                            if !is_multicast(destination) {
+                                PortCounters::add(&counters.unknown_destination, 1);
+                            }
+                            self.send_batches().await?;
+                            let group;
+                            let ports: &[HwAddr] = match self.group_ports(vlan, destination, now) {
+                                Some(ports) => {
+                                    group = ports;
+                                    &group
+                                }
+                                // Every node is reached through its own link.
+                                None if !config.nodes.is_empty() => &config.nodes,
+                                None => {
+                                    if self.admit(from, broadcast, data.as_ref(), now).await?.is_some() {
+                                        let data = Arc::new(data.as_ref().to_vec());
+                                        self.forward_data(from, broadcast, data, now).await?;
+                                    }
+                                    continue;
+                                }
+                            };
+                            // The ports the frame goes out through, with the
+                            // tag it has there and whether their link lets it
+                            // through as it is.
+                            let mut targets = Vec::new();
+                            for &port in ports {
+                                if port == from || !self.forwards(port) || self.removed.contains(port) {
+                                    continue;
+                                }
+                                let Some(tag) = self.egress_tag(vlan, port, data.as_ref()) else {
+                                    continue;
+                                };
+                                if let Some(passthrough) = self.admit(from, port, data.as_ref(), now).await? {
+                                    targets.push((port, tag, passthrough));
+                                }
+                            }
+                            // A port whose link lets the frame through as it
+                            // is takes the frame of the UMEM itself.
+                            let tag = vlan::tag(data.as_ref());
+                            let owner = targets
+                                .iter()
+                                .position(|&(_, port_tag, passthrough)| port_tag == tag && passthrough)
+                                .map(|i| targets.swap_remove(i).0);
+                            // The others share one copy for each tag the frame
+                            // leaves with, which the last port to send it
+                            // takes without copying it again.
+                            let mut copies = Vec::new();
+                            let targets: Vec<_> = targets
+                                .into_iter()
+                                .map(|(port, tag, _)| (port, tagged_copy(&mut copies, data.as_ref(), tag)))
+                                .collect();
+                            drop(copies);
+                            for (port, data) in targets {
+                                self.forward_data(from, port, data, now).await?;
+                            }
+                            if let Some(port) = owner {
+                                self.batch(port, frame, len);
+                            }
+                            continue;
+                        };
+                        if self.removed.contains(port) {
+                            trace!("{port} was removed");
+                            PortCounters::add(&counters.drops, 1);
+                            continue;
+                        }
+                        if !self.forwards(port) {
+                            trace!("{port} does not forward");
+                            PortCounters::add(&counters.blocked, 1);
+                            continue;
+                        }
+                        let Some(tag) = self.egress_tag(vlan, port, data.as_ref()) else {
+                            trace!("{port} is not in vlan {vlan}");
+                            PortCounters::add(&counters.vlan_drops, 1);
+                            continue;
+                        };
+                        let Some(passthrough) = self.admit(from, port, data.as_ref(), now).await? else {
+                            continue;
+                        };
+                        if !passthrough || tag != vlan::tag(data.as_ref()) {
+                            if passthrough {
+                                // Sent right away, after the frames batched
+                                // before it.
+                                self.send_batches().await?;
+                            }
+                            let data = tagged_copy(&mut Vec::new(), data.as_ref(), tag);
+                            self.forward_data(from, port, data, now).await?;
+                            continue;
+                        }
+                        self.batch(port, frame, len);
+                    }
+                    self.macs.commit(self.control.mac_table(), now);
+                    self.flush_flood().await?;
+                    self.send_batches().await?;
+                }
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {}
+            }
+            if !self.tick(Instant::now()).await? {
+                return Ok(());
+            }
+        }
+    }
+}
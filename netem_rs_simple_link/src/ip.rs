@@ -0,0 +1,338 @@
+//! Checksums and headers of IPv4, IPv6 and ICMP, for the packets the router
+//! forwards and the ones it sends itself.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use hwaddr::HwAddr;
+use tokio::time::Instant;
+
+use crate::{
+    ecn::{ETHERTYPE_IPV4, ETHERTYPE_IPV6},
+    ether::ETHER_HEADER_LEN,
+};
+
+pub const IPV4_HEADER_LEN: usize = 20;
+pub const IPV6_HEADER_LEN: usize = 40;
+pub const PROTO_ICMP: u8 = 1;
+pub const PROTO_ICMPV6: u8 = 58;
+/// Smallest MTUs a link may have, from RFC 791 and RFC 8200.
+pub const MIN_IPV4_MTU: usize = 68;
+pub const MIN_IPV6_MTU: usize = 1280;
+/// TTL and hop limit of the packets the router sends, as in Linux.
+pub const DEFAULT_TTL: u8 = 64;
+
+/// Type, code, checksum and 4 bytes that depend on the type.
+pub const ICMP_HEADER_LEN: usize = 8;
+pub const ICMP_ECHO_REPLY: u8 = 0;
+pub const ICMP_ECHO_REQUEST: u8 = 8;
+pub const ICMPV6_ECHO_REQUEST: u8 = 128;
+pub const ICMPV6_ECHO_REPLY: u8 = 129;
+
+const ICMP_DEST_UNREACHABLE: u8 = 3;
+const ICMP_NET_UNREACHABLE: u8 = 0;
+const ICMP_HOST_UNREACHABLE: u8 = 1;
+const ICMP_FRAG_NEEDED: u8 = 4;
+const ICMP_TIME_EXCEEDED: u8 = 11;
+/// The ICMP errors, which are never answered with another one.
+const ICMP_ERRORS: [u8; 5] = [ICMP_DEST_UNREACHABLE, 4, 5, ICMP_TIME_EXCEEDED, 12];
+const ICMPV6_DEST_UNREACHABLE: u8 = 1;
+const ICMPV6_NO_ROUTE: u8 = 0;
+const ICMPV6_ADDRESS_UNREACHABLE: u8 = 3;
+const ICMPV6_PACKET_TOO_BIG: u8 = 2;
+const ICMPV6_TIME_EXCEEDED: u8 = 3;
+/// ICMPv6 errors have the types below 128.
+const ICMPV6_INFORMATIONAL: u8 = 128;
+/// Largest ICMP error messages with their IP header, quoting as much of the
+/// packet in error as fits, as RFC 1812 and RFC 4443 ask.
+const ICMP_ERROR_MAX_LEN: usize = 576;
+const ICMPV6_ERROR_MAX_LEN: usize = MIN_IPV6_MTU;
+
+const MIN_FRAME_LEN: usize = 60;
+
+/// Add `data` to the one's complement `sum`, as 16 bits words.
+pub fn sum(data: &[u8], mut sum: u32) -> u32 {
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        sum += u32::from(u16::from_be_bytes([word[0], word[1]]));
+    }
+    if let [last] = words.remainder() {
+        sum += u32::from(*last) << 8;
+    }
+    sum
+}
+
+/// The internet checksum of RFC 1071 for a sum of words.
+pub fn fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+pub fn checksum(data: &[u8]) -> u16 {
+    fold(sum(data, 0))
+}
+
+/// The sum of the IPv6 pseudo-header of an upper-layer packet of `len`
+/// bytes.
+pub fn ipv6_pseudo_sum(
+    source: &Ipv6Addr,
+    destination: &Ipv6Addr,
+    next_header: u8,
+    len: usize,
+) -> u32 {
+    let len = len as u32;
+    sum(&destination.octets(), sum(&source.octets(), 0))
+        + (len >> 16)
+        + (len & 0xffff)
+        + u32::from(next_header)
+}
+
+/// Compute the checksum of an IPv4 header again.
+pub fn set_ipv4_checksum(header: &mut [u8]) {
+    header[10..12].fill(0);
+    let checksum = checksum(header);
+    header[10..12].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// Take one off the TTL or the hop limit of the IPv4 or IPv6 `packet`,
+/// fixing the IPv4 header checksum. Returns false, leaving the packet
+/// untouched, if it must not be forwarded any further.
+pub fn decrement_ttl(packet: &mut [u8]) -> bool {
+    let (ttl_offset, version) = match packet[0] >> 4 {
+        4 => (8, 4),
+        6 => (7, 6),
+        _ => return false,
+    };
+    if packet[ttl_offset] <= 1 {
+        return false;
+    }
+    packet[ttl_offset] -= 1;
+    if version == 4 {
+        let header_len = usize::from(packet[0] & 0x0f) * 4;
+        set_ipv4_checksum(&mut packet[..header_len]);
+    }
+    true
+}
+
+/// The length of the IPv4 or IPv6 `packet` with its header, without the
+/// padding of its frame.
+pub fn packet_len(packet: &[u8]) -> usize {
+    match packet[0] >> 4 {
+        4 => usize::from(u16::from_be_bytes([packet[2], packet[3]])),
+        6 => IPV6_HEADER_LEN + usize::from(u16::from_be_bytes([packet[4], packet[5]])),
+        _ => packet.len(),
+    }
+}
+
+/// Whether the IPv4 `packet` must not be fragmented.
+pub fn dont_fragment(packet: &[u8]) -> bool {
+    packet[6] & 0x40 != 0
+}
+
+/// Why the router could not forward a packet, as told to its sender.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IcmpError {
+    /// No route goes to the destination.
+    NoRoute,
+    /// The next hop did not answer ARP or NDP.
+    Unreachable,
+    /// The TTL or the hop limit ran out.
+    TtlExpired,
+    /// Larger than the MTU of the interface or the link out, for an IPv4
+    /// packet that must not be fragmented or an IPv6 one, which routers
+    /// never fragment.
+    TooBig(usize),
+}
+
+/// The source of the IPv4 or IPv6 `packet`.
+pub fn source_address(packet: &[u8]) -> Option<IpAddr> {
+    match packet[0] >> 4 {
+        4 => Some(Ipv4Addr::from(<[u8; 4]>::try_from(&packet[12..16]).unwrap()).into()),
+        6 => Some(Ipv6Addr::from(<[u8; 16]>::try_from(&packet[8..24]).unwrap()).into()),
+        _ => None,
+    }
+}
+
+/// The ICMP or ICMPv6 message telling the sender of `packet` about `error`,
+/// without its checksum, or `None` if the sender must not be told: when
+/// the packet is an ICMP error itself, a fragment other than the first or
+/// from an address that does not designate a single host.
+pub fn icmp_error(error: IcmpError, packet: &[u8]) -> Option<Vec<u8>> {
+    match packet[0] >> 4 {
+        4 => icmpv4_error(error, packet),
+        6 => icmpv6_error(error, packet),
+        _ => None,
+    }
+}
+
+fn icmpv4_error(error: IcmpError, packet: &[u8]) -> Option<Vec<u8>> {
+    let header_len = usize::from(packet[0] & 0x0f) * 4;
+    let fragment_offset = u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff;
+    let source = Ipv4Addr::from(<[u8; 4]>::try_from(&packet[12..16]).unwrap());
+    let is_error = packet[9] == PROTO_ICMP
+        && packet
+            .get(header_len)
+            .is_some_and(|kind| ICMP_ERRORS.contains(kind));
+    if is_error
+        || fragment_offset != 0
+        || source.is_unspecified()
+        || source.is_broadcast()
+        || source.is_multicast()
+        || source.is_loopback()
+    {
+        return None;
+    }
+    let (kind, code, rest) = match error {
+        IcmpError::NoRoute => (ICMP_DEST_UNREACHABLE, ICMP_NET_UNREACHABLE, [0; 4]),
+        IcmpError::Unreachable => (ICMP_DEST_UNREACHABLE, ICMP_HOST_UNREACHABLE, [0; 4]),
+        IcmpError::TtlExpired => (ICMP_TIME_EXCEEDED, 0, [0; 4]),
+        IcmpError::TooBig(mtu) => {
+            let [high, low] = (mtu.min(usize::from(u16::MAX)) as u16).to_be_bytes();
+            (ICMP_DEST_UNREACHABLE, ICMP_FRAG_NEEDED, [0, 0, high, low])
+        }
+    };
+    let quoted = packet_len(packet).min(ICMP_ERROR_MAX_LEN - IPV4_HEADER_LEN - ICMP_HEADER_LEN);
+    Some(error_message(kind, code, rest, &packet[..quoted]))
+}
+
+fn icmpv6_error(error: IcmpError, packet: &[u8]) -> Option<Vec<u8>> {
+    let source = Ipv6Addr::from(<[u8; 16]>::try_from(&packet[8..24]).unwrap());
+    let is_error = packet[6] == PROTO_ICMPV6
+        && packet
+            .get(IPV6_HEADER_LEN)
+            .is_some_and(|&kind| kind < ICMPV6_INFORMATIONAL);
+    if is_error || source.is_unspecified() || source.is_multicast() || source.is_loopback() {
+        return None;
+    }
+    let (kind, code, rest) = match error {
+        IcmpError::NoRoute => (ICMPV6_DEST_UNREACHABLE, ICMPV6_NO_ROUTE, [0; 4]),
+        IcmpError::Unreachable => (ICMPV6_DEST_UNREACHABLE, ICMPV6_ADDRESS_UNREACHABLE, [0; 4]),
+        IcmpError::TtlExpired => (ICMPV6_TIME_EXCEEDED, 0, [0; 4]),
+        IcmpError::TooBig(mtu) => (
+            ICMPV6_PACKET_TOO_BIG,
+            0,
+            (mtu.min(u32::MAX as usize) as u32).to_be_bytes(),
+        ),
+    };
+    let quoted = packet_len(packet).min(ICMPV6_ERROR_MAX_LEN - IPV6_HEADER_LEN - ICMP_HEADER_LEN);
+    Some(error_message(kind, code, rest, &packet[..quoted]))
+}
+
+fn error_message(kind: u8, code: u8, rest: [u8; 4], quoted: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(ICMP_HEADER_LEN + quoted.len());
+    message.extend_from_slice(&[kind, code, 0, 0]);
+    message.extend_from_slice(&rest);
+    message.extend_from_slice(quoted);
+    message
+}
+
+/// ICMP errors sent per second and at once by an actor, as `icmp_msgs_per_sec` and
+/// `icmp_msgs_burst` in Linux.
+const ICMP_RATE: f64 = 1000.0;
+const ICMP_BURST: f64 = 50.0;
+
+/// A token bucket limiting the ICMP errors sent, so that a flood of bad
+/// packets does not turn into a flood of errors.
+pub struct IcmpLimiter {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl IcmpLimiter {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            tokens: ICMP_BURST,
+            updated: now,
+        }
+    }
+
+    pub fn allow(&mut self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * ICMP_RATE).min(ICMP_BURST);
+        self.updated = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Set the addresses of `frame`.
+pub fn set_macs(frame: &mut [u8], destination: HwAddr, source: HwAddr) {
+    frame[..6].copy_from_slice(&destination.octets());
+    frame[6..12].copy_from_slice(&source.octets());
+}
+
+/// An Ethernet frame of `payload`, padded to the minimum frame size.
+pub fn ether_frame(destination: HwAddr, source: HwAddr, ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(MIN_FRAME_LEN.max(ETHER_HEADER_LEN + payload.len()));
+    frame.extend_from_slice(&destination.octets());
+    frame.extend_from_slice(&source.octets());
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    if frame.len() < MIN_FRAME_LEN {
+        frame.resize(MIN_FRAME_LEN, 0);
+    }
+    frame
+}
+
+/// An Ethernet frame with an ICMP message from `source` to `destination`,
+/// whose checksum it fills in.
+pub fn icmp_frame(
+    macs: (HwAddr, HwAddr),
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    message: &[u8],
+) -> Vec<u8> {
+    let total_len = (IPV4_HEADER_LEN + message.len()) as u16;
+    let mut packet = Vec::with_capacity(usize::from(total_len));
+    // Version and IHL, TOS, total length, identification, fragment offset,
+    // TTL, protocol and checksum.
+    packet.extend_from_slice(&[0x45, 0]);
+    packet.extend_from_slice(&total_len.to_be_bytes());
+    packet.extend_from_slice(&[0, 0, 0, 0, DEFAULT_TTL, PROTO_ICMP, 0, 0]);
+    packet.extend_from_slice(&source.octets());
+    packet.extend_from_slice(&destination.octets());
+    set_ipv4_checksum(&mut packet);
+    let offset = packet.len();
+    packet.extend_from_slice(message);
+    packet[offset + 2..offset + 4].fill(0);
+    let checksum = checksum(&packet[offset..]);
+    packet[offset + 2..offset + 4].copy_from_slice(&checksum.to_be_bytes());
+    ether_frame(macs.0, macs.1, ETHERTYPE_IPV4, &packet)
+}
+
+/// An Ethernet frame with an ICMPv6 message from `source` to
+/// `destination`, whose checksum it fills in.
+pub fn icmpv6_frame(
+    macs: (HwAddr, HwAddr),
+    source: Ipv6Addr,
+    destination: Ipv6Addr,
+    hop_limit: u8,
+    message: &[u8],
+) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(IPV6_HEADER_LEN + message.len());
+    // Version, traffic class and flow label, payload length, next header
+    // and hop limit.
+    packet.extend_from_slice(&[0x60, 0, 0, 0]);
+    packet.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    packet.extend_from_slice(&[PROTO_ICMPV6, hop_limit]);
+    packet.extend_from_slice(&source.octets());
+    packet.extend_from_slice(&destination.octets());
+    packet.extend_from_slice(message);
+    let icmp = &mut packet[IPV6_HEADER_LEN..];
+    icmp[2..4].fill(0);
+    let pseudo = ipv6_pseudo_sum(&source, &destination, PROTO_ICMPV6, message.len());
+    let checksum = fold(sum(icmp, pseudo));
+    icmp[2..4].copy_from_slice(&checksum.to_be_bytes());
+    ether_frame(macs.0, macs.1, ETHERTYPE_IPV6, &packet)
+}
+
+/// Whether the ICMPv6 `message` from `source` to `destination` has a valid
+/// checksum.
+pub fn icmpv6_checksum_ok(source: &Ipv6Addr, destination: &Ipv6Addr, message: &[u8]) -> bool {
+    let pseudo = ipv6_pseudo_sum(source, destination, PROTO_ICMPV6, message.len());
+    fold(sum(message, pseudo)) == 0
+}
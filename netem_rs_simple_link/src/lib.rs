@@ -0,0 +1,23 @@
+pub mod actors;
+pub mod bridge;
+pub mod control;
+pub mod deploy;
+pub mod ecn;
+pub mod ether;
+pub mod forward;
+pub mod ip;
+pub mod link;
+pub mod loss;
+pub mod meta;
+pub mod multicast;
+pub mod neighbor;
+pub mod netem;
+pub mod qdisc;
+pub mod route;
+pub mod router;
+pub mod shaper;
+pub mod stp;
+pub mod testenv;
+pub mod topology;
+pub mod trace;
+pub mod vlan;
@@ -0,0 +1,294 @@
+//! One direction of an emulated link: the netem stage followed by an optional
+//! rate shaper at the egress, whose qdisc is the bottleneck queue.
+//!
+//! The shaper belongs to the egress port rather than to the link, so that the
+//! links from several ports towards one port share its rate, as they share
+//! the wire of a real port.
+
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::IpAddr,
+    sync::{Arc, Mutex, Weak},
+};
+
+use tokio::time::Instant;
+
+use crate::{
+    ecn::ether_payload,
+    netem::{Netem, NetemConfig},
+    shaper::{Shaper, ShaperConfig, ShaperStats},
+};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LinkParams {
+    pub netem: NetemConfig,
+    pub shaper: Option<ShaperConfig>,
+    /// A link that is down drops every frame.
+    pub down: bool,
+    /// Largest payload of the frames the link carries, VLAN tags aside,
+    /// like the MTU of an interface.
+    pub mtu: Option<usize>,
+    /// Addresses to answer the IP packets larger than `mtu` from, with ICMP
+    /// fragmentation needed or ICMPv6 packet too big. Without an address of
+    /// their family, they are dropped silently as on an Ethernet segment.
+    pub icmp_from: Vec<IpAddr>,
+}
+
+impl LinkParams {
+    pub fn is_passthrough(&self) -> bool {
+        !self.down && self.netem.is_passthrough() && self.shaper.is_none()
+    }
+}
+
+/// The shaper of an egress port, shared by the links towards it.
+pub type EgressShaper<T> = Arc<Mutex<Shaper<T>>>;
+
+/// The shapers of the egress ports, kept as long as a link uses them.
+pub struct EgressShapers<K, T> {
+    shapers: Mutex<HashMap<K, Weak<Mutex<Shaper<T>>>>>,
+}
+
+impl<K, T> Default for EgressShapers<K, T> {
+    fn default() -> Self {
+        Self {
+            shapers: Mutex::default(),
+        }
+    }
+}
+
+impl<K: Eq + Hash, T: AsRef<[u8]> + AsMut<[u8]> + Send + Sync + 'static> EgressShapers<K, T> {
+    /// The shaper of `port`, with `config`. A port has the parameters of the
+    /// last link that set them.
+    pub fn get(&self, port: K, config: &ShaperConfig, now: Instant) -> EgressShaper<T> {
+        let mut shapers = self.shapers.lock().unwrap();
+        if let Some(shaper) = shapers.get(&port).and_then(Weak::upgrade) {
+            let mut locked = shaper.lock().unwrap();
+            if locked.config() != config {
+                locked.set_config(config.clone(), now);
+            }
+            drop(locked);
+            return shaper;
+        }
+        shapers.retain(|_, shaper| shaper.strong_count() > 0);
+        let shaper = Arc::new(Mutex::new(Shaper::new(config.clone())));
+        shapers.insert(port, Arc::downgrade(&shaper));
+        shaper
+    }
+}
+
+pub struct Link<T> {
+    params: LinkParams,
+    netem: Netem<T>,
+    shaper: Option<EgressShaper<T>>,
+    /// Frames dropped because the link was down.
+    down_drops: u64,
+    /// Frames the shaper dropped while the link queued frames in it or took
+    /// frames out of it.
+    shaper_drops: u64,
+    /// Frames dropped because they were larger than the MTU.
+    oversize_drops: u64,
+}
+
+impl<T: Clone + AsRef<[u8]> + AsMut<[u8]> + Send + Sync + 'static> Link<T> {
+    /// A link with `params`, whose frames go through `shaper`, that of its
+    /// egress port with the shaper parameters of `params`, if any.
+    pub fn new(params: LinkParams, shaper: Option<EgressShaper<T>>) -> Self {
+        Self {
+            netem: Netem::new(params.netem.clone()),
+            shaper,
+            params,
+            down_drops: 0,
+            shaper_drops: 0,
+            oversize_drops: 0,
+        }
+    }
+
+    pub fn params(&self) -> &LinkParams {
+        &self.params
+    }
+
+    pub fn netem(&self) -> &Netem<T> {
+        &self.netem
+    }
+
+    /// The statistics of the shaper of the egress port.
+    pub fn shaper_stats(&self) -> Option<ShaperStats> {
+        self.shaper
+            .as_ref()
+            .map(|shaper| shaper.lock().unwrap().stats())
+    }
+
+    pub fn down_drops(&self) -> u64 {
+        self.down_drops
+    }
+
+    pub fn shaper_drops(&self) -> u64 {
+        self.shaper_drops
+    }
+
+    pub fn oversize_drops(&self) -> u64 {
+        self.oversize_drops
+    }
+
+    /// Whether `frame` fits in the MTU of the link. A frame that does not
+    /// is counted as dropped.
+    pub fn fits(&mut self, frame: &[u8]) -> bool {
+        let Some(mtu) = self.params.mtu else {
+            return true;
+        };
+        let offset = ether_payload(frame).map_or(0, |(offset, _)| offset);
+        if frame.len() - offset <= mtu {
+            return true;
+        }
+        self.oversize_drops += 1;
+        false
+    }
+
+    /// Replace the parameters, and the shaper with `shaper`. Frames already
+    /// in the netem stage keep their time to send, frames waiting in a
+    /// shaper no link uses any more are sent right away. Taking the link
+    /// down drops every frame in its netem stage, while the frames already
+    /// queued at the egress port still go out.
+    pub fn set_params(
+        &mut self,
+        params: LinkParams,
+        shaper: Option<EgressShaper<T>>,
+        now: Instant,
+    ) {
+        self.netem.set_config(params.netem.clone());
+        let same = match (&self.shaper, &shaper) {
+            (Some(old), Some(new)) => Arc::ptr_eq(old, new),
+            _ => false,
+        };
+        if !same {
+            let old = std::mem::replace(&mut self.shaper, shaper);
+            if let Some(old) = old.and_then(Arc::into_inner) {
+                for item in old.into_inner().unwrap().drain() {
+                    self.netem.push(item, now);
+                }
+            }
+        }
+        if params.down {
+            self.down_drops += self.netem.clear() as u64;
+        }
+        self.params = params;
+    }
+
+    /// Whether frames can be sent directly without going through the link.
+    pub fn is_passthrough(&self) -> bool {
+        !self.params.down && self.netem.is_passthrough() && self.shaper.is_none()
+    }
+
+    pub fn enqueue(&mut self, item: T, now: Instant) {
+        if self.params.down {
+            self.down_drops += 1;
+            return;
+        }
+        self.netem.enqueue(item, now);
+    }
+
+    /// The next time a frame may come out of the link.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let shaper = self
+            .shaper
+            .as_ref()
+            .and_then(|shaper| shaper.lock().unwrap().next_deadline());
+        match (self.netem.next_deadline(), shaper) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Pop the next frame that is ready to go on the wire. With a shaper, it
+    /// may be the frame of another link towards the same port.
+    pub fn dequeue(&mut self, now: Instant) -> Option<T> {
+        let Some(shaper) = &self.shaper else {
+            return self.netem.dequeue(now);
+        };
+        let mut shaper = shaper.lock().unwrap();
+        let dropped = shaper.stats().qdisc.dropped;
+        while let Some(item) = self.netem.dequeue(now) {
+            shaper.enqueue(item, now);
+        }
+        let item = shaper.dequeue(now);
+        self.shaper_drops += shaper.stats().qdisc.dropped - dropped;
+        item
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn shaped(rate: u64) -> LinkParams {
+        let mut shaper = ShaperConfig::new(rate);
+        shaper.burst = 1000;
+        shaper.overhead = 0;
+        LinkParams {
+            shaper: Some(shaper),
+            ..LinkParams::default()
+        }
+    }
+
+    fn link(shapers: &EgressShapers<u8, Vec<u8>>, port: u8, params: LinkParams) -> Link<Vec<u8>> {
+        let now = Instant::now();
+        let shaper = params
+            .shaper
+            .as_ref()
+            .map(|shaper| shapers.get(port, shaper, now));
+        Link::new(params, shaper)
+    }
+
+    #[test]
+    fn links_towards_a_port_share_its_rate() {
+        let shapers = EgressShapers::default();
+        // 1 MB/s, one 1000 bytes frame per millisecond.
+        let mut a = link(&shapers, 1, shaped(8_000_000));
+        let mut b = link(&shapers, 1, shaped(8_000_000));
+        let mut other = link(&shapers, 2, shaped(8_000_000));
+        let now = Instant::now();
+        for link in [&mut a, &mut b, &mut other] {
+            link.enqueue(vec![0; 1000], now);
+            link.enqueue(vec![0; 1000], now);
+        }
+        let sent = |a: &mut Link<_>, b: &mut Link<_>, now| {
+            let mut sent = 0;
+            while a.dequeue(now).is_some() || b.dequeue(now).is_some() {
+                sent += 1;
+            }
+            sent
+        };
+        // The four frames go out one by one, as if they came from one link.
+        for ms in 0..4 {
+            let at = now + Duration::from_micros(ms * 1000 + 500);
+            assert_eq!(sent(&mut a, &mut b, at), 1);
+        }
+        // The other port has a bucket of its own.
+        assert!(other.dequeue(now).is_some());
+    }
+
+    #[test]
+    fn last_link_takes_the_frames_of_a_removed_shaper() {
+        let shapers = EgressShapers::default();
+        let mut a = link(&shapers, 1, shaped(8_000_000));
+        let mut b = link(&shapers, 1, shaped(8_000_000));
+        let now = Instant::now();
+        for _ in 0..3 {
+            a.enqueue(vec![0; 1000], now);
+        }
+        assert!(a.dequeue(now).is_some());
+        assert!(a.dequeue(now).is_none());
+        // The shaper stays with b, which sends the frames of a at its rate.
+        a.set_params(LinkParams::default(), None, now);
+        assert!(a.dequeue(now).is_none());
+        let later = now + Duration::from_micros(1500);
+        assert!(b.dequeue(later).is_some());
+        // Without a link left, its frames are sent right away.
+        b.set_params(LinkParams::default(), None, later);
+        assert!(b.dequeue(later).is_some());
+        assert!(b.dequeue(later).is_none());
+    }
+}
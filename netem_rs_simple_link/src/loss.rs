@@ -0,0 +1,444 @@
+//! Loss models of the netem stage.
+//!
+//! Besides independent (or correlated) random loss, losses can be driven by a
+//! Markov chain: the Gilbert-Elliott model of `tc netem loss gemodel`, the
+//! 4-state model of `tc netem loss state`, or any chain given by its
+//! transition matrix.
+
+use std::{fmt, str::FromStr};
+
+use anyhow::{bail, Context};
+use rand::{rngs::SmallRng, Rng};
+
+use crate::netem::{format_percent, parse_percent, Correlated};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LossModel {
+    /// Each frame is lost with `probability`, correlated with the previous
+    /// draw by `correlation`.
+    Random { probability: f64, correlation: f64 },
+    /// A Markov chain where `transitions[i][j]` is the probability of going
+    /// from state `i` to state `j` before each frame, and `loss[j]` the
+    /// probability of losing the frame in state `j`. It starts in state 0.
+    Markov {
+        transitions: Vec<Vec<f64>>,
+        loss: Vec<f64>,
+    },
+}
+
+impl Default for LossModel {
+    fn default() -> Self {
+        LossModel::Random {
+            probability: 0.0,
+            correlation: 0.0,
+        }
+    }
+}
+
+impl LossModel {
+    /// The Gilbert-Elliott model: `p` is the probability of going from the
+    /// good to the bad state, `r` from the bad to the good state, and frames
+    /// are lost with `1 - h` in the bad state and `1 - k` in the good one.
+    pub fn gilbert_elliott(p: f64, r: f64, one_minus_h: f64, one_minus_k: f64) -> Self {
+        LossModel::Markov {
+            transitions: vec![vec![1.0 - p, p], vec![r, 1.0 - r]],
+            loss: vec![one_minus_k, one_minus_h],
+        }
+    }
+
+    /// The 4-state model of netem, with states 1 (reception in a gap), 2
+    /// (reception in a burst), 3 (loss in a burst) and 4 (isolated loss).
+    pub fn four_state(p13: f64, p31: f64, p32: f64, p23: f64, p14: f64) -> Self {
+        LossModel::Markov {
+            transitions: vec![
+                vec![1.0 - p13 - p14, 0.0, p13, p14],
+                vec![0.0, 1.0 - p23, p23, 0.0],
+                vec![p31, p32, 1.0 - p31 - p32, 0.0],
+                vec![1.0, 0.0, 0.0, 0.0],
+            ],
+            loss: vec![0.0, 0.0, 1.0, 1.0],
+        }
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match self {
+            LossModel::Random { .. } => Ok(()),
+            LossModel::Markov { transitions, loss } => {
+                if transitions.is_empty() || transitions.len() != loss.len() {
+                    bail!("markov loss needs one loss probability per state");
+                }
+                for (i, row) in transitions.iter().enumerate() {
+                    if row.len() != transitions.len() {
+                        bail!("row {i} of the transition matrix is not the size of the matrix");
+                    }
+                    if row.iter().any(|p| !(0.0..=1.0).contains(p)) {
+                        bail!("row {i} of the transition matrix has invalid probabilities");
+                    }
+                    if (row.iter().sum::<f64>() - 1.0).abs() > 1e-6 {
+                        bail!("row {i} of the transition matrix does not sum to 1");
+                    }
+                }
+                if loss.iter().any(|p| !(0.0..=1.0).contains(p)) {
+                    bail!("invalid loss probability");
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub fn is_lossless(&self) -> bool {
+        match self {
+            LossModel::Random { probability, .. } => *probability == 0.0,
+            LossModel::Markov { loss, .. } => loss.iter().all(|&p| p == 0.0),
+        }
+    }
+
+    /// The long run loss rate of the model.
+    pub fn expected_loss_rate(&self) -> f64 {
+        match self {
+            LossModel::Random { probability, .. } => *probability,
+            LossModel::Markov { transitions, loss } => stationary(transitions)
+                .iter()
+                .zip(loss)
+                .map(|(pi, loss)| pi * loss)
+                .sum(),
+        }
+    }
+}
+
+/// Stationary distribution of a Markov chain, by power iteration from the
+/// uniform distribution.
+fn stationary(transitions: &[Vec<f64>]) -> Vec<f64> {
+    let n = transitions.len();
+    let mut pi = vec![1.0 / n as f64; n];
+    for _ in 0..10_000 {
+        let mut next = vec![0.0; n];
+        for (i, row) in transitions.iter().enumerate() {
+            for (j, p) in row.iter().enumerate() {
+                next[j] += pi[i] * p;
+            }
+        }
+        let delta: f64 = next.iter().zip(&pi).map(|(a, b)| (a - b).abs()).sum();
+        pi = next;
+        if delta < 1e-12 {
+            break;
+        }
+    }
+    pi
+}
+
+impl FromStr for LossModel {
+    type Err = anyhow::Error;
+
+    /// Parse the arguments of `tc netem loss`: `P [CORRELATION]`,
+    /// `random P [CORRELATION]`, `gemodel p [r [1-h [1-k]]]` or
+    /// `state p13 [p31 [p32 [p23 [p14]]]]`. Any chain can be written
+    /// `markov TRANSITIONS LOSS`, with the rows of the transition matrix
+    /// separated by `/` and probabilities by `,`, e.g.
+    /// `markov 0.99,0.01/0.3,0.7 0,1`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut args = s.split_whitespace();
+        let kind = args.next().context("loss needs a probability")?;
+        let model = match kind {
+            "markov" => {
+                let parse_row = |row: &str| {
+                    row.split(',')
+                        .map(parse_percent)
+                        .collect::<anyhow::Result<Vec<_>>>()
+                };
+                let transitions = args
+                    .next()
+                    .context("markov needs a transition matrix")?
+                    .split('/')
+                    .map(parse_row)
+                    .collect::<anyhow::Result<_>>()?;
+                let loss = parse_row(args.next().context("markov needs loss probabilities")?)?;
+                if args.next().is_some() {
+                    bail!("too many arguments to markov");
+                }
+                LossModel::Markov { transitions, loss }
+            }
+            "gemodel" | "gmodel" => {
+                let p = args
+                    .map(parse_percent)
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                if p.is_empty() || p.len() > 4 {
+                    bail!("gemodel needs 1 to 4 probabilities");
+                }
+                LossModel::gilbert_elliott(
+                    p[0],
+                    p.get(1).copied().unwrap_or(1.0 - p[0]),
+                    p.get(2).copied().unwrap_or(1.0),
+                    p.get(3).copied().unwrap_or(0.0),
+                )
+            }
+            "state" => {
+                let p = args
+                    .map(parse_percent)
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                if p.is_empty() || p.len() > 5 {
+                    bail!("state needs 1 to 5 probabilities");
+                }
+                LossModel::four_state(
+                    p[0],
+                    p.get(1).copied().unwrap_or(1.0 - p[0]),
+                    p.get(2).copied().unwrap_or(0.0),
+                    p.get(3).copied().unwrap_or(1.0),
+                    p.get(4).copied().unwrap_or(0.0),
+                )
+            }
+            _ => {
+                let probability = if kind == "random" {
+                    args.next().context("loss needs a probability")?
+                } else {
+                    kind
+                };
+                let correlation = args.next().map(parse_percent).transpose()?;
+                if args.next().is_some() {
+                    bail!("too many arguments to loss");
+                }
+                LossModel::Random {
+                    probability: parse_percent(probability)?,
+                    correlation: correlation.unwrap_or(0.0),
+                }
+            }
+        };
+        model.validate()?;
+        Ok(model)
+    }
+}
+
+impl fmt::Display for LossModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LossModel::Random {
+                probability,
+                correlation,
+            } => write!(
+                f,
+                "{} {}",
+                format_percent(*probability),
+                format_percent(*correlation)
+            ),
+            LossModel::Markov { transitions, loss } => {
+                let join =
+                    |row: &[f64]| row.iter().map(f64::to_string).collect::<Vec<_>>().join(",");
+                let transitions = transitions
+                    .iter()
+                    .map(|row| join(row))
+                    .collect::<Vec<_>>()
+                    .join("/");
+                write!(f, "markov {transitions} {}", join(loss))
+            }
+        }
+    }
+}
+
+/// Counters of the frames seen by a loss model and of the bursts of
+/// consecutive losses, to check the model against its configuration.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LossStats {
+    pub frames: u64,
+    pub lost: u64,
+    /// Number of runs of consecutive lost frames.
+    pub bursts: u64,
+    pub max_burst: u64,
+    current_burst: u64,
+}
+
+impl LossStats {
+    fn record(&mut self, lost: bool) {
+        self.frames += 1;
+        if !lost {
+            self.current_burst = 0;
+            return;
+        }
+        self.lost += 1;
+        if self.current_burst == 0 {
+            self.bursts += 1;
+        }
+        self.current_burst += 1;
+        self.max_burst = self.max_burst.max(self.current_burst);
+    }
+
+    pub fn loss_rate(&self) -> f64 {
+        self.lost as f64 / self.frames.max(1) as f64
+    }
+
+    pub fn mean_burst_length(&self) -> f64 {
+        self.lost as f64 / self.bursts.max(1) as f64
+    }
+
+    /// Mean number of frames received between two bursts.
+    pub fn mean_gap_length(&self) -> f64 {
+        (self.frames - self.lost) as f64 / self.bursts.max(1) as f64
+    }
+}
+
+/// A loss model and its running state.
+pub struct Loss {
+    model: LossModel,
+    state: usize,
+    cor: Correlated,
+    stats: LossStats,
+}
+
+impl Loss {
+    pub fn new(model: LossModel) -> Self {
+        Self {
+            model,
+            state: 0,
+            cor: Correlated::default(),
+            stats: LossStats::default(),
+        }
+    }
+
+    pub fn model(&self) -> &LossModel {
+        &self.model
+    }
+
+    /// Replace the model, restarting a Markov chain from its first state if
+    /// the number of states changed.
+    pub fn set_model(&mut self, model: LossModel) {
+        if let LossModel::Markov { loss, .. } = &model {
+            if self.state >= loss.len() {
+                self.state = 0;
+            }
+        }
+        self.model = model;
+    }
+
+    pub fn stats(&self) -> LossStats {
+        self.stats
+    }
+
+    /// Whether the next frame is lost.
+    pub fn is_lost(&mut self, rng: &mut SmallRng) -> bool {
+        let lost = match &self.model {
+            LossModel::Random {
+                probability,
+                correlation,
+            } => *probability != 0.0 && self.cor.next(rng, *correlation) < *probability,
+            LossModel::Markov { transitions, loss } => {
+                let row = &transitions[self.state];
+                let mut u: f64 = rng.gen();
+                // Fall back to the last state when rounding leaves `u` over.
+                self.state = row.len() - 1;
+                for (j, p) in row.iter().enumerate() {
+                    if u < *p {
+                        self.state = j;
+                        break;
+                    }
+                    u -= p;
+                }
+                let loss = loss[self.state];
+                loss != 0.0 && rng.gen::<f64>() < loss
+            }
+        };
+        self.stats.record(lost);
+        lost
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+
+    const FRAMES: u64 = 200_000;
+
+    fn run(model: LossModel) -> LossStats {
+        let mut rng = SmallRng::seed_from_u64(1);
+        let mut loss = Loss::new(model);
+        for _ in 0..FRAMES {
+            loss.is_lost(&mut rng);
+        }
+        loss.stats()
+    }
+
+    fn assert_near(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{actual} is not {expected}"
+        );
+    }
+
+    #[test]
+    fn random() {
+        let model: LossModel = "1%".parse().unwrap();
+        assert_eq!(model.expected_loss_rate(), 0.01);
+        assert_near(run(model).loss_rate(), 0.01, 0.002);
+        assert!(LossModel::default().is_lossless());
+        assert_eq!(run(LossModel::default()).lost, 0);
+    }
+
+    #[test]
+    fn gilbert() {
+        // Bursts of 1 / r frames every 1 / p received ones on average.
+        let model: LossModel = "gemodel 1% 25%".parse().unwrap();
+        let expected = 0.01 / (0.01 + 0.25);
+        assert_near(model.expected_loss_rate(), expected, 1e-9);
+        let stats = run(model);
+        assert_near(stats.loss_rate(), expected, 0.005);
+        assert_near(stats.mean_burst_length(), 4.0, 0.2);
+        assert_near(stats.mean_gap_length(), 100.0, 5.0);
+    }
+
+    #[test]
+    fn gilbert_elliott() {
+        let model = LossModel::gilbert_elliott(0.05, 0.2, 0.5, 0.01);
+        // A fifth of the time in the bad state.
+        let expected = 0.8 * 0.01 + 0.2 * 0.5;
+        assert_near(model.expected_loss_rate(), expected, 1e-9);
+        assert_near(run(model).loss_rate(), expected, 0.005);
+    }
+
+    #[test]
+    fn four_state() {
+        let model: LossModel = "state 2% 20% 10% 50% 1%".parse().unwrap();
+        let stats = run(model.clone());
+        assert_near(stats.loss_rate(), model.expected_loss_rate(), 0.005);
+        // Isolated losses and bursts of 1 / p31 lost frames on average.
+        assert!(stats.mean_burst_length() > 1.5);
+    }
+
+    #[test]
+    fn markov() {
+        let model: LossModel = "markov 0.99,0.01/0.3,0.7 0,1".parse().unwrap();
+        let LossModel::Markov { transitions, loss } = &model else {
+            panic!("{model:?}");
+        };
+        assert_eq!(transitions, &[vec![0.99, 0.01], vec![0.3, 0.7]]);
+        assert_eq!(loss, &[0.0, 1.0]);
+        assert_near(model.expected_loss_rate(), 0.01 / 0.31, 1e-9);
+        assert_eq!(model.to_string().parse::<LossModel>().unwrap(), model);
+        assert_near(run(model).mean_burst_length(), 1.0 / 0.3, 0.15);
+    }
+
+    #[test]
+    fn invalid() {
+        for model in [
+            "markov 0.5,0.5 0,1",
+            "markov 0.5,0.6/0.5,0.5 0,1",
+            "markov 1,0/0,1 0",
+            "gemodel",
+            "gemodel 1% 2% 3% 4% 5%",
+            "150%",
+            "1% 2% 3%",
+        ] {
+            assert!(model.parse::<LossModel>().is_err(), "{model}");
+        }
+    }
+
+    #[test]
+    fn set_model_restarts_smaller_chain() {
+        let mut rng = SmallRng::seed_from_u64(1);
+        let mut loss = Loss::new(LossModel::four_state(1.0, 0.0, 0.0, 1.0, 0.0));
+        assert!(loss.is_lost(&mut rng));
+        assert_eq!(loss.state, 2);
+        loss.set_model(LossModel::gilbert_elliott(0.0, 1.0, 1.0, 0.0));
+        assert_eq!(loss.state, 0);
+        assert!(!loss.is_lost(&mut rng));
+    }
+}
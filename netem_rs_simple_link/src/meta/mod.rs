@@ -0,0 +1,295 @@
+//! Membership of the nodes of a remote runtime cluster.
+//!
+//! The meta service keeps the nodes that registered with it. Each node
+//! registers its address, the MAC of its Ethernet interface and its XDP
+//! subnet when its `RemtoeRuntime` starts, and gets the nodes that
+//! registered before it, which it adds to its port table.
+//!
+//! A registered node then sends heartbeats, and leaves when it misses them
+//! for the TTL of the service. It also watches the nodes that join and
+//! leave after it registered, which `membership` hands out to the rest of
+//! the process.
+
+mod service;
+
+pub mod proto {
+    tonic::include_proto!("meta");
+}
+
+use std::{
+    sync::{Once, OnceLock},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use hwaddr::HwAddr;
+use log::{error, info, warn};
+use netem_rs::{HostAddr, MetaClient, NodeInfo};
+use tokio::sync::broadcast;
+use tonic::transport::Channel;
+
+pub use service::{serve, MetaServiceImpl};
+
+use self::proto::{
+    membership_event::Kind, meta_service_client::MetaServiceClient, HeartbeatRequest,
+    ListNodesRequest, RegisterRequest, WatchRequest,
+};
+
+/// How long a node waits before registering again when the meta service
+/// cannot be reached, as it may start after the node.
+const REGISTER_RETRY: Duration = Duration::from_secs(1);
+/// How often a registered node tells the meta service it is alive, well
+/// within the TTL of the service.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+/// Membership changes kept for the receivers that are late to read them.
+const MEMBERSHIP_BACKLOG: usize = 256;
+
+/// A node that joined or left the cluster after this one registered.
+/// `Joined` may come again for a known node, when it registered again or
+/// the node watches again after losing the meta service.
+#[derive(Clone)]
+pub enum MembershipChange {
+    Joined(NodeInfo),
+    Left(NodeInfo),
+}
+
+static MEMBERSHIP: OnceLock<broadcast::Sender<MembershipChange>> = OnceLock::new();
+
+/// The client this node registered with, and the node as it registered.
+static REGISTERED: OnceLock<(GrpcMetaClient, NodeInfo)> = OnceLock::new();
+
+/// The peers the runtime added to its port table, those that registered
+/// before this node.
+static PORTS: OnceLock<Vec<HostAddr>> = OnceLock::new();
+
+fn membership_sender() -> &'static broadcast::Sender<MembershipChange> {
+    MEMBERSHIP.get_or_init(|| broadcast::channel(MEMBERSHIP_BACKLOG).0)
+}
+
+/// The changes of the membership of the cluster from now on.
+pub fn membership() -> broadcast::Receiver<MembershipChange> {
+    membership_sender().subscribe()
+}
+
+/// Every node of the cluster but this one, as the meta service has them
+/// now, to catch up with the membership after missing some of its changes.
+pub async fn peers() -> anyhow::Result<Vec<NodeInfo>> {
+    let (meta, node) = REGISTERED
+        .get()
+        .ok_or_else(|| anyhow!("not registered with the meta service"))?;
+    let response = meta.client.clone().list_nodes(ListNodesRequest {}).await?;
+    let mut peers = Vec::new();
+    for other in &response.into_inner().nodes {
+        let other = from_proto_node(other)?;
+        if other.addr != node.addr {
+            peers.push(other);
+        }
+    }
+    Ok(peers)
+}
+
+/// Whether the runtime has a port for `node`. netem_rs only adds the peers
+/// it gets when it registers, so a node that joins for the first time after
+/// this one has none until this node restarts.
+pub fn has_port(node: &NodeInfo) -> bool {
+    PORTS.get().is_some_and(|ports| ports.contains(&node.addr))
+}
+
+pub fn to_proto_node(info: &NodeInfo) -> proto::Node {
+    proto::Node {
+        host: info.addr.host.clone(),
+        port: info.addr.port.into(),
+        eth_mac_addr: info
+            .eth_mac_addr
+            .map(|mac| mac.octets().to_vec())
+            .unwrap_or_default(),
+        xdp_subnet_id: info.xdp_subnet_id.into(),
+    }
+}
+
+pub fn from_proto_node(node: &proto::Node) -> anyhow::Result<NodeInfo> {
+    let eth_mac_addr = match node.eth_mac_addr.len() {
+        0 => None,
+        6 => Some(HwAddr::from(
+            <[u8; 6]>::try_from(node.eth_mac_addr.as_slice()).unwrap(),
+        )),
+        _ => return Err(anyhow!("a MAC address is 6 bytes")),
+    };
+    Ok(NodeInfo {
+        addr: HostAddr {
+            host: node.host.clone(),
+            port: node
+                .port
+                .try_into()
+                .map_err(|_| anyhow!("invalid port {}", node.port))?,
+        },
+        eth_mac_addr,
+        xdp_subnet_id: node
+            .xdp_subnet_id
+            .try_into()
+            .map_err(|_| anyhow!("invalid xdp subnet id {}", node.xdp_subnet_id))?,
+    })
+}
+
+/// The value of the command line option `name` of the runtime, given as
+/// `name value`.
+fn runtime_arg(name: &str) -> Option<String> {
+    let mut args = std::env::args();
+    while let Some(arg) = args.next() {
+        if arg == name {
+            return args.next();
+        }
+    }
+    None
+}
+
+/// This node as registered at `addr`, with the Ethernet MAC and XDP subnet
+/// given to its runtime in XDP mode.
+fn local_node(addr: HostAddr) -> NodeInfo {
+    let eth_mac_addr = runtime_arg("--eth-mac-addr").and_then(|mac| match mac.parse() {
+        Ok(mac) => Some(mac),
+        Err(_) => {
+            error!("invalid --eth-mac-addr {mac}");
+            None
+        }
+    });
+    let xdp_subnet_id = runtime_arg("--xdp-subnet-id")
+        .and_then(|id| match id.parse() {
+            Ok(id) => Some(id),
+            Err(_) => {
+                error!("invalid --xdp-subnet-id {id}");
+                None
+            }
+        })
+        .unwrap_or_default();
+    NodeInfo {
+        addr,
+        eth_mac_addr,
+        xdp_subnet_id,
+    }
+}
+
+/// A `MetaClient` that registers the node with the meta service, then keeps
+/// it registered and follows the membership of the cluster.
+#[derive(Clone)]
+pub struct GrpcMetaClient {
+    client: MetaServiceClient<Channel>,
+}
+
+impl GrpcMetaClient {
+    async fn try_register(&self, node: &NodeInfo) -> anyhow::Result<Vec<NodeInfo>> {
+        let response = self
+            .client
+            .clone()
+            .register(RegisterRequest {
+                node: Some(to_proto_node(node)),
+            })
+            .await?;
+        response
+            .into_inner()
+            .peers
+            .iter()
+            .map(from_proto_node)
+            .collect()
+    }
+
+    /// Send heartbeats for `node` forever, registering it again when the
+    /// meta service forgot it.
+    async fn heartbeat(self, node: NodeInfo) {
+        let request = HeartbeatRequest {
+            host: node.addr.host.clone(),
+            port: node.addr.port.into(),
+        };
+        let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            interval.tick().await;
+            match self.client.clone().heartbeat(request.clone()).await {
+                Ok(response) if !response.get_ref().registered => {
+                    warn!("no longer registered with the meta service, registering again");
+                    if let Err(e) = self.try_register(&node).await {
+                        warn!("failed to register with the meta service: {e:#}");
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => warn!("failed to send a heartbeat to the meta service: {e}"),
+            }
+        }
+    }
+
+    /// Pass the nodes other than `node` that join and leave on to
+    /// `membership`, watching again whenever the stream ends.
+    async fn watch(self, node: NodeInfo) {
+        loop {
+            if let Err(e) = self.watch_once(&node).await {
+                warn!("lost the membership of the cluster: {e:#}, watching again");
+            }
+            tokio::time::sleep(REGISTER_RETRY).await;
+        }
+    }
+
+    async fn watch_once(&self, node: &NodeInfo) -> anyhow::Result<()> {
+        let mut events = self
+            .client
+            .clone()
+            .watch(WatchRequest {})
+            .await?
+            .into_inner();
+        while let Some(event) = events.message().await? {
+            let Some(other) = &event.node else {
+                continue;
+            };
+            let other = from_proto_node(other)?;
+            if other.addr == node.addr {
+                continue;
+            }
+            let change = match event.kind() {
+                Kind::Joined => MembershipChange::Joined(other),
+                Kind::Left => MembershipChange::Left(other),
+            };
+            // Fails only when nobody follows the membership.
+            let _ = membership_sender().send(change);
+        }
+        Ok(())
+    }
+}
+
+impl MetaClient for GrpcMetaClient {
+    fn connet(meta_addr: HostAddr) -> Self {
+        // Connected on first use, so that the node can start before the
+        // meta service.
+        let channel = Channel::from_shared(format!("http://{}:{}", meta_addr.host, meta_addr.port))
+            .expect("invalid meta address")
+            .connect_lazy();
+        Self {
+            client: MetaServiceClient::new(channel),
+        }
+    }
+
+    async fn register(&self, addr: HostAddr) -> Vec<NodeInfo> {
+        let node = local_node(addr);
+        loop {
+            match self.try_register(&node).await {
+                Ok(peers) => {
+                    info!(
+                        "registered {}:{} with {} peers",
+                        node.addr.host,
+                        node.addr.port,
+                        peers.len()
+                    );
+                    static FOLLOW: Once = Once::new();
+                    FOLLOW.call_once(|| {
+                        let _ = REGISTERED.set((self.clone(), node.clone()));
+                        let _ = PORTS.set(peers.iter().map(|peer| peer.addr.clone()).collect());
+                        tokio::spawn(self.clone().heartbeat(node.clone()));
+                        tokio::spawn(self.clone().watch(node.clone()));
+                    });
+                    return peers;
+                }
+                Err(e) => {
+                    warn!("failed to register with the meta service: {e:#}, retrying");
+                    tokio::time::sleep(REGISTER_RETRY).await;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::{info, warn};
+use tokio::{
+    sync::{broadcast, mpsc},
+    time::Instant,
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{transport::Server, Request, Response, Status};
+
+use super::proto::{
+    self, membership_event::Kind, meta_service_server::MetaServiceServer, HeartbeatRequest,
+    HeartbeatResponse, ListNodesRequest, ListNodesResponse, MembershipEvent, RegisterRequest,
+    RegisterResponse, WatchRequest,
+};
+
+/// Membership events kept for the watchers that are late to read them. A
+/// watcher that falls further behind has its stream ended, and watches
+/// again.
+const EVENT_BACKLOG: usize = 1024;
+
+struct Member {
+    node: proto::Node,
+    last_seen: Instant,
+}
+
+/// The registered nodes, in the order they first registered.
+pub struct MetaServiceImpl {
+    members: Mutex<Vec<Member>>,
+    events: broadcast::Sender<MembershipEvent>,
+    /// How long a node stays registered without a heartbeat.
+    ttl: Duration,
+}
+
+impl MetaServiceImpl {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            members: Mutex::new(Vec::new()),
+            events: broadcast::channel(EVENT_BACKLOG).0,
+            ttl,
+        }
+    }
+
+    /// Remove the nodes whose last heartbeat is older than the TTL.
+    pub fn expire(&self, now: Instant) {
+        let mut members = self.members.lock().unwrap();
+        members.retain(|member| {
+            if now.duration_since(member.last_seen) < self.ttl {
+                return true;
+            }
+            let node = &member.node;
+            warn!(
+                "{}:{} left, no heartbeat for {:?}",
+                node.host, node.port, self.ttl
+            );
+            self.notify(Kind::Left, node);
+            false
+        });
+    }
+
+    fn notify(&self, kind: Kind, node: &proto::Node) {
+        // Fails only when nobody watches.
+        let _ = self.events.send(MembershipEvent {
+            kind: kind.into(),
+            node: Some(node.clone()),
+        });
+    }
+}
+
+fn same_addr(a: &proto::Node, host: &str, port: u32) -> bool {
+    a.host == host && a.port == port
+}
+
+#[tonic::async_trait]
+impl proto::meta_service_server::MetaService for MetaServiceImpl {
+    async fn register(
+        &self,
+        request: Request<RegisterRequest>,
+    ) -> Result<Response<RegisterResponse>, Status> {
+        let node = request
+            .into_inner()
+            .node
+            .ok_or_else(|| Status::invalid_argument("node is missing"))?;
+        if node.host.is_empty() {
+            return Err(Status::invalid_argument("host is empty"));
+        }
+        if !matches!(node.eth_mac_addr.len(), 0 | 6) {
+            return Err(Status::invalid_argument("a MAC address is 6 bytes"));
+        }
+        let now = Instant::now();
+        let mut members = self.members.lock().unwrap();
+        // A node that restarts keeps its place.
+        match members
+            .iter_mut()
+            .find(|member| same_addr(&member.node, &node.host, node.port))
+        {
+            Some(member) => {
+                info!("{}:{} registered again", node.host, node.port);
+                member.node = node.clone();
+                member.last_seen = now;
+            }
+            None => {
+                info!("{}:{} registered", node.host, node.port);
+                members.push(Member {
+                    node: node.clone(),
+                    last_seen: now,
+                });
+            }
+        }
+        self.notify(Kind::Joined, &node);
+        let peers = members
+            .iter()
+            .filter(|member| !same_addr(&member.node, &node.host, node.port))
+            .map(|member| member.node.clone())
+            .collect();
+        Ok(Response::new(RegisterResponse { peers }))
+    }
+
+    async fn heartbeat(
+        &self,
+        request: Request<HeartbeatRequest>,
+    ) -> Result<Response<HeartbeatResponse>, Status> {
+        let request = request.into_inner();
+        let mut members = self.members.lock().unwrap();
+        let member = members
+            .iter_mut()
+            .find(|member| same_addr(&member.node, &request.host, request.port));
+        let registered = member.is_some();
+        if let Some(member) = member {
+            member.last_seen = Instant::now();
+        }
+        Ok(Response::new(HeartbeatResponse { registered }))
+    }
+
+    async fn list_nodes(
+        &self,
+        _request: Request<ListNodesRequest>,
+    ) -> Result<Response<ListNodesResponse>, Status> {
+        let members = self.members.lock().unwrap();
+        let nodes = members.iter().map(|member| member.node.clone()).collect();
+        Ok(Response::new(ListNodesResponse { nodes }))
+    }
+
+    type WatchStream = ReceiverStream<Result<MembershipEvent, Status>>;
+
+    async fn watch(
+        &self,
+        _request: Request<WatchRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        // Subscribed under the lock, so that no event is missed or seen
+        // twice between the current nodes and the changes.
+        let (current, mut events) = {
+            let members = self.members.lock().unwrap();
+            let current: Vec<_> = members
+                .iter()
+                .map(|member| MembershipEvent {
+                    kind: Kind::Joined.into(),
+                    node: Some(member.node.clone()),
+                })
+                .collect();
+            (current, self.events.subscribe())
+        };
+        let (sender, receiver) = mpsc::channel(EVENT_BACKLOG);
+        tokio::spawn(async move {
+            for event in current {
+                if sender.send(Ok(event)).await.is_err() {
+                    return;
+                }
+            }
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => Ok(event),
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        Err(Status::aborted(format!("missed {n} events, watch again")))
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                let lagged = event.is_err();
+                if sender.send(event).await.is_err() || lagged {
+                    return;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+}
+
+/// Serve the meta service on `addr` until it fails, forgetting the nodes
+/// without a heartbeat for `ttl`.
+pub async fn serve(addr: SocketAddr, ttl: Duration) -> anyhow::Result<()> {
+    info!("meta service listening on {addr}, nodes expire after {ttl:?}");
+    let service = Arc::new(MetaServiceImpl::new(ttl));
+    let expiring = service.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(ttl / 4);
+        loop {
+            interval.tick().await;
+            expiring.expire(Instant::now());
+        }
+    });
+    Server::builder()
+        .add_service(MetaServiceServer::from_arc(service))
+        .serve(addr)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,261 @@
+//! IGMP and MLD snooping.
+//!
+//! Multicast frames are flooded like broadcasts, unless snooping is on and
+//! some port joined their group: they then only go to the ports that joined
+//! it and to the ports of the multicast routers, found by the queries they
+//! send. Groups are known by their MAC address, which is all the forwarding
+//! looks at, and are joined separately in each VLAN.
+
+use std::{collections::HashMap, sync::RwLock, time::Duration};
+
+use hwaddr::HwAddr;
+use tokio::time::Instant;
+
+use crate::{
+    ecn::{ether_payload, ETHERTYPE_IPV4, ETHERTYPE_IPV6},
+    vlan::Vid,
+};
+
+/// Time a port stays in a group without a new report, the default Group
+/// Membership Interval of IGMP and Multicast Address Listening Interval of
+/// MLD.
+pub const MEMBERSHIP_INTERVAL: Duration = Duration::from_secs(260);
+
+const IPPROTO_IGMP: u8 = 2;
+const IPPROTO_HOPOPTS: u8 = 0;
+const IPPROTO_ROUTING: u8 = 43;
+const IPPROTO_DSTOPTS: u8 = 60;
+const IPPROTO_ICMPV6: u8 = 58;
+
+const IGMP_QUERY: u8 = 0x11;
+const IGMP_V1_REPORT: u8 = 0x12;
+const IGMP_V2_REPORT: u8 = 0x16;
+const IGMP_LEAVE: u8 = 0x17;
+const IGMP_V3_REPORT: u8 = 0x22;
+
+const MLD_QUERY: u8 = 130;
+const MLD_REPORT: u8 = 131;
+const MLD_DONE: u8 = 132;
+const MLD_V2_REPORT: u8 = 143;
+
+/// What a membership message tells about the port it came in from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GroupEvent {
+    Join(HwAddr),
+    Leave(HwAddr),
+    /// A query, sent by a multicast router.
+    Query,
+}
+
+/// The membership messages in an IGMP or MLD frame.
+pub fn group_events(frame: &[u8]) -> Vec<GroupEvent> {
+    let Some((offset, ethertype)) = ether_payload(frame) else {
+        return Vec::new();
+    };
+    let packet = &frame[offset..];
+    let events = match ethertype {
+        ETHERTYPE_IPV4 => igmp_events(packet),
+        ETHERTYPE_IPV6 => mld_events(packet),
+        _ => None,
+    };
+    events.unwrap_or_default()
+}
+
+fn igmp_events(packet: &[u8]) -> Option<Vec<GroupEvent>> {
+    if packet.first()? >> 4 != 4 || *packet.get(9)? != IPPROTO_IGMP {
+        return None;
+    }
+    let igmp = packet.get(usize::from(packet[0] & 0x0f) * 4..)?;
+    let event = match *igmp.first()? {
+        IGMP_QUERY => GroupEvent::Query,
+        IGMP_V1_REPORT | IGMP_V2_REPORT => GroupEvent::Join(ipv4_group(igmp.get(4..8)?)),
+        IGMP_LEAVE => GroupEvent::Leave(ipv4_group(igmp.get(4..8)?)),
+        IGMP_V3_REPORT => return v3_report(igmp, 4, ipv4_group),
+        _ => return None,
+    };
+    Some(vec![event])
+}
+
+fn mld_events(packet: &[u8]) -> Option<Vec<GroupEvent>> {
+    if packet.first()? >> 4 != 6 {
+        return None;
+    }
+    // MLD comes after a hop-by-hop header with the router alert option.
+    let mut next = *packet.get(6)?;
+    let mut offset = 40;
+    while matches!(next, IPPROTO_HOPOPTS | IPPROTO_ROUTING | IPPROTO_DSTOPTS) {
+        let header = packet.get(offset..offset + 2)?;
+        next = header[0];
+        offset += (usize::from(header[1]) + 1) * 8;
+    }
+    if next != IPPROTO_ICMPV6 {
+        return None;
+    }
+    let mld = packet.get(offset..)?;
+    let event = match *mld.first()? {
+        MLD_QUERY => GroupEvent::Query,
+        MLD_REPORT => GroupEvent::Join(ipv6_group(mld.get(8..24)?)),
+        MLD_DONE => GroupEvent::Leave(ipv6_group(mld.get(8..24)?)),
+        MLD_V2_REPORT => return v3_report(mld, 16, ipv6_group),
+        _ => return None,
+    };
+    Some(vec![event])
+}
+
+/// The group records of an IGMPv3 or MLDv2 report, whose addresses are
+/// `address_len` bytes long.
+fn v3_report(
+    report: &[u8],
+    address_len: usize,
+    group: fn(&[u8]) -> HwAddr,
+) -> Option<Vec<GroupEvent>> {
+    const MODE_IS_INCLUDE: u8 = 1;
+    const MODE_IS_EXCLUDE: u8 = 2;
+    const CHANGE_TO_INCLUDE: u8 = 3;
+    const CHANGE_TO_EXCLUDE: u8 = 4;
+    const ALLOW_NEW_SOURCES: u8 = 5;
+
+    let records = u16::from_be_bytes([*report.get(6)?, *report.get(7)?]);
+    let mut offset = 8;
+    let mut events = Vec::new();
+    for _ in 0..records {
+        let header = report.get(offset..offset + 4 + address_len)?;
+        let kind = header[0];
+        let aux_len = usize::from(header[1]) * 4;
+        let sources = usize::from(u16::from_be_bytes([header[2], header[3]]));
+        let group = group(&header[4..]);
+        // Including no source is leaving the group, anything else receives
+        // at least part of it.
+        match kind {
+            MODE_IS_EXCLUDE | CHANGE_TO_EXCLUDE => events.push(GroupEvent::Join(group)),
+            MODE_IS_INCLUDE | CHANGE_TO_INCLUDE if sources == 0 => {
+                events.push(GroupEvent::Leave(group))
+            }
+            MODE_IS_INCLUDE | CHANGE_TO_INCLUDE | ALLOW_NEW_SOURCES => {
+                events.push(GroupEvent::Join(group))
+            }
+            _ => {}
+        }
+        offset += 4 + address_len + sources * address_len + aux_len;
+    }
+    Some(events)
+}
+
+/// The MAC address of an IPv4 group, 01:00:5e and its low 23 bits.
+fn ipv4_group(address: &[u8]) -> HwAddr {
+    HwAddr::from([0x01, 0x00, 0x5e, address[1] & 0x7f, address[2], address[3]])
+}
+
+/// The MAC address of an IPv6 group, 33:33 and its low 32 bits.
+fn ipv6_group(address: &[u8]) -> HwAddr {
+    HwAddr::from([
+        0x33,
+        0x33,
+        address[12],
+        address[13],
+        address[14],
+        address[15],
+    ])
+}
+
+/// Whether `group` may be the one of a link-local control group, such as
+/// 224.0.0.1 or ff02::1, which is always flooded.
+pub fn is_control_group(group: HwAddr) -> bool {
+    matches!(
+        group.octets(),
+        [0x01, 0x00, 0x5e, 0x00, 0x00, _] | [0x33, 0x33, 0x00, 0x00, 0x00, _]
+    )
+}
+
+/// The ports that joined each group and the ports of the multicast routers,
+/// keyed by VLAN.
+pub struct GroupTable {
+    groups: RwLock<HashMap<(Vid, HwAddr), HashMap<HwAddr, Instant>>>,
+    routers: RwLock<HashMap<(Vid, HwAddr), Instant>>,
+}
+
+impl GroupTable {
+    pub fn new() -> Self {
+        Self {
+            groups: RwLock::default(),
+            routers: RwLock::default(),
+        }
+    }
+
+    /// Apply a membership message that came in from `port` in `vlan`. A port
+    /// leaves a group right away, as with the fast leave of Linux bridges.
+    pub fn update(&self, vlan: Vid, event: GroupEvent, port: HwAddr, now: Instant) {
+        match event {
+            GroupEvent::Join(group) => {
+                let mut groups = self.groups.write().unwrap();
+                groups.entry((vlan, group)).or_default().insert(port, now);
+            }
+            GroupEvent::Leave(group) => {
+                let mut groups = self.groups.write().unwrap();
+                if let Some(ports) = groups.get_mut(&(vlan, group)) {
+                    ports.remove(&port);
+                    if ports.is_empty() {
+                        groups.remove(&(vlan, group));
+                    }
+                }
+            }
+            GroupEvent::Query => {
+                self.routers.write().unwrap().insert((vlan, port), now);
+            }
+        }
+    }
+
+    /// The ports to send a frame for `group` in `vlan` to, `None` to flood
+    /// it when no port joined the group.
+    pub fn ports(&self, vlan: Vid, group: HwAddr, now: Instant) -> Option<Vec<HwAddr>> {
+        let alive = |seen: &Instant| now.duration_since(*seen) < MEMBERSHIP_INTERVAL;
+        let groups = self.groups.read().unwrap();
+        let mut ports: Vec<_> = groups
+            .get(&(vlan, group))?
+            .iter()
+            .filter(|(_, seen)| alive(seen))
+            .map(|(&port, _)| port)
+            .collect();
+        if ports.is_empty() {
+            return None;
+        }
+        let routers = self.routers.read().unwrap();
+        for (&(router_vlan, port), seen) in routers.iter() {
+            if router_vlan == vlan && alive(seen) && !ports.contains(&port) {
+                ports.push(port);
+            }
+        }
+        Some(ports)
+    }
+
+    /// Forget the memberships and routers that were not refreshed in time.
+    pub fn expire(&self, now: Instant) {
+        let alive = |seen: &mut Instant| now.duration_since(*seen) < MEMBERSHIP_INTERVAL;
+        let mut groups = self.groups.write().unwrap();
+        for ports in groups.values_mut() {
+            ports.retain(|_, seen| alive(seen));
+        }
+        groups.retain(|_, ports| !ports.is_empty());
+        self.routers.write().unwrap().retain(|_, seen| alive(seen));
+    }
+
+    /// Forget the memberships and the router of `port`, as when it is
+    /// removed.
+    pub fn remove_port(&self, port: HwAddr) {
+        let mut groups = self.groups.write().unwrap();
+        for ports in groups.values_mut() {
+            ports.remove(&port);
+        }
+        groups.retain(|_, ports| !ports.is_empty());
+        self.routers
+            .write()
+            .unwrap()
+            .retain(|&(_, router), _| router != port);
+    }
+}
+
+impl Default for GroupTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,389 @@
+//! Neighbor resolution for the router: the ARP and NDP messages it answers
+//! and sends, and the table of the neighbors it learned from them.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::RwLock,
+    time::Duration,
+};
+
+use hwaddr::HwAddr;
+use tokio::time::Instant;
+
+use crate::ip::{ether_frame, icmpv6_frame};
+
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+/// Time after which a neighbor is resolved again, as `gc_stale_time` in
+/// Linux.
+pub const NEIGHBOR_AGING: Duration = Duration::from_secs(60);
+/// Most neighbors the table keeps, as `gc_thresh3` in Linux.
+pub const MAX_NEIGHBORS: usize = 1024;
+
+const ARP_LEN: usize = 28;
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+
+const ICMPV6_NEIGHBOR_SOLICITATION: u8 = 135;
+const ICMPV6_NEIGHBOR_ADVERTISEMENT: u8 = 136;
+/// The header of a solicitation or an advertisement, up to the target.
+const NDP_LEN: usize = 24;
+/// NDP messages are only valid with this hop limit, which shows they were
+/// not routed.
+pub const NDP_HOP_LIMIT: u8 = 255;
+const OPTION_SOURCE_LINK_LAYER: u8 = 1;
+const OPTION_TARGET_LINK_LAYER: u8 = 2;
+const FLAG_ROUTER: u8 = 0x80;
+const FLAG_SOLICITED: u8 = 0x40;
+const FLAG_OVERRIDE: u8 = 0x20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Arp {
+    pub request: bool,
+    pub sender_mac: HwAddr,
+    pub sender_ip: Ipv4Addr,
+    pub target_ip: Ipv4Addr,
+}
+
+impl Arp {
+    /// The ARP request or reply for IPv4 over Ethernet in `payload`.
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        let arp = payload.get(..ARP_LEN)?;
+        if arp[..6] != [0, 1, 0x08, 0x00, 6, 4] {
+            return None;
+        }
+        let request = match u16::from_be_bytes([arp[6], arp[7]]) {
+            ARP_REQUEST => true,
+            ARP_REPLY => false,
+            _ => return None,
+        };
+        let ip = |at: usize| Ipv4Addr::new(arp[at], arp[at + 1], arp[at + 2], arp[at + 3]);
+        Some(Self {
+            request,
+            sender_mac: HwAddr::from(<[u8; 6]>::try_from(&arp[8..14]).unwrap()),
+            sender_ip: ip(14),
+            target_ip: ip(24),
+        })
+    }
+}
+
+fn arp_frame(
+    operation: u16,
+    destination: HwAddr,
+    (sender_mac, sender_ip): (HwAddr, Ipv4Addr),
+    (target_mac, target_ip): (HwAddr, Ipv4Addr),
+) -> Vec<u8> {
+    let mut arp = Vec::with_capacity(ARP_LEN);
+    arp.extend_from_slice(&[0, 1, 0x08, 0x00, 6, 4]);
+    arp.extend_from_slice(&operation.to_be_bytes());
+    arp.extend_from_slice(&sender_mac.octets());
+    arp.extend_from_slice(&sender_ip.octets());
+    arp.extend_from_slice(&target_mac.octets());
+    arp.extend_from_slice(&target_ip.octets());
+    ether_frame(destination, sender_mac, ETHERTYPE_ARP, &arp)
+}
+
+/// A broadcast request for the MAC of `target` from `mac` and `ip`.
+pub fn arp_request(mac: HwAddr, ip: Ipv4Addr, target: Ipv4Addr) -> Vec<u8> {
+    let broadcast = HwAddr::from([0xff; 6]);
+    arp_frame(
+        ARP_REQUEST,
+        broadcast,
+        (mac, ip),
+        (HwAddr::from([0; 6]), target),
+    )
+}
+
+/// The reply to `request` that its target is at `mac`.
+pub fn arp_reply(request: &Arp, mac: HwAddr) -> Vec<u8> {
+    arp_frame(
+        ARP_REPLY,
+        request.sender_mac,
+        (mac, request.target_ip),
+        (request.sender_mac, request.sender_ip),
+    )
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ndp {
+    /// A request for the MAC of `target`, from the sender at `source_mac`
+    /// if it gave it.
+    Solicitation {
+        target: Ipv6Addr,
+        source_mac: Option<HwAddr>,
+    },
+    /// `target` is at `target_mac`.
+    Advertisement {
+        target: Ipv6Addr,
+        target_mac: Option<HwAddr>,
+    },
+}
+
+impl Ndp {
+    /// The neighbor solicitation or advertisement in the ICMPv6 `message`.
+    pub fn parse(message: &[u8]) -> Option<Self> {
+        if message.len() < NDP_LEN {
+            return None;
+        }
+        let kind = message[0];
+        if !matches!(
+            kind,
+            ICMPV6_NEIGHBOR_SOLICITATION | ICMPV6_NEIGHBOR_ADVERTISEMENT
+        ) || message[1] != 0
+        {
+            return None;
+        }
+        let target = Ipv6Addr::from(<[u8; 16]>::try_from(&message[8..NDP_LEN]).unwrap());
+        let mut options = &message[NDP_LEN..];
+        let mut link_layer = None;
+        // Options are made of 8 bytes units, the link-layer address ones of
+        // one unit.
+        while let [option, len, ..] = *options {
+            let len = usize::from(len) * 8;
+            if len == 0 || options.len() < len {
+                return None;
+            }
+            let wanted = if kind == ICMPV6_NEIGHBOR_SOLICITATION {
+                OPTION_SOURCE_LINK_LAYER
+            } else {
+                OPTION_TARGET_LINK_LAYER
+            };
+            if option == wanted && len == 8 {
+                link_layer = Some(HwAddr::from(<[u8; 6]>::try_from(&options[2..8]).unwrap()));
+            }
+            options = &options[len..];
+        }
+        Some(if kind == ICMPV6_NEIGHBOR_SOLICITATION {
+            Ndp::Solicitation {
+                target,
+                source_mac: link_layer,
+            }
+        } else {
+            Ndp::Advertisement {
+                target,
+                target_mac: link_layer,
+            }
+        })
+    }
+}
+
+/// The solicited-node multicast address of `addr`.
+pub fn solicited_node(addr: Ipv6Addr) -> Ipv6Addr {
+    let octets = addr.octets();
+    Ipv6Addr::new(
+        0xff02,
+        0,
+        0,
+        0,
+        0,
+        1,
+        0xff00 | u16::from(octets[13]),
+        u16::from_be_bytes([octets[14], octets[15]]),
+    )
+}
+
+/// The MAC of the IPv6 multicast group `group`.
+pub fn ipv6_multicast_mac(group: Ipv6Addr) -> HwAddr {
+    let octets = group.octets();
+    HwAddr::from([0x33, 0x33, octets[12], octets[13], octets[14], octets[15]])
+}
+
+fn ndp_message(kind: u8, flags: u8, target: Ipv6Addr, option: u8, mac: HwAddr) -> Vec<u8> {
+    let mut message = vec![kind, 0, 0, 0, flags, 0, 0, 0];
+    message.extend_from_slice(&target.octets());
+    message.extend_from_slice(&[option, 1]);
+    message.extend_from_slice(&mac.octets());
+    message
+}
+
+/// A multicast solicitation for the MAC of `target` from `mac` and `ip`.
+pub fn neighbor_solicitation(mac: HwAddr, ip: Ipv6Addr, target: Ipv6Addr) -> Vec<u8> {
+    let group = solicited_node(target);
+    let message = ndp_message(
+        ICMPV6_NEIGHBOR_SOLICITATION,
+        0,
+        target,
+        OPTION_SOURCE_LINK_LAYER,
+        mac,
+    );
+    icmpv6_frame(
+        (ipv6_multicast_mac(group), mac),
+        ip,
+        group,
+        NDP_HOP_LIMIT,
+        &message,
+    )
+}
+
+/// The advertisement of a router that `target` is at `mac`, in answer to
+/// the solicitation of `destination` at `destination_mac`.
+pub fn neighbor_advertisement(
+    mac: HwAddr,
+    target: Ipv6Addr,
+    destination_mac: HwAddr,
+    destination: Ipv6Addr,
+) -> Vec<u8> {
+    let message = ndp_message(
+        ICMPV6_NEIGHBOR_ADVERTISEMENT,
+        FLAG_ROUTER | FLAG_SOLICITED | FLAG_OVERRIDE,
+        target,
+        OPTION_TARGET_LINK_LAYER,
+        mac,
+    );
+    icmpv6_frame(
+        (destination_mac, mac),
+        target,
+        destination,
+        NDP_HOP_LIMIT,
+        &message,
+    )
+}
+
+/// The MAC of the neighbors of the router, learned from ARP and NDP and
+/// shared by its ports. It keeps at most `MAX_NEIGHBORS` of them, so that
+/// a flood of messages from made up addresses cannot grow it for ever.
+#[derive(Default)]
+pub struct NeighborTable {
+    entries: RwLock<HashMap<IpAddr, (HwAddr, Instant)>>,
+}
+
+impl NeighborTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record that `ip` is at `mac`. When the table is full, the stale
+    /// neighbors are forgotten, or the oldest one if none is.
+    pub fn learn(&self, ip: IpAddr, mac: HwAddr, now: Instant) {
+        let mut entries = self.entries.write().unwrap();
+        if entries.len() >= MAX_NEIGHBORS && !entries.contains_key(&ip) {
+            entries.retain(|_, &mut (_, learned)| now.duration_since(learned) < NEIGHBOR_AGING);
+            if entries.len() >= MAX_NEIGHBORS {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, &(_, learned))| learned)
+                    .map(|(&ip, _)| ip);
+                entries.remove(&oldest.unwrap());
+            }
+        }
+        entries.insert(ip, (mac, now));
+    }
+
+    /// Forget the neighbors that are stale.
+    pub fn expire(&self, now: Instant) {
+        self.entries
+            .write()
+            .unwrap()
+            .retain(|_, &mut (_, learned)| now.duration_since(learned) < NEIGHBOR_AGING);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The MAC of `ip`, unless it is stale.
+    pub fn lookup(&self, ip: IpAddr, now: Instant) -> Option<HwAddr> {
+        let entries = self.entries.read().unwrap();
+        let &(mac, learned) = entries.get(&ip)?;
+        (now.duration_since(learned) < NEIGHBOR_AGING).then_some(mac)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mac(last: u8) -> HwAddr {
+        HwAddr::from([2, 0, 0, 0, 0, last])
+    }
+
+    #[test]
+    fn ndp_roundtrip() {
+        let target: Ipv6Addr = "fd00::1".parse().unwrap();
+        let message = ndp_message(
+            ICMPV6_NEIGHBOR_SOLICITATION,
+            0,
+            target,
+            OPTION_SOURCE_LINK_LAYER,
+            mac(1),
+        );
+        assert_eq!(
+            Ndp::parse(&message),
+            Some(Ndp::Solicitation {
+                target,
+                source_mac: Some(mac(1)),
+            })
+        );
+        let message = ndp_message(
+            ICMPV6_NEIGHBOR_ADVERTISEMENT,
+            FLAG_SOLICITED,
+            target,
+            OPTION_TARGET_LINK_LAYER,
+            mac(2),
+        );
+        assert_eq!(
+            Ndp::parse(&message),
+            Some(Ndp::Advertisement {
+                target,
+                target_mac: Some(mac(2)),
+            })
+        );
+    }
+
+    #[test]
+    fn short_ndp_is_rejected() {
+        let target: Ipv6Addr = "fd00::1".parse().unwrap();
+        let message = ndp_message(
+            ICMPV6_NEIGHBOR_SOLICITATION,
+            0,
+            target,
+            OPTION_SOURCE_LINK_LAYER,
+            mac(1),
+        );
+        for len in 0..NDP_LEN {
+            assert_eq!(Ndp::parse(&message[..len]), None, "{len} bytes");
+        }
+        // A truncated option is rejected, no option is fine.
+        assert_eq!(Ndp::parse(&message[..NDP_LEN + 4]), None);
+        assert!(Ndp::parse(&message[..NDP_LEN]).is_some());
+    }
+
+    #[test]
+    fn neighbors_age_out() {
+        let table = NeighborTable::new();
+        let now = Instant::now();
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        table.learn(ip, mac(1), now);
+        assert_eq!(table.lookup(ip, now), Some(mac(1)));
+        assert_eq!(table.lookup(ip, now + NEIGHBOR_AGING), None);
+        table.expire(now + NEIGHBOR_AGING);
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn table_is_capped() {
+        let table = NeighborTable::new();
+        let now = Instant::now();
+        let ip = |i: usize| IpAddr::from(Ipv4Addr::from(0x0a00_0000 + i as u32));
+        for i in 0..MAX_NEIGHBORS {
+            table.learn(ip(i), mac(1), now + Duration::from_millis(i as u64));
+        }
+        assert_eq!(table.len(), MAX_NEIGHBORS);
+        // The oldest one makes room.
+        let later = now + Duration::from_secs(1);
+        table.learn(ip(MAX_NEIGHBORS), mac(2), later);
+        assert_eq!(table.len(), MAX_NEIGHBORS);
+        assert_eq!(table.lookup(ip(0), later), None);
+        assert_eq!(table.lookup(ip(1), later), Some(mac(1)));
+        assert_eq!(table.lookup(ip(MAX_NEIGHBORS), later), Some(mac(2)));
+        // The stale ones all go at once.
+        let stale = later + NEIGHBOR_AGING + Duration::from_secs(1);
+        table.learn(ip(MAX_NEIGHBORS + 1), mac(3), stale);
+        assert_eq!(table.len(), 1);
+    }
+}
@@ -0,0 +1,818 @@
+//! A userspace model of the Linux `sch_netem` qdisc.
+//!
+//! The parameters and the order in which they are applied follow `tc netem`:
+//! a frame may be duplicated, then lost, then corrupted, and finally it is
+//! either delayed by `latency +- jitter` or, when it is picked for reordering,
+//! sent right away ahead of the delayed ones.
+
+use std::{cmp::Ordering, collections::BinaryHeap, fmt, str::FromStr, time::Duration};
+
+use anyhow::{anyhow, bail, Context};
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use tokio::time::Instant;
+
+use crate::loss::{Loss, LossModel, LossStats};
+
+/// Distribution used to spread the delay around `latency` when jitter is set.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Distribution {
+    #[default]
+    Uniform,
+    Normal,
+    Pareto,
+    ParetoNormal,
+}
+
+impl Distribution {
+    /// Map a uniform sample in (0, 1) to a sample of the distribution scaled to
+    /// a standard deviation of one, which is what the netem tables contain.
+    fn sample(self, u: f64) -> f64 {
+        match self {
+            Distribution::Uniform => 2.0 * u - 1.0,
+            Distribution::Normal => inverse_normal_cdf(u),
+            Distribution::Pareto => pareto(u),
+            Distribution::ParetoNormal => 0.25 * inverse_normal_cdf(u) + 0.75 * pareto(u),
+        }
+    }
+}
+
+impl FromStr for Distribution {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uniform" => Ok(Distribution::Uniform),
+            "normal" => Ok(Distribution::Normal),
+            "pareto" => Ok(Distribution::Pareto),
+            "paretonormal" => Ok(Distribution::ParetoNormal),
+            _ => bail!("unknown distribution {s}"),
+        }
+    }
+}
+
+impl fmt::Display for Distribution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Distribution::Uniform => "uniform",
+            Distribution::Normal => "normal",
+            Distribution::Pareto => "pareto",
+            Distribution::ParetoNormal => "paretonormal",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Impairments applied to one direction of a link.
+///
+/// Probabilities and correlations are in `[0, 1]`. It can be parsed from the
+/// options accepted by `tc qdisc add ... netem`, e.g.
+/// `delay 10ms 2ms 25% distribution normal loss 1% duplicate 0.1%`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NetemConfig {
+    pub latency: Duration,
+    pub jitter: Duration,
+    pub delay_correlation: f64,
+    pub distribution: Distribution,
+    pub loss: LossModel,
+    pub duplicate: f64,
+    pub duplicate_correlation: f64,
+    pub corrupt: f64,
+    pub corrupt_correlation: f64,
+    pub reorder: f64,
+    pub reorder_correlation: f64,
+    pub gap: u32,
+    /// Link rate in bits per second, 0 for no serialization delay.
+    pub rate: u64,
+    /// Maximum number of frames held by the stage, 0 for no limit.
+    pub limit: usize,
+}
+
+impl Default for NetemConfig {
+    fn default() -> Self {
+        Self {
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            delay_correlation: 0.0,
+            distribution: Distribution::default(),
+            loss: LossModel::default(),
+            duplicate: 0.0,
+            duplicate_correlation: 0.0,
+            corrupt: 0.0,
+            corrupt_correlation: 0.0,
+            reorder: 0.0,
+            reorder_correlation: 0.0,
+            gap: 0,
+            rate: 0,
+            // Same default as `tc netem`.
+            limit: 1000,
+        }
+    }
+}
+
+impl NetemConfig {
+    /// Whether frames can bypass the stage untouched.
+    pub fn is_passthrough(&self) -> bool {
+        self.latency.is_zero()
+            && self.jitter.is_zero()
+            && self.loss.is_lossless()
+            && self.duplicate == 0.0
+            && self.corrupt == 0.0
+            && self.rate == 0
+    }
+}
+
+impl FromStr for NetemConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = NetemConfig::default();
+        let mut args = s.split_whitespace().peekable();
+        while let Some(arg) = args.next() {
+            match arg {
+                "delay" | "latency" => {
+                    let latency = args.next().context("delay needs a time")?;
+                    config.latency = parse_duration(latency)?;
+                    if let Some(jitter) = args.next_if(|s| parse_duration(s).is_ok()) {
+                        config.jitter = parse_duration(jitter)?;
+                        if let Some(cor) = args.next_if(|s| parse_percent(s).is_ok()) {
+                            config.delay_correlation = parse_percent(cor)?;
+                        }
+                    }
+                }
+                "distribution" => {
+                    config.distribution =
+                        args.next().context("distribution needs a name")?.parse()?;
+                }
+                "loss" | "drop" => {
+                    let mut loss = vec![args.next().context("loss needs a probability")?];
+                    if loss[0] == "markov" {
+                        loss.extend(args.by_ref().take(2));
+                    } else {
+                        // The loss model takes every probability up to the next
+                        // option.
+                        while let Some(arg) = args.next_if(|s| parse_percent(s).is_ok()) {
+                            loss.push(arg);
+                        }
+                    }
+                    config.loss = loss.join(" ").parse()?;
+                }
+                "duplicate" => {
+                    config.duplicate =
+                        parse_percent(args.next().context("duplicate needs a probability")?)?;
+                    if let Some(cor) = args.next_if(|s| parse_percent(s).is_ok()) {
+                        config.duplicate_correlation = parse_percent(cor)?;
+                    }
+                }
+                "corrupt" => {
+                    config.corrupt =
+                        parse_percent(args.next().context("corrupt needs a probability")?)?;
+                    if let Some(cor) = args.next_if(|s| parse_percent(s).is_ok()) {
+                        config.corrupt_correlation = parse_percent(cor)?;
+                    }
+                }
+                "reorder" => {
+                    config.reorder =
+                        parse_percent(args.next().context("reorder needs a probability")?)?;
+                    if let Some(cor) = args.next_if(|s| parse_percent(s).is_ok()) {
+                        config.reorder_correlation = parse_percent(cor)?;
+                    }
+                }
+                "gap" => {
+                    config.gap = args.next().context("gap needs a distance")?.parse()?;
+                }
+                "rate" => {
+                    config.rate = parse_rate(args.next().context("rate needs a rate")?)?;
+                }
+                "limit" => {
+                    config.limit = args.next().context("limit needs a size")?.parse()?;
+                }
+                _ => bail!("unknown netem option {arg}"),
+            }
+        }
+        // Like tc, reordering without a gap reorders every picked frame.
+        if config.reorder > 0.0 && config.gap == 0 {
+            config.gap = 1;
+        }
+        Ok(config)
+    }
+}
+
+impl fmt::Display for NetemConfig {
+    /// Write the options in the syntax accepted by `from_str`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut options = Vec::new();
+        if !self.latency.is_zero() || !self.jitter.is_zero() {
+            let mut delay = format!("delay {}", format_duration(self.latency));
+            if !self.jitter.is_zero() {
+                delay += &format!(
+                    " {} {}",
+                    format_duration(self.jitter),
+                    format_percent(self.delay_correlation)
+                );
+                options.push(delay);
+                options.push(format!("distribution {}", self.distribution));
+            } else {
+                options.push(delay);
+            }
+        }
+        if !self.loss.is_lossless() {
+            options.push(format!("loss {}", self.loss));
+        }
+        for (name, p, cor) in [
+            ("duplicate", self.duplicate, self.duplicate_correlation),
+            ("corrupt", self.corrupt, self.corrupt_correlation),
+            ("reorder", self.reorder, self.reorder_correlation),
+        ] {
+            if p != 0.0 {
+                options.push(format!(
+                    "{name} {} {}",
+                    format_percent(p),
+                    format_percent(cor)
+                ));
+            }
+        }
+        if self.reorder != 0.0 {
+            options.push(format!("gap {}", self.gap));
+        }
+        if self.rate != 0 {
+            options.push(format!("rate {}bit", self.rate));
+        }
+        options.push(format!("limit {}", self.limit));
+        f.write_str(&options.join(" "))
+    }
+}
+
+/// Write a time in the syntax of `parse_duration`.
+pub fn format_duration(duration: Duration) -> String {
+    format!("{}us", duration.as_micros())
+}
+
+/// Write a probability in the syntax of `parse_percent`.
+pub fn format_percent(p: f64) -> String {
+    format!("{}%", p * 100.0)
+}
+
+/// Parse a tc style time such as `10ms`, `250us`, `1.5s` or a bare number of
+/// microseconds.
+pub fn parse_duration(s: &str) -> anyhow::Result<Duration> {
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+    let value: f64 = value.parse().map_err(|_| anyhow!("invalid time {s}"))?;
+    let secs = match unit {
+        "s" | "sec" | "secs" => value,
+        "ms" | "msec" | "msecs" => value / 1e3,
+        "" | "us" | "usec" | "usecs" => value / 1e6,
+        "ns" | "nsec" | "nsecs" => value / 1e9,
+        _ => bail!("invalid time unit in {s}"),
+    };
+    Ok(Duration::from_secs_f64(secs))
+}
+
+/// Parse a tc style rate such as `100mbit`, `1gbit` or `10mbps` into bits per
+/// second. As in tc, the `bps` suffixes count bytes.
+pub fn parse_rate(s: &str) -> anyhow::Result<u64> {
+    let lower = s.to_ascii_lowercase();
+    let split = lower
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(lower.len());
+    let (value, unit) = lower.split_at(split);
+    let value: f64 = value.parse().map_err(|_| anyhow!("invalid rate {s}"))?;
+    let scale = match unit {
+        "" | "bit" => 1.0,
+        "kbit" => 1e3,
+        "mbit" => 1e6,
+        "gbit" => 1e9,
+        "tbit" => 1e12,
+        "bps" => 8.0,
+        "kbps" => 8e3,
+        "mbps" => 8e6,
+        "gbps" => 8e9,
+        "tbps" => 8e12,
+        _ => bail!("invalid rate unit in {s}"),
+    };
+    let rate = value * scale;
+    // Nothing would ever be sent at a rate of zero.
+    if !rate.is_finite() || rate < 1.0 {
+        bail!("rate {s} must be at least 1bit");
+    }
+    Ok(rate as u64)
+}
+
+/// Parse a probability written either as a percentage (`1%`) or as a fraction
+/// (`0.01`).
+pub fn parse_percent(s: &str) -> anyhow::Result<f64> {
+    let value = match s.strip_suffix('%') {
+        Some(percent) => percent.parse::<f64>().map(|p| p / 100.0),
+        None => s.parse::<f64>(),
+    }
+    .map_err(|_| anyhow!("invalid probability {s}"))?;
+    if !(0.0..=1.0).contains(&value) {
+        bail!("probability {s} out of range");
+    }
+    Ok(value)
+}
+
+/// Correlated random source, the equivalent of netem's `get_crandom`.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Correlated {
+    last: f64,
+}
+
+impl Correlated {
+    pub(crate) fn next(&mut self, rng: &mut SmallRng, rho: f64) -> f64 {
+        let value: f64 = rng.gen();
+        if rho == 0.0 {
+            return value;
+        }
+        self.last = value * (1.0 - rho) + self.last * rho;
+        self.last
+    }
+}
+
+/// Counters of what the stage did to the frames it saw.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NetemStats {
+    pub enqueued: u64,
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub corrupted: u64,
+    pub reordered: u64,
+    pub overlimits: u64,
+}
+
+struct Pending<T> {
+    time_to_send: Instant,
+    seq: u64,
+    item: T,
+}
+
+impl<T> PartialEq for Pending<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Pending<T> {}
+
+impl<T> PartialOrd for Pending<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Pending<T> {
+    // Reversed so that the `BinaryHeap` pops the earliest frame first.
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .time_to_send
+            .cmp(&self.time_to_send)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+/// The impairment stage of one link direction.
+///
+/// Frames are handed in with [`Netem::enqueue`] and come out of
+/// [`Netem::dequeue`] once their time to send has passed, ordered by that time
+/// like netem's tfifo, so jitter larger than the inter-frame gap reorders them.
+pub struct Netem<T> {
+    config: NetemConfig,
+    rng: SmallRng,
+    delay_cor: Correlated,
+    loss: Loss,
+    dup_cor: Correlated,
+    corrupt_cor: Correlated,
+    reorder_cor: Correlated,
+    counter: u32,
+    seq: u64,
+    last_time_to_send: Option<Instant>,
+    queue: BinaryHeap<Pending<T>>,
+    stats: NetemStats,
+}
+
+impl<T: Clone + AsRef<[u8]> + AsMut<[u8]>> Netem<T> {
+    pub fn new(config: NetemConfig) -> Self {
+        Self {
+            loss: Loss::new(config.loss.clone()),
+            config,
+            rng: SmallRng::from_entropy(),
+            delay_cor: Correlated::default(),
+            dup_cor: Correlated::default(),
+            corrupt_cor: Correlated::default(),
+            reorder_cor: Correlated::default(),
+            counter: 0,
+            seq: 0,
+            last_time_to_send: None,
+            queue: BinaryHeap::new(),
+            stats: NetemStats::default(),
+        }
+    }
+
+    pub fn config(&self) -> &NetemConfig {
+        &self.config
+    }
+
+    /// Replace the parameters. Frames already queued keep their time to send.
+    pub fn set_config(&mut self, config: NetemConfig) {
+        self.loss.set_model(config.loss.clone());
+        self.config = config;
+    }
+
+    pub fn stats(&self) -> NetemStats {
+        self.stats
+    }
+
+    pub fn loss_stats(&self) -> LossStats {
+        self.loss.stats()
+    }
+
+    /// Whether frames can be forwarded directly, without going through the
+    /// stage. The queue must be empty too, or the bypass would overtake it.
+    pub fn is_passthrough(&self) -> bool {
+        self.queue.is_empty() && self.config.is_passthrough()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Apply the impairments to `item` and queue what survives.
+    pub fn enqueue(&mut self, mut item: T, now: Instant) {
+        self.stats.enqueued += 1;
+        if self.config.limit != 0 && self.queue.len() >= self.config.limit {
+            self.stats.overlimits += 1;
+            self.stats.dropped += 1;
+            return;
+        }
+
+        let mut count = 1;
+        if chance(
+            &mut self.rng,
+            &mut self.dup_cor,
+            self.config.duplicate,
+            self.config.duplicate_correlation,
+        ) {
+            count += 1;
+        }
+        if self.loss.is_lost(&mut self.rng) {
+            count -= 1;
+        }
+        if count == 0 {
+            self.stats.dropped += 1;
+            return;
+        }
+
+        // Like netem, the duplicate is cloned before the original is
+        // corrupted, so that a corrupted frame is never sent twice.
+        let duplicate = (count > 1).then(|| item.clone());
+        if chance(
+            &mut self.rng,
+            &mut self.corrupt_cor,
+            self.config.corrupt,
+            self.config.corrupt_correlation,
+        ) {
+            let data = item.as_mut();
+            if !data.is_empty() {
+                let byte = self.rng.gen_range(0..data.len());
+                data[byte] ^= 1 << self.rng.gen_range(0..8);
+                self.stats.corrupted += 1;
+            }
+        }
+
+        if let Some(duplicate) = duplicate {
+            // netem re-enqueues the clone at the root, so it gets its own delay.
+            self.stats.duplicated += 1;
+            self.schedule(duplicate, now);
+        }
+        self.schedule(item, now);
+    }
+
+    fn schedule(&mut self, item: T, now: Instant) {
+        let reorder = self.config.gap != 0
+            && self.counter + 1 >= self.config.gap
+            && chance(
+                &mut self.rng,
+                &mut self.reorder_cor,
+                self.config.reorder,
+                self.config.reorder_correlation,
+            );
+        let time_to_send = if reorder {
+            // Sent ahead of everything that is still being delayed.
+            self.counter = 0;
+            self.stats.reordered += 1;
+            now
+        } else {
+            self.counter += 1;
+            let mut start = now;
+            let mut delay = self.delay();
+            if self.config.rate != 0 {
+                // Like netem, the delay overlaps with the wait for the previous
+                // frame to leave the wire, then the frame is serialized.
+                if let Some(last) = self.last_time_to_send.filter(|&last| last > now) {
+                    delay = delay.saturating_sub(last - now);
+                    start = last;
+                }
+                delay += transmission_time(item.as_ref().len(), self.config.rate);
+            }
+            let time_to_send = start + delay;
+            self.last_time_to_send = Some(time_to_send);
+            time_to_send
+        };
+        self.push(item, time_to_send);
+    }
+
+    /// Queue `item` to be sent at `time_to_send` without impairing it.
+    pub fn push(&mut self, item: T, time_to_send: Instant) {
+        self.seq += 1;
+        self.queue.push(Pending {
+            time_to_send,
+            seq: self.seq,
+            item,
+        });
+    }
+
+    /// The next frame's time to send, if any frame is queued.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.queue.peek().map(|pending| pending.time_to_send)
+    }
+
+    /// Pop the next frame whose time to send is not after `now`.
+    pub fn dequeue(&mut self, now: Instant) -> Option<T> {
+        if self.next_deadline()? > now {
+            return None;
+        }
+        self.queue.pop().map(|pending| pending.item)
+    }
+
+    /// Drop every queued frame, returning how many there were.
+    pub fn clear(&mut self) -> usize {
+        let len = self.queue.len();
+        self.queue.clear();
+        len
+    }
+
+    fn delay(&mut self) -> Duration {
+        let latency = self.config.latency.as_secs_f64();
+        let jitter = self.config.jitter.as_secs_f64();
+        if jitter == 0.0 {
+            return self.config.latency;
+        }
+        let u = self
+            .delay_cor
+            .next(&mut self.rng, self.config.delay_correlation)
+            .clamp(f64::EPSILON, 1.0 - f64::EPSILON);
+        let delay = latency + jitter * self.config.distribution.sample(u);
+        Duration::from_secs_f64(delay.max(0.0))
+    }
+}
+
+/// Time to put `len` bytes on a wire of `rate` bits per second.
+pub fn transmission_time(len: usize, rate: u64) -> Duration {
+    Duration::from_secs_f64(len as f64 * 8.0 / rate as f64)
+}
+
+fn chance(rng: &mut SmallRng, cor: &mut Correlated, probability: f64, correlation: f64) -> bool {
+    probability != 0.0 && cor.next(rng, correlation) < probability
+}
+
+/// Pareto with shape 3, shifted and scaled to zero mean and unit variance.
+fn pareto(u: f64) -> f64 {
+    const SHAPE: f64 = 3.0;
+    // Mean is 1.5 * scale and the variance 0.75 * scale^2 for shape 3.
+    let scale = 1.0 / 0.75f64.sqrt();
+    scale / (1.0 - u).powf(1.0 / SHAPE) - 1.5 * scale
+}
+
+/// Acklam's rational approximation of the standard normal quantile function.
+fn inverse_normal_cdf(p: f64) -> f64 {
+    const A: [f64; 6] = [
+        -3.969683028665376e1,
+        2.209460984245205e2,
+        -2.759285104469687e2,
+        1.383_577_518_672_69e2,
+        -3.066479806614716e1,
+        2.506628277459239,
+    ];
+    const B: [f64; 5] = [
+        -5.447609879822406e1,
+        1.615858368580409e2,
+        -1.556989798598866e2,
+        6.680131188771972e1,
+        -1.328068155288572e1,
+    ];
+    const C: [f64; 6] = [
+        -7.784894002430293e-3,
+        -3.223964580411365e-1,
+        -2.400758277161838,
+        -2.549732539343734,
+        4.374664141464968,
+        2.938163982698783,
+    ];
+    const D: [f64; 4] = [
+        7.784695709041462e-3,
+        3.224671290700398e-1,
+        2.445134137142996,
+        3.754408661907416,
+    ];
+    const LOW: f64 = 0.02425;
+
+    if p < LOW {
+        let q = (-2.0 * p.ln()).sqrt();
+        (((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5])
+            / ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.0)
+    } else if p <= 1.0 - LOW {
+        let q = p - 0.5;
+        let r = q * q;
+        (((((A[0] * r + A[1]) * r + A[2]) * r + A[3]) * r + A[4]) * r + A[5]) * q
+            / (((((B[0] * r + B[1]) * r + B[2]) * r + B[3]) * r + B[4]) * r + 1.0)
+    } else {
+        -inverse_normal_cdf(1.0 - p)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    /// Dequeue every frame due by `now`.
+    fn due(netem: &mut Netem<Vec<u8>>, now: Instant) -> Vec<u8> {
+        std::iter::from_fn(|| netem.dequeue(now))
+            .map(|frame| frame[0])
+            .collect()
+    }
+
+    #[test]
+    fn parse_options() {
+        let config: NetemConfig = "delay 10ms 2ms 25% distribution normal loss 1% 30% \
+             duplicate 0.5% corrupt 0.1% 5% reorder 25% 50% gap 5 rate 100mbit limit 500"
+            .parse()
+            .unwrap();
+        assert_eq!(config.latency, 10 * MS);
+        assert_eq!(config.jitter, 2 * MS);
+        assert_eq!(config.delay_correlation, 0.25);
+        assert_eq!(config.distribution, Distribution::Normal);
+        assert_eq!(
+            config.loss,
+            LossModel::Random {
+                probability: 0.01,
+                correlation: 0.3
+            }
+        );
+        assert_eq!(
+            (config.duplicate, config.duplicate_correlation),
+            (0.005, 0.0)
+        );
+        assert_eq!((config.corrupt, config.corrupt_correlation), (0.001, 0.05));
+        assert_eq!((config.reorder, config.reorder_correlation), (0.25, 0.5));
+        assert_eq!(config.gap, 5);
+        assert_eq!(config.rate, 100_000_000);
+        assert_eq!(config.limit, 500);
+        assert_eq!(config.to_string().parse::<NetemConfig>().unwrap(), config);
+    }
+
+    #[test]
+    fn parse_optional_arguments() {
+        // Jitter and correlations are optional, and the next option ends
+        // them.
+        let config: NetemConfig = "delay 10ms loss 1% duplicate 1%".parse().unwrap();
+        assert_eq!((config.latency, config.jitter), (10 * MS, Duration::ZERO));
+        assert_eq!(config.loss, "1%".parse().unwrap());
+        assert_eq!(config.duplicate, 0.01);
+        assert_eq!(config.distribution, Distribution::Uniform);
+
+        let config: NetemConfig = "loss gemodel 1% 30% corrupt 1%".parse().unwrap();
+        assert_eq!(config.loss, LossModel::gilbert_elliott(0.01, 0.3, 1.0, 0.0));
+        assert_eq!(config.corrupt, 0.01);
+
+        // Like tc, reordering without a gap reorders every picked frame.
+        let config: NetemConfig = "delay 10ms reorder 25%".parse().unwrap();
+        assert_eq!(config.gap, 1);
+        assert!(NetemConfig::default().is_passthrough());
+        assert!(!config.is_passthrough());
+    }
+
+    #[test]
+    fn parse_invalid() {
+        for options in [
+            "delay",
+            "delay 10parsecs",
+            "distribution cauchy",
+            "loss 150%",
+            "duplicate",
+            "gap x",
+            "rate 0",
+            "jitter 1ms",
+        ] {
+            assert!(options.parse::<NetemConfig>().is_err(), "{options}");
+        }
+    }
+
+    #[test]
+    fn parse_units() {
+        assert_eq!(parse_duration("1.5s").unwrap(), 1500 * MS);
+        assert_eq!(parse_duration("250us").unwrap(), Duration::from_micros(250));
+        assert_eq!(parse_duration("100").unwrap(), Duration::from_micros(100));
+        assert_eq!(parse_rate("1gbit").unwrap(), 1_000_000_000);
+        assert_eq!(parse_rate("10mbps").unwrap(), 80_000_000);
+        assert_eq!(parse_percent("0.01").unwrap(), 0.01);
+        assert_eq!(parse_percent("50%").unwrap(), 0.5);
+        assert!(parse_percent("-1%").is_err());
+    }
+
+    #[test]
+    fn delay_keeps_order() {
+        let mut netem = Netem::new("delay 10ms".parse().unwrap());
+        let start = Instant::now();
+        for i in 0..3u8 {
+            netem.enqueue(vec![i], start + u32::from(i) * MS);
+        }
+        assert_eq!(netem.next_deadline(), Some(start + 10 * MS));
+        assert!(due(&mut netem, start + 9 * MS).is_empty());
+        assert_eq!(due(&mut netem, start + 11 * MS), [0, 1]);
+        assert_eq!(due(&mut netem, start + 12 * MS), [2]);
+        assert!(netem.is_empty());
+    }
+
+    #[test]
+    fn frames_leave_by_time_to_send() {
+        // As with jitter larger than the gap between frames; frames due at
+        // the same time keep their order.
+        let mut netem = Netem::new(NetemConfig::default());
+        let start = Instant::now();
+        netem.push(vec![0], start + 3 * MS);
+        netem.push(vec![1], start + MS);
+        netem.push(vec![2], start + 2 * MS);
+        netem.push(vec![3], start + MS);
+        assert_eq!(due(&mut netem, start + 3 * MS), [1, 3, 2, 0]);
+    }
+
+    #[test]
+    fn reorder_sends_every_gap_th_frame_at_once() {
+        let mut netem = Netem::new("delay 10ms reorder 100% gap 3".parse().unwrap());
+        let start = Instant::now();
+        for i in 0..6u8 {
+            netem.enqueue(vec![i], start);
+        }
+        assert_eq!(due(&mut netem, start), [2, 5]);
+        assert_eq!(due(&mut netem, start + 10 * MS), [0, 1, 3, 4]);
+        assert_eq!(netem.stats().reordered, 2);
+    }
+
+    #[test]
+    fn rate_serializes_frames() {
+        // 100 bytes take 100ms at 8kbit, and the delay overlaps with the
+        // wait for the previous frame.
+        let mut netem = Netem::new("delay 150ms rate 8kbit".parse().unwrap());
+        let start = Instant::now();
+        netem.enqueue(vec![0; 100], start);
+        netem.enqueue(vec![1; 100], start);
+        netem.enqueue(vec![2; 100], start);
+        assert!(due(&mut netem, start + 249 * MS).is_empty());
+        assert_eq!(due(&mut netem, start + 250 * MS), [0]);
+        assert_eq!(due(&mut netem, start + 350 * MS), [1]);
+        assert_eq!(due(&mut netem, start + 450 * MS), [2]);
+    }
+
+    #[test]
+    fn limit_drops() {
+        let mut netem = Netem::new("delay 10ms limit 2".parse().unwrap());
+        let now = Instant::now();
+        for i in 0..3u8 {
+            netem.enqueue(vec![i], now);
+        }
+        assert_eq!(netem.len(), 2);
+        let stats = netem.stats();
+        assert_eq!((stats.overlimits, stats.dropped), (1, 1));
+    }
+
+    #[test]
+    fn duplicate_is_cloned_before_corruption() {
+        let mut netem = Netem::new(NetemConfig {
+            duplicate: 1.0,
+            corrupt: 1.0,
+            ..NetemConfig::default()
+        });
+        let now = Instant::now();
+        netem.enqueue(vec![0u8; 64], now);
+
+        // The duplicate is scheduled first and keeps the original bytes.
+        let duplicate = netem.dequeue(now).unwrap();
+        let original = netem.dequeue(now).unwrap();
+        assert!(netem.dequeue(now).is_none());
+        assert_eq!(duplicate, vec![0u8; 64]);
+        let flipped: u32 = original.iter().map(|byte| byte.count_ones()).sum();
+        assert_eq!(flipped, 1);
+
+        let stats = netem.stats();
+        assert_eq!(stats.duplicated, 1);
+        assert_eq!(stats.corrupted, 1);
+    }
+}
@@ -0,0 +1,294 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use super::{CodelConfig, Qdisc, QdiscStats, Queue};
+use crate::ecn::set_ce;
+
+/// A queue holding less than this many bytes is never considered standing.
+const MTU: usize = 1514;
+
+/// State of the CoDel control loop of one queue, as in RFC 8289.
+#[derive(Default)]
+pub(super) struct CodelVars {
+    count: u32,
+    lastcount: u32,
+    dropping: bool,
+    first_above_time: Option<Instant>,
+    drop_next: Option<Instant>,
+}
+
+/// Parameters of the control loop.
+#[derive(Clone, Copy)]
+pub(super) struct CodelParams {
+    pub target: Duration,
+    pub interval: Duration,
+    pub ecn: bool,
+}
+
+impl CodelParams {
+    fn control_law(&self, t: Instant, count: u32) -> Instant {
+        t + self.interval.div_f64(f64::from(count.max(1)).sqrt())
+    }
+}
+
+/// Pop the head of `queue` and tell whether the queue has been standing over
+/// the target for an interval, so that it is fine to drop.
+fn do_dequeue<T: AsRef<[u8]>>(
+    queue: &mut Queue<T>,
+    vars: &mut CodelVars,
+    params: &CodelParams,
+    now: Instant,
+) -> (Option<T>, bool) {
+    let Some(entry) = queue.pop() else {
+        vars.first_above_time = None;
+        return (None, false);
+    };
+    let sojourn = now.saturating_duration_since(entry.enqueued);
+    if sojourn < params.target || queue.bytes <= MTU {
+        vars.first_above_time = None;
+        return (Some(entry.item), false);
+    }
+    match vars.first_above_time {
+        None => {
+            vars.first_above_time = Some(now + params.interval);
+            (Some(entry.item), false)
+        }
+        Some(first_above_time) => (Some(entry.item), now >= first_above_time),
+    }
+}
+
+/// The CoDel dequeue of RFC 8289. Every dropped or marked frame is counted in
+/// `stats`.
+pub(super) fn codel_dequeue<T: AsRef<[u8]> + AsMut<[u8]>>(
+    queue: &mut Queue<T>,
+    vars: &mut CodelVars,
+    params: &CodelParams,
+    stats: &mut QdiscStats,
+    now: Instant,
+) -> Option<T> {
+    let (mut item, mut ok_to_drop) = do_dequeue(queue, vars, params, now);
+    if item.is_none() {
+        vars.dropping = false;
+        return None;
+    }
+
+    if vars.dropping {
+        if !ok_to_drop {
+            vars.dropping = false;
+        }
+        while let Some(drop_next) = vars.drop_next.filter(|&t| vars.dropping && now >= t) {
+            vars.count += 1;
+            if params.ecn && item.as_mut().is_some_and(|item| set_ce(item.as_mut())) {
+                stats.marked += 1;
+                vars.drop_next = Some(params.control_law(drop_next, vars.count));
+                return item;
+            }
+            stats.early_drops += 1;
+            stats.dropped += 1;
+            (item, ok_to_drop) = do_dequeue(queue, vars, params, now);
+            if item.is_none() || !ok_to_drop {
+                vars.dropping = false;
+            } else {
+                vars.drop_next = Some(params.control_law(drop_next, vars.count));
+            }
+        }
+    } else if ok_to_drop {
+        if params.ecn && item.as_mut().is_some_and(|item| set_ce(item.as_mut())) {
+            stats.marked += 1;
+        } else {
+            stats.early_drops += 1;
+            stats.dropped += 1;
+            (item, _) = do_dequeue(queue, vars, params, now);
+        }
+        vars.dropping = true;
+        // Start from the previous drop rate if the last dropping state was
+        // recent, so that the loop converges quickly.
+        let delta = vars.count.saturating_sub(vars.lastcount);
+        let recent = vars
+            .drop_next
+            .is_some_and(|t| now.saturating_duration_since(t) < params.interval * 16);
+        vars.count = if delta > 1 && recent { delta } else { 1 };
+        vars.lastcount = vars.count;
+        vars.drop_next = Some(params.control_law(now, vars.count));
+    }
+    item
+}
+
+/// Controlled Delay AQM, as in RFC 8289.
+pub struct Codel<T> {
+    config: CodelConfig,
+    queue: Queue<T>,
+    vars: CodelVars,
+    stats: QdiscStats,
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> Codel<T> {
+    pub fn new(config: CodelConfig) -> Self {
+        Self {
+            config,
+            queue: Queue::new(),
+            vars: CodelVars::default(),
+            stats: QdiscStats::default(),
+        }
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]> + Send + Sync> Qdisc<T> for Codel<T> {
+    fn enqueue(&mut self, item: T, now: Instant) {
+        let len = item.as_ref().len();
+        if self
+            .config
+            .limit
+            .exceeded_by(self.queue.len(), self.queue.bytes, len)
+        {
+            self.stats.overlimits += 1;
+            self.stats.dropped += 1;
+            return;
+        }
+        self.stats.enqueued += 1;
+        self.queue.push(item, now);
+    }
+
+    fn dequeue(&mut self, now: Instant) -> Option<T> {
+        let params = CodelParams {
+            target: self.config.target,
+            interval: self.config.interval,
+            ecn: self.config.ecn,
+        };
+        let item = codel_dequeue(
+            &mut self.queue,
+            &mut self.vars,
+            &params,
+            &mut self.stats,
+            now,
+        )?;
+        self.stats.dequeued += 1;
+        Some(item)
+    }
+
+    fn stats(&self) -> QdiscStats {
+        QdiscStats {
+            backlog: self.queue.len(),
+            backlog_bytes: self.queue.bytes,
+            ..self.stats
+        }
+    }
+
+    fn drain(&mut self) -> Vec<T> {
+        self.queue.drain().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::{ecn::tests::ipv4_frame, qdisc::Size};
+
+    const TARGET: Duration = Duration::from_millis(5);
+    const INTERVAL: Duration = Duration::from_millis(100);
+
+    fn codel(ecn: bool, frame: Vec<u8>, frames: usize, now: Instant) -> Codel<Vec<u8>> {
+        let mut codel = Codel::new(CodelConfig {
+            limit: Size::Packets(1000),
+            target: TARGET,
+            interval: INTERVAL,
+            ecn,
+        });
+        for _ in 0..frames {
+            codel.enqueue(frame.clone(), now);
+        }
+        codel
+    }
+
+    #[test]
+    fn control_law() {
+        let params = CodelParams {
+            target: TARGET,
+            interval: INTERVAL,
+            ecn: false,
+        };
+        let t = Instant::now();
+        assert_eq!(params.control_law(t, 0), t + INTERVAL);
+        assert_eq!(params.control_law(t, 1), t + INTERVAL);
+        assert_eq!(params.control_law(t, 4), t + INTERVAL / 2);
+        assert_eq!(params.control_law(t, 100), t + INTERVAL / 10);
+    }
+
+    #[test]
+    fn short_queue_is_not_dropped() {
+        let start = Instant::now();
+        // Less than an MTU in the queue is never standing.
+        let mut codel = codel(false, vec![0; 500], 3, start);
+        let late = start + Duration::from_secs(1);
+        while codel.dequeue(late).is_some() {}
+        assert_eq!(codel.stats().dropped, 0);
+    }
+
+    #[test]
+    fn drops_follow_control_law() {
+        let start = Instant::now();
+        let mut codel = codel(false, vec![0; 1000], 100, start);
+
+        // Over the target for the first time: nothing is dropped for an
+        // interval.
+        let t = start + Duration::from_millis(10);
+        assert!(codel.dequeue(t).is_some());
+        assert_eq!(codel.stats().dropped, 0);
+        assert!(!codel.vars.dropping);
+
+        // Still over the target an interval later: drop and go on dropping
+        // an interval later.
+        let t = t + INTERVAL;
+        assert!(codel.dequeue(t).is_some());
+        assert_eq!(codel.stats().dropped, 1);
+        assert!(codel.vars.dropping);
+        assert_eq!(codel.vars.drop_next, Some(t + INTERVAL));
+
+        assert!(codel.dequeue(t + INTERVAL / 2).is_some());
+        assert_eq!(codel.stats().dropped, 1);
+
+        // The next drops come closer, at interval / sqrt(count).
+        let t = t + INTERVAL;
+        assert!(codel.dequeue(t).is_some());
+        assert_eq!(codel.stats().dropped, 2);
+        assert_eq!(codel.vars.count, 2);
+        assert_eq!(
+            codel.vars.drop_next,
+            Some(t + INTERVAL.div_f64(2f64.sqrt()))
+        );
+    }
+
+    #[test]
+    fn leaves_dropping_under_target() {
+        let start = Instant::now();
+        let mut codel = codel(false, vec![0; 1000], 10, start);
+        let t = start + Duration::from_millis(10);
+        codel.dequeue(t);
+        codel.dequeue(t + INTERVAL);
+        assert!(codel.vars.dropping);
+        // Frames that just came in are under the target.
+        let t = t + INTERVAL * 2;
+        while codel.dequeue(t).is_some() {}
+        for _ in 0..3 {
+            codel.enqueue(vec![0; 1000], t);
+        }
+        assert!(codel.dequeue(t + Duration::from_millis(1)).is_some());
+        assert!(!codel.vars.dropping);
+    }
+
+    #[test]
+    fn ecn_marks_instead_of_dropping() {
+        let start = Instant::now();
+        let frame = ipv4_frame(Ipv4Addr::new(10, 0, 0, 1), 0b10, 1000);
+        let mut codel = codel(true, frame, 100, start);
+        let t = start + Duration::from_millis(10);
+        codel.dequeue(t);
+        let marked = codel.dequeue(t + INTERVAL).unwrap();
+        assert_eq!(marked[15] & 0b11, 0b11);
+        let stats = codel.stats();
+        assert_eq!((stats.marked, stats.dropped), (1, 0));
+    }
+}
@@ -0,0 +1,87 @@
+use tokio::time::Instant;
+
+use super::{Qdisc, QdiscStats, Queue, Size};
+
+/// Tail-drop FIFO, like `pfifo` or `bfifo` depending on the unit of the limit.
+pub struct Fifo<T> {
+    limit: Size,
+    queue: Queue<T>,
+    stats: QdiscStats,
+}
+
+impl<T: AsRef<[u8]>> Fifo<T> {
+    pub fn new(limit: Size) -> Self {
+        Self {
+            limit,
+            queue: Queue::new(),
+            stats: QdiscStats::default(),
+        }
+    }
+}
+
+impl<T: AsRef<[u8]> + Send + Sync> Qdisc<T> for Fifo<T> {
+    fn enqueue(&mut self, item: T, now: Instant) {
+        let len = item.as_ref().len();
+        if self
+            .limit
+            .exceeded_by(self.queue.len(), self.queue.bytes, len)
+        {
+            self.stats.overlimits += 1;
+            self.stats.dropped += 1;
+            return;
+        }
+        self.stats.enqueued += 1;
+        self.queue.push(item, now);
+    }
+
+    fn dequeue(&mut self, _now: Instant) -> Option<T> {
+        let entry = self.queue.pop()?;
+        self.stats.dequeued += 1;
+        Some(entry.item)
+    }
+
+    fn stats(&self) -> QdiscStats {
+        QdiscStats {
+            backlog: self.queue.len(),
+            backlog_bytes: self.queue.bytes,
+            ..self.stats
+        }
+    }
+
+    fn drain(&mut self) -> Vec<T> {
+        self.queue.drain().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qdisc::Size;
+
+    #[test]
+    fn packet_limit() {
+        let mut fifo = Fifo::new(Size::Packets(2));
+        let now = Instant::now();
+        for i in 0..3u8 {
+            fifo.enqueue(vec![i; 100], now);
+        }
+        let stats = fifo.stats();
+        assert_eq!((stats.enqueued, stats.overlimits, stats.dropped), (2, 1, 1));
+        assert_eq!((stats.backlog, stats.backlog_bytes), (2, 200));
+        assert_eq!(fifo.dequeue(now), Some(vec![0; 100]));
+        assert_eq!(fifo.dequeue(now), Some(vec![1; 100]));
+        assert_eq!(fifo.dequeue(now), None);
+    }
+
+    #[test]
+    fn byte_limit() {
+        let mut fifo = Fifo::new(Size::Bytes(250));
+        let now = Instant::now();
+        fifo.enqueue(vec![0; 100], now);
+        fifo.enqueue(vec![1; 200], now);
+        fifo.enqueue(vec![2; 150], now);
+        assert_eq!(fifo.stats().overlimits, 1);
+        assert_eq!(fifo.drain(), [vec![0; 100], vec![2; 150]]);
+        assert_eq!(fifo.stats().backlog_bytes, 0);
+    }
+}
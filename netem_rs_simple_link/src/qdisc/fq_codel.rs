@@ -0,0 +1,308 @@
+use std::{
+    collections::{hash_map::RandomState, VecDeque},
+    hash::{BuildHasher, Hash, Hasher},
+};
+
+use tokio::time::Instant;
+
+use super::{
+    codel::{codel_dequeue, CodelParams, CodelVars},
+    FqCodelConfig, Qdisc, QdiscStats, Queue,
+};
+use crate::ecn::ether_payload;
+
+struct Flow<T> {
+    queue: Queue<T>,
+    vars: CodelVars,
+    deficit: i64,
+    /// Whether the flow is in the new or the old list.
+    active: bool,
+}
+
+/// Flow queueing with a CoDel per flow and deficit round robin between the
+/// flows, as in RFC 8290.
+pub struct FqCodel<T> {
+    config: FqCodelConfig,
+    flows: Vec<Flow<T>>,
+    new_flows: VecDeque<usize>,
+    old_flows: VecDeque<usize>,
+    hasher: RandomState,
+    backlog: usize,
+    backlog_bytes: usize,
+    stats: QdiscStats,
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> FqCodel<T> {
+    pub fn new(config: FqCodelConfig) -> Self {
+        let flows = (0..config.flows.max(1))
+            .map(|_| Flow {
+                queue: Queue::new(),
+                vars: CodelVars::default(),
+                deficit: 0,
+                active: false,
+            })
+            .collect();
+        Self {
+            config,
+            flows,
+            new_flows: VecDeque::new(),
+            old_flows: VecDeque::new(),
+            hasher: RandomState::new(),
+            backlog: 0,
+            backlog_bytes: 0,
+            stats: QdiscStats::default(),
+        }
+    }
+
+    fn classify(&self, data: &[u8]) -> usize {
+        let mut hasher = self.hasher.build_hasher();
+        hash_flow(data, &mut hasher);
+        (hasher.finish() % self.flows.len() as u64) as usize
+    }
+
+    /// Drop the head of the flow with the largest backlog, like fq_codel does
+    /// when the limit is hit.
+    fn drop_from_fattest(&mut self) {
+        let Some(flow) = self.flows.iter_mut().max_by_key(|flow| flow.queue.bytes) else {
+            return;
+        };
+        if let Some(entry) = flow.queue.pop() {
+            self.backlog -= 1;
+            self.backlog_bytes -= entry.item.as_ref().len();
+            self.stats.overlimits += 1;
+            self.stats.dropped += 1;
+        }
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]> + Send + Sync> Qdisc<T> for FqCodel<T> {
+    fn enqueue(&mut self, item: T, now: Instant) {
+        let len = item.as_ref().len();
+        let index = self.classify(item.as_ref());
+        let flow = &mut self.flows[index];
+        flow.queue.push(item, now);
+        if !flow.active {
+            flow.active = true;
+            flow.deficit = self.config.quantum as i64;
+            self.new_flows.push_back(index);
+        }
+        self.stats.enqueued += 1;
+        self.backlog += 1;
+        self.backlog_bytes += len;
+        while self.backlog > 0 && self.config.limit.exceeded(self.backlog, self.backlog_bytes) {
+            self.drop_from_fattest();
+        }
+    }
+
+    fn dequeue(&mut self, now: Instant) -> Option<T> {
+        let params = CodelParams {
+            target: self.config.target,
+            interval: self.config.interval,
+            ecn: self.config.ecn,
+        };
+        loop {
+            let (from_new, index) = match self.new_flows.front() {
+                Some(&index) => (true, index),
+                None => (false, *self.old_flows.front()?),
+            };
+            let list = if from_new {
+                &mut self.new_flows
+            } else {
+                &mut self.old_flows
+            };
+            let flow = &mut self.flows[index];
+
+            if flow.deficit <= 0 {
+                flow.deficit += self.config.quantum as i64;
+                list.pop_front();
+                self.old_flows.push_back(index);
+                continue;
+            }
+
+            let before = (flow.queue.len(), flow.queue.bytes);
+            let item = codel_dequeue(
+                &mut flow.queue,
+                &mut flow.vars,
+                &params,
+                &mut self.stats,
+                now,
+            );
+            self.backlog -= before.0 - flow.queue.len();
+            self.backlog_bytes -= before.1 - flow.queue.bytes;
+
+            let Some(item) = item else {
+                list.pop_front();
+                // An emptied new flow goes to the old list so that it cannot
+                // starve the old flows by coming back as new right away.
+                if from_new && !self.old_flows.is_empty() {
+                    self.old_flows.push_back(index);
+                } else {
+                    flow.active = false;
+                }
+                continue;
+            };
+            flow.deficit -= item.as_ref().len() as i64;
+            self.stats.dequeued += 1;
+            return Some(item);
+        }
+    }
+
+    fn stats(&self) -> QdiscStats {
+        QdiscStats {
+            backlog: self.backlog,
+            backlog_bytes: self.backlog_bytes,
+            ..self.stats
+        }
+    }
+
+    fn drain(&mut self) -> Vec<T> {
+        self.new_flows.clear();
+        self.old_flows.clear();
+        self.backlog = 0;
+        self.backlog_bytes = 0;
+        self.flows
+            .iter_mut()
+            .flat_map(|flow| {
+                flow.active = false;
+                flow.queue.drain()
+            })
+            .collect()
+    }
+}
+
+/// Hash the addresses, protocol and ports of the IP packet in an Ethernet
+/// frame, or the MAC addresses if it is not IP.
+fn hash_flow(data: &[u8], hasher: &mut impl Hasher) {
+    let (offset, ethertype) = ether_payload(data).unzip();
+    let ip = offset
+        .and_then(|offset| data.get(offset..))
+        .unwrap_or_default();
+    let ports = match ethertype {
+        Some(0x0800) if ip.len() >= 20 => {
+            ip[12..20].hash(hasher);
+            ip[9].hash(hasher);
+            let header_len = usize::from(ip[0] & 0x0f) * 4;
+            let fragment_offset = read_u16(ip, 6).unwrap_or(0) & 0x1fff;
+            (fragment_offset == 0 && matches!(ip[9], 6 | 17 | 132))
+                .then(|| ip.get(header_len..header_len + 4))
+                .flatten()
+        }
+        Some(0x86dd) if ip.len() >= 40 => {
+            ip[8..40].hash(hasher);
+            ip[6].hash(hasher);
+            matches!(ip[6], 6 | 17 | 132)
+                .then(|| ip.get(40..44))
+                .flatten()
+        }
+        _ => {
+            data.get(..12).hash(hasher);
+            None
+        }
+    };
+    ports.hash(hasher);
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::qdisc::Size;
+
+    /// A frame of `len` bytes with a UDP packet from 10.0.0.`flow`.
+    fn frame(flow: u8, len: usize) -> Vec<u8> {
+        let mut frame = vec![0u8; len.max(42)];
+        frame[12..14].copy_from_slice(&[0x08, 0x00]);
+        frame[14] = 0x45;
+        frame[23] = 17;
+        frame[26..30].copy_from_slice(&[10, 0, 0, flow]);
+        frame[30..34].copy_from_slice(&[10, 0, 0, 2]);
+        frame[34..38].copy_from_slice(&[0x30, 0x39, 0x00, 0x35]);
+        frame
+    }
+
+    /// An FQ-CoDel in which the flows 1 and 2 do not share a queue.
+    fn fq_codel(limit: Size) -> FqCodel<Vec<u8>> {
+        loop {
+            let fq_codel = FqCodel::new(FqCodelConfig {
+                limit,
+                target: Duration::from_millis(5),
+                interval: Duration::from_millis(100),
+                flows: 16,
+                quantum: 1514,
+                ecn: false,
+            });
+            if fq_codel.classify(&frame(1, 100)) != fq_codel.classify(&frame(2, 100)) {
+                return fq_codel;
+            }
+        }
+    }
+
+    fn source(frame: &[u8]) -> u8 {
+        frame[29]
+    }
+
+    #[test]
+    fn deficit_round_robin() {
+        // Flow 1 sends frames twice as large as flow 2, both get the same
+        // bytes in each round of a quantum.
+        let mut fq_codel = fq_codel(Size::Packets(10240));
+        let now = Instant::now();
+        for _ in 0..100 {
+            fq_codel.enqueue(frame(1, 1000), now);
+            fq_codel.enqueue(frame(2, 500), now);
+            fq_codel.enqueue(frame(2, 500), now);
+        }
+        let mut bytes = [0i64; 2];
+        for _ in 0..150 {
+            let item = fq_codel.dequeue(now).unwrap();
+            bytes[usize::from(source(&item)) - 1] += item.len() as i64;
+            assert!((bytes[0] - bytes[1]).abs() <= 2 * 1514, "{bytes:?}");
+        }
+        assert_eq!(bytes[0], bytes[1]);
+    }
+
+    #[test]
+    fn new_flow_goes_first() {
+        let mut fq_codel = fq_codel(Size::Packets(10240));
+        let now = Instant::now();
+        for _ in 0..10 {
+            fq_codel.enqueue(frame(1, 1000), now);
+        }
+        // Flow 1 used up its first quantum and went to the old flows.
+        assert_eq!(source(&fq_codel.dequeue(now).unwrap()), 1);
+        assert_eq!(source(&fq_codel.dequeue(now).unwrap()), 1);
+        fq_codel.enqueue(frame(2, 100), now);
+        assert_eq!(source(&fq_codel.dequeue(now).unwrap()), 2);
+        assert_eq!(source(&fq_codel.dequeue(now).unwrap()), 1);
+    }
+
+    #[test]
+    fn limit_drops_from_fattest() {
+        let mut fq_codel = fq_codel(Size::Packets(10));
+        let now = Instant::now();
+        fq_codel.enqueue(frame(2, 100), now);
+        for _ in 0..10 {
+            fq_codel.enqueue(frame(1, 1000), now);
+        }
+        let stats = fq_codel.stats();
+        assert_eq!((stats.overlimits, stats.backlog), (1, 10));
+        let drained = fq_codel.drain();
+        assert_eq!(drained.iter().filter(|item| source(item) == 2).count(), 1);
+        assert_eq!(fq_codel.stats().backlog, 0);
+    }
+
+    #[test]
+    fn flow_hash() {
+        let fq_codel = fq_codel(Size::Packets(10240));
+        // Same addresses and ports, whatever the payload.
+        let mut other = frame(1, 100);
+        other[50] = 0xff;
+        assert_eq!(fq_codel.classify(&frame(1, 100)), fq_codel.classify(&other));
+    }
+}
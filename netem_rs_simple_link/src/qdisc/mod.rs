@@ -0,0 +1,379 @@
+//! Queueing disciplines for the bottleneck of a link.
+//!
+//! A qdisc holds the frames waiting for the rate shaper and decides which of
+//! them are dropped when the link is congested.
+
+mod codel;
+mod fifo;
+mod fq_codel;
+mod red;
+
+use std::{collections::VecDeque, fmt, str::FromStr, time::Duration};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Deserializer};
+use tokio::time::Instant;
+
+pub use codel::Codel;
+pub use fifo::Fifo;
+pub use fq_codel::FqCodel;
+pub use red::Red;
+
+use crate::{
+    netem::{format_duration, parse_duration},
+    shaper::parse_size,
+};
+
+pub trait Qdisc<T>: Send + Sync {
+    /// Queue `item`, or drop it if the queue is full or the AQM decides so.
+    fn enqueue(&mut self, item: T, now: Instant);
+
+    /// Pop the next frame to send, dropping frames on the way if the AQM
+    /// decides so.
+    fn dequeue(&mut self, now: Instant) -> Option<T>;
+
+    fn stats(&self) -> QdiscStats;
+
+    /// Take every queued frame out of the qdisc, in dequeue order as far as
+    /// it has one.
+    fn drain(&mut self) -> Vec<T>;
+
+    fn len(&self) -> usize {
+        self.stats().backlog
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QdiscStats {
+    pub enqueued: u64,
+    pub dequeued: u64,
+    /// Every dropped frame, whatever the reason.
+    pub dropped: u64,
+    /// Frames dropped because the queue was full.
+    pub overlimits: u64,
+    /// Frames dropped by the AQM before the queue was full.
+    pub early_drops: u64,
+    /// Frames the AQM marked with Congestion Experienced instead of dropping.
+    pub marked: u64,
+    pub backlog: usize,
+    pub backlog_bytes: usize,
+}
+
+/// A queue size, counted either in frames or in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Size {
+    Packets(usize),
+    Bytes(usize),
+}
+
+impl Size {
+    /// The amount of `packets` frames of `bytes` total, in this unit.
+    fn of(self, packets: usize, bytes: usize) -> usize {
+        match self {
+            Size::Packets(_) => packets,
+            Size::Bytes(_) => bytes,
+        }
+    }
+
+    fn value(self) -> usize {
+        match self {
+            Size::Packets(n) | Size::Bytes(n) => n,
+        }
+    }
+
+    /// Whether a queue of `packets` frames and `bytes` bytes is over the size.
+    fn exceeded(self, packets: usize, bytes: usize) -> bool {
+        self.of(packets, bytes) > self.value()
+    }
+
+    /// Whether adding a frame of `len` bytes to a queue of `packets` frames
+    /// and `bytes` bytes would exceed the size.
+    fn exceeded_by(self, packets: usize, bytes: usize, len: usize) -> bool {
+        self.exceeded(packets + 1, bytes + len)
+    }
+}
+
+impl FromStr for Size {
+    type Err = anyhow::Error;
+
+    /// A bare number is a count of frames, a number with a unit such as `b`
+    /// or `kb` a count of bytes.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.bytes().all(|c| c.is_ascii_digit()) {
+            Ok(Size::Packets(s.parse()?))
+        } else {
+            Ok(Size::Bytes(parse_size(s)?))
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Size {
+    /// Either a number of frames or a string parsed with `from_str`.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Packets(usize),
+            Text(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Packets(n) => Ok(Size::Packets(n)),
+            Raw::Text(s) => s.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Size::Packets(n) => write!(f, "{n}"),
+            Size::Bytes(n) => write!(f, "{n}b"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RedConfig {
+    pub limit: Size,
+    /// Average queue size under which nothing is dropped, in the unit of
+    /// `limit`.
+    pub min: usize,
+    /// Average queue size over which everything is dropped.
+    pub max: usize,
+    /// Drop probability when the average reaches `max`.
+    pub probability: f64,
+    /// Weight of the current queue size in the moving average.
+    pub weight: f64,
+    /// Mark ECN capable frames instead of dropping them early.
+    pub ecn: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CodelConfig {
+    pub limit: Size,
+    pub target: Duration,
+    pub interval: Duration,
+    /// Mark ECN capable frames instead of dropping them.
+    pub ecn: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FqCodelConfig {
+    pub limit: Size,
+    pub target: Duration,
+    pub interval: Duration,
+    pub flows: usize,
+    /// Bytes a flow may send each round.
+    pub quantum: usize,
+    /// Mark ECN capable frames instead of dropping them.
+    pub ecn: bool,
+}
+
+/// Which qdisc to use and its parameters. It can be parsed from the options
+/// of the matching `tc qdisc`, e.g. `fq_codel limit 10240 target 5ms`.
+/// Limits are in frames, or in bytes when they have a unit. The AQMs mark
+/// ECN capable frames instead of dropping them with `ecn`, which is the
+/// default of `fq_codel` only, like in Linux.
+#[derive(Clone, Debug, PartialEq)]
+pub enum QdiscConfig {
+    Fifo { limit: Size },
+    Red(RedConfig),
+    Codel(CodelConfig),
+    FqCodel(FqCodelConfig),
+}
+
+impl Default for QdiscConfig {
+    fn default() -> Self {
+        QdiscConfig::Fifo {
+            limit: Size::Packets(1000),
+        }
+    }
+}
+
+impl QdiscConfig {
+    pub fn limit(&self) -> Size {
+        match self {
+            QdiscConfig::Fifo { limit } => *limit,
+            QdiscConfig::Red(config) => config.limit,
+            QdiscConfig::Codel(config) => config.limit,
+            QdiscConfig::FqCodel(config) => config.limit,
+        }
+    }
+
+    pub fn set_limit(&mut self, size: Size) {
+        match self {
+            QdiscConfig::Fifo { limit } => *limit = size,
+            QdiscConfig::Red(config) => config.limit = size,
+            QdiscConfig::Codel(config) => config.limit = size,
+            QdiscConfig::FqCodel(config) => config.limit = size,
+        }
+    }
+
+    pub fn build<T: AsRef<[u8]> + AsMut<[u8]> + Send + Sync + 'static>(&self) -> Box<dyn Qdisc<T>> {
+        match self {
+            QdiscConfig::Fifo { limit } => Box::new(Fifo::new(*limit)),
+            QdiscConfig::Red(config) => Box::new(Red::new(config.clone())),
+            QdiscConfig::Codel(config) => Box::new(Codel::new(config.clone())),
+            QdiscConfig::FqCodel(config) => Box::new(FqCodel::new(config.clone())),
+        }
+    }
+}
+
+impl FromStr for QdiscConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut args = s.split_whitespace();
+        let kind = args.next().context("missing qdisc kind")?;
+        let mut limit = None;
+        let mut min = None;
+        let mut max = None;
+        let mut probability = None;
+        let mut weight = None;
+        let mut target = None;
+        let mut interval = None;
+        let mut flows = None;
+        let mut quantum = None;
+        let mut ecn = None;
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().with_context(|| format!("{arg} needs a value"));
+            match arg {
+                "ecn" => ecn = Some(true),
+                "noecn" => ecn = Some(false),
+                "limit" => limit = Some(value()?.parse::<Size>()?),
+                "min" => min = Some(value()?.parse::<Size>()?.value()),
+                "max" => max = Some(value()?.parse::<Size>()?.value()),
+                "probability" => probability = Some(value()?.parse::<f64>()?),
+                "weight" => weight = Some(value()?.parse::<f64>()?),
+                "target" => target = Some(parse_duration(value()?)?),
+                "interval" => interval = Some(parse_duration(value()?)?),
+                "flows" => flows = Some(value()?.parse::<usize>()?),
+                "quantum" => quantum = Some(parse_size(value()?)?),
+                _ => bail!("unknown qdisc option {arg}"),
+            }
+        }
+        let config = match kind {
+            "fifo" | "pfifo" | "bfifo" => QdiscConfig::Fifo {
+                limit: limit.unwrap_or(Size::Packets(1000)),
+            },
+            "red" => {
+                let limit = limit.unwrap_or(Size::Packets(1000));
+                let max = max.unwrap_or(limit.value() / 4);
+                let min = min.unwrap_or(max / 3);
+                if min >= max || max > limit.value() {
+                    bail!("red needs min < max <= limit");
+                }
+                QdiscConfig::Red(RedConfig {
+                    limit,
+                    min,
+                    max,
+                    probability: probability.unwrap_or(0.02),
+                    weight: weight.unwrap_or(0.002),
+                    ecn: ecn.unwrap_or(false),
+                })
+            }
+            "codel" => QdiscConfig::Codel(CodelConfig {
+                limit: limit.unwrap_or(Size::Packets(1000)),
+                target: target.unwrap_or(Duration::from_millis(5)),
+                interval: interval.unwrap_or(Duration::from_millis(100)),
+                ecn: ecn.unwrap_or(false),
+            }),
+            "fq_codel" => QdiscConfig::FqCodel(FqCodelConfig {
+                limit: limit.unwrap_or(Size::Packets(10240)),
+                target: target.unwrap_or(Duration::from_millis(5)),
+                interval: interval.unwrap_or(Duration::from_millis(100)),
+                flows: flows.unwrap_or(1024),
+                quantum: quantum.unwrap_or(1514),
+                ecn: ecn.unwrap_or(true),
+            }),
+            _ => bail!("unknown qdisc {kind}"),
+        };
+        Ok(config)
+    }
+}
+
+impl fmt::Display for QdiscConfig {
+    /// Write the options in the syntax accepted by `from_str`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ecn = |ecn: bool| if ecn { "ecn" } else { "noecn" };
+        match self {
+            QdiscConfig::Fifo { limit } => write!(f, "fifo limit {limit}"),
+            QdiscConfig::Red(config) => write!(
+                f,
+                "red limit {} min {} max {} probability {} weight {} {}",
+                config.limit,
+                config.min,
+                config.max,
+                config.probability,
+                config.weight,
+                ecn(config.ecn)
+            ),
+            QdiscConfig::Codel(config) => write!(
+                f,
+                "codel limit {} target {} interval {} {}",
+                config.limit,
+                format_duration(config.target),
+                format_duration(config.interval),
+                ecn(config.ecn)
+            ),
+            QdiscConfig::FqCodel(config) => write!(
+                f,
+                "fq_codel limit {} target {} interval {} flows {} quantum {} {}",
+                config.limit,
+                format_duration(config.target),
+                format_duration(config.interval),
+                config.flows,
+                config.quantum,
+                ecn(config.ecn)
+            ),
+        }
+    }
+}
+
+/// A frame and the time it entered the qdisc.
+struct Entry<T> {
+    item: T,
+    enqueued: Instant,
+}
+
+/// A FIFO keeping track of its size in bytes.
+struct Queue<T> {
+    entries: VecDeque<Entry<T>>,
+    bytes: usize,
+}
+
+impl<T: AsRef<[u8]>> Queue<T> {
+    fn new() -> Self {
+        Self {
+            entries: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn push(&mut self, item: T, now: Instant) {
+        self.bytes += item.as_ref().len();
+        self.entries.push_back(Entry {
+            item,
+            enqueued: now,
+        });
+    }
+
+    fn pop(&mut self) -> Option<Entry<T>> {
+        let entry = self.entries.pop_front()?;
+        self.bytes -= entry.item.as_ref().len();
+        Some(entry)
+    }
+
+    fn drain(&mut self) -> impl Iterator<Item = T> + '_ {
+        self.bytes = 0;
+        self.entries.drain(..).map(|entry| entry.item)
+    }
+}
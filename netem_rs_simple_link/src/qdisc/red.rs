@@ -0,0 +1,186 @@
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use tokio::time::Instant;
+
+use super::{Qdisc, QdiscStats, Queue, RedConfig};
+use crate::ecn::set_ce;
+
+/// Random Early Detection, as described by Floyd and Jacobson.
+///
+/// The average queue size is measured in the unit of the limit. Unlike
+/// `tc red`, the average only moves on arrivals and does not decay while the
+/// queue is idle.
+pub struct Red<T> {
+    config: RedConfig,
+    queue: Queue<T>,
+    rng: SmallRng,
+    avg: f64,
+    /// Frames since the last early drop, -1 while the average is under `min`.
+    count: i64,
+    stats: QdiscStats,
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> Red<T> {
+    pub fn new(config: RedConfig) -> Self {
+        Self {
+            config,
+            queue: Queue::new(),
+            rng: SmallRng::from_entropy(),
+            avg: 0.0,
+            count: -1,
+            stats: QdiscStats::default(),
+        }
+    }
+
+    /// Whether the frame arriving now should be dropped early.
+    fn early_drop(&mut self) -> bool {
+        let current = self.config.limit.of(self.queue.len(), self.queue.bytes) as f64;
+        self.avg = (1.0 - self.config.weight) * self.avg + self.config.weight * current;
+
+        let (min, max) = (self.config.min as f64, self.config.max as f64);
+        if self.avg < min {
+            self.count = -1;
+            return false;
+        }
+        if self.avg >= max {
+            self.count = -1;
+            return true;
+        }
+        self.count += 1;
+        let pb = self.config.probability * (self.avg - min) / (max - min);
+        let pa = pb / (1.0 - self.count as f64 * pb).max(f64::EPSILON);
+        if self.rng.gen::<f64>() < pa {
+            self.count = 0;
+            return true;
+        }
+        false
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]> + Send + Sync> Qdisc<T> for Red<T> {
+    fn enqueue(&mut self, mut item: T, now: Instant) {
+        let len = item.as_ref().len();
+        if self.early_drop() {
+            if self.config.ecn && set_ce(item.as_mut()) {
+                self.stats.marked += 1;
+            } else {
+                self.stats.early_drops += 1;
+                self.stats.dropped += 1;
+                return;
+            }
+        }
+        if self
+            .config
+            .limit
+            .exceeded_by(self.queue.len(), self.queue.bytes, len)
+        {
+            self.stats.overlimits += 1;
+            self.stats.dropped += 1;
+            return;
+        }
+        self.stats.enqueued += 1;
+        self.queue.push(item, now);
+    }
+
+    fn dequeue(&mut self, _now: Instant) -> Option<T> {
+        let entry = self.queue.pop()?;
+        self.stats.dequeued += 1;
+        Some(entry.item)
+    }
+
+    fn stats(&self) -> QdiscStats {
+        QdiscStats {
+            backlog: self.queue.len(),
+            backlog_bytes: self.queue.bytes,
+            ..self.stats
+        }
+    }
+
+    fn drain(&mut self) -> Vec<T> {
+        self.queue.drain().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::{ecn::tests::ipv4_frame, qdisc::Size};
+
+    /// A RED whose average follows the queue at once.
+    fn red(ecn: bool) -> Red<Vec<u8>> {
+        Red::new(RedConfig {
+            limit: Size::Packets(100),
+            min: 10,
+            max: 30,
+            probability: 0.1,
+            weight: 1.0,
+            ecn,
+        })
+    }
+
+    fn fill(red: &mut Red<Vec<u8>>, frames: usize) {
+        let now = Instant::now();
+        for _ in 0..frames {
+            red.queue.push(vec![0; 100], now);
+        }
+    }
+
+    #[test]
+    fn no_drop_under_min() {
+        let mut red = red(false);
+        let now = Instant::now();
+        for _ in 0..10 {
+            red.enqueue(vec![0; 100], now);
+        }
+        assert_eq!(red.stats().enqueued, 10);
+        assert_eq!(red.stats().dropped, 0);
+    }
+
+    #[test]
+    fn drop_over_max() {
+        let mut red = red(false);
+        fill(&mut red, 30);
+        for _ in 0..100 {
+            red.enqueue(vec![0; 100], Instant::now());
+        }
+        assert_eq!(red.stats().early_drops, 100);
+        assert_eq!(red.stats().backlog, 30);
+    }
+
+    #[test]
+    fn drop_probability() {
+        // Halfway between min and max, each frame is dropped with pb = 0.05,
+        // spread by the count since the last drop so that the gaps between
+        // drops are uniform between 1 and 1 / pb frames.
+        let mut red = red(false);
+        fill(&mut red, 20);
+        let mut gap = 0;
+        let mut drops = 0;
+        let frames = 100_000;
+        for _ in 0..frames {
+            gap += 1;
+            if red.early_drop() {
+                assert!(gap <= 20, "gap of {gap} frames");
+                drops += 1;
+                gap = 0;
+            }
+        }
+        let rate = drops as f64 / frames as f64;
+        // 1 / 10.5 for a mean gap of (1 + 20) / 2.
+        assert!((0.085..0.105).contains(&rate), "drop rate {rate}");
+    }
+
+    #[test]
+    fn ecn_marks_instead_of_dropping() {
+        let mut red = red(true);
+        fill(&mut red, 30);
+        let source = Ipv4Addr::new(10, 0, 0, 1);
+        red.enqueue(ipv4_frame(source, 0b10, 100), Instant::now());
+        red.enqueue(ipv4_frame(source, 0b00, 100), Instant::now());
+        let stats = red.stats();
+        assert_eq!((stats.marked, stats.early_drops), (1, 1));
+        let marked = red.queue.entries.back().unwrap();
+        assert_eq!(marked.item[15] & 0b11, 0b11);
+    }
+}
@@ -0,0 +1,198 @@
+//! The routing table of the router, looked up by longest prefix match.
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, bail};
+use hwaddr::HwAddr;
+
+/// An address with a prefix length, like `10.0.1.0/24`. Interface addresses
+/// keep their host bits, like `10.0.1.254/24`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Prefix {
+    pub addr: IpAddr,
+    pub len: u8,
+}
+
+impl Prefix {
+    fn max_len(addr: &IpAddr) -> u8 {
+        match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        }
+    }
+
+    /// The prefix with its host bits cleared.
+    pub fn network(&self) -> Prefix {
+        let addr = match self.addr {
+            IpAddr::V4(addr) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.len)).unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(u32::from(addr) & mask))
+            }
+            IpAddr::V6(addr) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.len))
+                    .unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(addr) & mask))
+            }
+        };
+        Prefix {
+            addr,
+            len: self.len,
+        }
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let other = Prefix {
+            addr,
+            len: self.len,
+        };
+        addr.is_ipv4() == self.addr.is_ipv4() && other.network() == self.network()
+    }
+}
+
+impl FromStr for Prefix {
+    type Err = anyhow::Error;
+
+    /// A bare address is a host prefix.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (addr, len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| anyhow!("invalid address {s}"))?;
+        let max_len = Prefix::max_len(&addr);
+        let len = match len {
+            Some(len) => len
+                .parse()
+                .map_err(|_| anyhow!("invalid prefix length {s}"))?,
+            None => max_len,
+        };
+        if len > max_len {
+            bail!("invalid prefix length {s}, at most {max_len}");
+        }
+        Ok(Prefix { addr, len })
+    }
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Route {
+    pub prefix: Prefix,
+    /// The gateway of the route, `None` when the destination is on the link.
+    pub via: Option<IpAddr>,
+    /// The port the route goes out through, as the MAC of its node.
+    pub port: HwAddr,
+}
+
+impl Route {
+    /// The neighbor a packet for `destination` goes to.
+    pub fn next_hop(&self, destination: IpAddr) -> IpAddr {
+        self.via.unwrap_or(destination)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RouteTable {
+    /// Sorted by decreasing prefix length, so that the first match is the
+    /// longest.
+    routes: Vec<Route>,
+}
+
+impl RouteTable {
+    pub fn insert(&mut self, route: Route) {
+        let at = self
+            .routes
+            .partition_point(|other| other.prefix.len >= route.prefix.len);
+        self.routes.insert(at, route);
+    }
+
+    /// The route of the longest prefix holding `destination`.
+    pub fn lookup(&self, destination: IpAddr) -> Option<&Route> {
+        self.routes
+            .iter()
+            .find(|route| route.prefix.contains(destination))
+    }
+
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(prefix: &str, port: u8) -> Route {
+        Route {
+            prefix: prefix.parse().unwrap(),
+            via: None,
+            port: HwAddr::from([0xaa, 0, 0, 0, 0, port]),
+        }
+    }
+
+    fn port(table: &RouteTable, destination: &str) -> Option<u8> {
+        table
+            .lookup(destination.parse().unwrap())
+            .map(|route| route.port.octets()[5])
+    }
+
+    #[test]
+    fn prefixes() {
+        let prefix: Prefix = "10.0.1.254/24".parse().unwrap();
+        assert_eq!(prefix.network(), "10.0.1.0/24".parse().unwrap());
+        assert!(prefix.contains("10.0.1.1".parse().unwrap()));
+        assert!(!prefix.contains("10.0.2.1".parse().unwrap()));
+        assert!(!prefix.contains("::a00:101".parse().unwrap()));
+        let host: Prefix = "fd00::1".parse().unwrap();
+        assert_eq!(host.len, 128);
+        assert_eq!(host.to_string(), "fd00::1/128");
+        let all: Prefix = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains("192.168.1.1".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<Prefix>().is_err());
+        assert!("10.0.0/8".parse::<Prefix>().is_err());
+    }
+
+    #[test]
+    fn longest_prefix_match() {
+        let mut table = RouteTable::default();
+        // In any order.
+        table.insert(route("10.0.0.0/8", 1));
+        table.insert(route("10.0.1.5/32", 3));
+        table.insert(route("0.0.0.0/0", 0));
+        table.insert(route("10.0.1.0/24", 2));
+        table.insert(route("fd00::/16", 4));
+        table.insert(route("fd00:1::/64", 5));
+        assert_eq!(port(&table, "10.0.1.5"), Some(3));
+        assert_eq!(port(&table, "10.0.1.6"), Some(2));
+        assert_eq!(port(&table, "10.0.2.1"), Some(1));
+        assert_eq!(port(&table, "192.168.0.1"), Some(0));
+        assert_eq!(port(&table, "fd00:1::1"), Some(5));
+        assert_eq!(port(&table, "fd00:2::1"), Some(4));
+        // The IPv4 default route holds no IPv6 address.
+        assert_eq!(port(&table, "fe80::1"), None);
+        let lens: Vec<_> = table
+            .routes()
+            .iter()
+            .map(|route| route.prefix.len)
+            .collect();
+        assert!(lens.windows(2).all(|w| w[0] >= w[1]));
+    }
+
+    #[test]
+    fn next_hop() {
+        let mut route = route("10.0.4.0/24", 1);
+        let destination = "10.0.4.1".parse().unwrap();
+        assert_eq!(route.next_hop(destination), destination);
+        route.via = Some("10.0.3.1".parse().unwrap());
+        assert_eq!(route.next_hop(destination), route.via.unwrap());
+    }
+}
@@ -0,0 +1,580 @@
+//! An IP router, to run instead of the bridge of `ForwardActor` when the
+//! nodes are in different subnets.
+//!
+//! Each port of the router is an interface with its own MAC and addresses,
+//! declared in the topology file. The router answers the ARP requests and
+//! neighbor solicitations for its addresses and the pings to them, and
+//! forwards the other IPv4 and IPv6 packets by longest prefix match against
+//! its routing table: the subnets of its interfaces and the static routes of
+//! the topology. A forwarded packet loses one from its TTL or hop limit,
+//! gets the MAC of its next hop, which the router resolves with ARP or NDP,
+//! and goes through the link between the nodes of the ports like with the
+//! bridge.
+//!
+//! The packets the router cannot forward are answered with the ICMP or
+//! ICMPv6 error a real router would send: time exceeded when their TTL runs
+//! out, destination unreachable when there is no route or their next hop
+//! does not answer, and fragmentation needed or packet too big when they do
+//! not fit in the MTU of the interface or the link out, so that traceroute
+//! and path MTU discovery work across the emulated network.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use hwaddr::HwAddr;
+use log::{debug, trace};
+use netem_rs::{Actor, ActorContext};
+use tokio::time::{sleep_until, Instant};
+
+use crate::{
+    bridge::is_multicast,
+    control::{ControlView, PortCounters},
+    ecn::{ETHERTYPE_IPV4, ETHERTYPE_IPV6},
+    ether::{self, mac_at, ETHER_HEADER_LEN},
+    forward::ForwardActor,
+    ip::{
+        checksum, decrement_ttl, dont_fragment, icmp_error, icmp_frame, icmpv6_checksum_ok,
+        icmpv6_frame, packet_len, set_macs, source_address, IcmpError, DEFAULT_TTL,
+        ICMPV6_ECHO_REPLY, ICMPV6_ECHO_REQUEST, ICMP_ECHO_REPLY, ICMP_ECHO_REQUEST,
+        ICMP_HEADER_LEN, IPV6_HEADER_LEN, PROTO_ICMP, PROTO_ICMPV6,
+    },
+    neighbor::{
+        arp_reply, arp_request, neighbor_advertisement, neighbor_solicitation, Arp, Ndp,
+        ETHERTYPE_ARP, NDP_HOP_LIMIT,
+    },
+    route::{Prefix, RouteTable},
+};
+
+/// An interface of the router.
+#[derive(Clone, Debug)]
+pub struct Interface {
+    /// The port of the interface, as the MAC of its node.
+    pub port: HwAddr,
+    /// The MAC the router sends from and answers ARP and NDP with.
+    pub mac: HwAddr,
+    /// The addresses of the interface, with the length of their subnet.
+    pub addresses: Vec<Prefix>,
+    /// Largest IP packet sent out of the interface.
+    pub mtu: Option<usize>,
+}
+
+impl Interface {
+    pub fn has_address(&self, ip: IpAddr) -> bool {
+        self.addresses.iter().any(|prefix| prefix.addr == ip)
+    }
+
+    /// Whether `ip` is in one of the subnets of the interface.
+    pub fn on_link(&self, ip: IpAddr) -> bool {
+        self.addresses.iter().any(|prefix| prefix.contains(ip))
+    }
+
+    /// The address to send to `ip` from: the one of its subnet, or else the
+    /// first one of its family.
+    pub fn source_for(&self, ip: IpAddr) -> Option<IpAddr> {
+        self.addresses
+            .iter()
+            .find(|prefix| prefix.contains(ip))
+            .or_else(|| {
+                self.addresses
+                    .iter()
+                    .find(|prefix| prefix.addr.is_ipv4() == ip.is_ipv4())
+            })
+            .map(|prefix| prefix.addr)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RouterConfig {
+    pub interfaces: Vec<Interface>,
+    pub routes: RouteTable,
+}
+
+impl RouterConfig {
+    /// Whether the nodes are routed rather than bridged.
+    pub fn is_enabled(&self) -> bool {
+        !self.interfaces.is_empty()
+    }
+
+    /// The interface on the port of the node `port`.
+    pub fn interface(&self, port: HwAddr) -> Option<&Interface> {
+        self.interfaces
+            .iter()
+            .find(|interface| interface.port == port)
+    }
+
+    /// Whether `ip` is an address of the router.
+    pub fn is_local(&self, ip: IpAddr) -> bool {
+        self.interfaces
+            .iter()
+            .any(|interface| interface.has_address(ip))
+    }
+}
+
+static ROUTER_CONFIG: OnceLock<RouterConfig> = OnceLock::new();
+
+/// The configuration set by `init_router_config`, or an empty one.
+pub fn router_config() -> &'static RouterConfig {
+    ROUTER_CONFIG.get_or_init(RouterConfig::default)
+}
+
+/// Must be called before the runtime starts so that every `RouterActor`
+/// picks it up.
+pub fn init_router_config(config: RouterConfig) -> anyhow::Result<()> {
+    ROUTER_CONFIG
+        .set(config)
+        .map_err(|_| anyhow::anyhow!("router config is already set"))
+}
+
+/// How long a packet waits for the MAC of its next hop before it is
+/// dropped, and how often the next hop is solicited meanwhile, as with the
+/// 3 probes of Linux.
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(3);
+const SOLICIT_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the waiting packets look for the MAC of their next hop, which
+/// the actor of another port may learn.
+const RESOLVE_POLL: Duration = Duration::from_millis(10);
+/// Most packets waiting for the MAC of their next hop.
+const MAX_PENDING: usize = 64;
+
+/// A packet waiting for the MAC of its next hop.
+struct Pending {
+    from: HwAddr,
+    port: HwAddr,
+    next_hop: IpAddr,
+    data: Vec<u8>,
+    expires: Instant,
+}
+
+/// Routes the frames received from its port. The links, the control
+/// service and the counters of the port are those of `ForwardActor`.
+pub struct RouterActor {
+    forward: ForwardActor,
+    config: &'static RouterConfig,
+    pending: Vec<Pending>,
+    /// When each next hop being resolved was last solicited.
+    solicited: HashMap<IpAddr, Instant>,
+}
+
+impl RouterActor {
+    /// Send a frame of the router itself out through `port`.
+    async fn send_to(&self, port: HwAddr, frame: Vec<u8>) -> anyhow::Result<()> {
+        self.forward.send_to(port, frame).await
+    }
+
+    async fn receive(&mut self, frame: &[u8], now: Instant) -> anyhow::Result<()> {
+        let source = mac_at(frame, 6);
+        self.forward.find_port(source).await;
+        let port = self.forward.port.unwrap_or(source);
+        let config = self.config;
+        let Some(interface) = config.interface(port) else {
+            trace!("no interface on the port of {port}");
+            return Ok(());
+        };
+        let destination = mac_at(frame, 0);
+        if destination != interface.mac && !is_multicast(destination) {
+            return Ok(());
+        }
+        // Interfaces are not VLAN-aware, tagged frames are not for them.
+        match u16::from_be_bytes([frame[12], frame[13]]) {
+            ETHERTYPE_ARP => self.receive_arp(interface, frame, now).await,
+            ETHERTYPE_IPV4 if destination == interface.mac => {
+                self.receive_ipv4(interface, frame, now).await
+            }
+            ETHERTYPE_IPV6 => self.receive_ipv6(interface, frame, now).await,
+            _ => Ok(()),
+        }
+    }
+
+    async fn receive_arp(
+        &mut self,
+        interface: &Interface,
+        frame: &[u8],
+        now: Instant,
+    ) -> anyhow::Result<()> {
+        let Some(arp) = Arp::parse(&frame[ETHER_HEADER_LEN..]) else {
+            return Ok(());
+        };
+        let sender = IpAddr::V4(arp.sender_ip);
+        if interface.on_link(sender) {
+            self.forward
+                .control
+                .neighbors()
+                .learn(sender, arp.sender_mac, now);
+        }
+        if arp.request && interface.has_address(arp.target_ip.into()) {
+            trace!("{} asks for {}", arp.sender_ip, arp.target_ip);
+            self.send_to(interface.port, arp_reply(&arp, interface.mac))
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn receive_ipv4(
+        &mut self,
+        interface: &Interface,
+        frame: &[u8],
+        now: Instant,
+    ) -> anyhow::Result<()> {
+        // The header and the total length were validated with the frame.
+        let packet = &frame[ETHER_HEADER_LEN..];
+        let header_len = usize::from(packet[0] & 0x0f) * 4;
+        let total_len = usize::from(u16::from_be_bytes([packet[2], packet[3]]));
+        let counters = &self.forward.state.counters;
+        if checksum(&packet[..header_len]) != 0 {
+            trace!("bad IPv4 header checksum");
+            PortCounters::add(&counters.bad_checksum, 1);
+            return Ok(());
+        }
+        let source = Ipv4Addr::from(<[u8; 4]>::try_from(&packet[12..16]).unwrap());
+        let destination = Ipv4Addr::from(<[u8; 4]>::try_from(&packet[16..20]).unwrap());
+        if self.config.is_local(destination.into()) {
+            let message = &packet[header_len..total_len];
+            let echo = message.len() >= ICMP_HEADER_LEN && message[0] == ICMP_ECHO_REQUEST;
+            if packet[9] == PROTO_ICMP && echo {
+                let mut reply = message.to_vec();
+                reply[0] = ICMP_ECHO_REPLY;
+                let macs = (mac_at(frame, 6), interface.mac);
+                let reply = icmp_frame(macs, destination, source, &reply);
+                self.send_to(interface.port, reply).await?;
+            }
+            return Ok(());
+        }
+        if destination.is_broadcast() || destination.is_multicast() {
+            return Ok(());
+        }
+        self.route(interface, frame, destination.into(), now).await
+    }
+
+    async fn receive_ipv6(
+        &mut self,
+        interface: &Interface,
+        frame: &[u8],
+        now: Instant,
+    ) -> anyhow::Result<()> {
+        let packet = &frame[ETHER_HEADER_LEN..];
+        let payload_len = usize::from(u16::from_be_bytes([packet[4], packet[5]]));
+        let (next_header, hop_limit) = (packet[6], packet[7]);
+        let source = Ipv6Addr::from(<[u8; 16]>::try_from(&packet[8..24]).unwrap());
+        let destination = Ipv6Addr::from(<[u8; 16]>::try_from(&packet[24..40]).unwrap());
+        let message = &packet[IPV6_HEADER_LEN..IPV6_HEADER_LEN + payload_len];
+        let icmp =
+            next_header == PROTO_ICMPV6 && icmpv6_checksum_ok(&source, &destination, message);
+        if icmp && hop_limit == NDP_HOP_LIMIT {
+            if let Some(ndp) = Ndp::parse(message) {
+                return self
+                    .receive_ndp(interface, mac_at(frame, 6), source, ndp, now)
+                    .await;
+            }
+        }
+        if self.config.is_local(destination.into()) {
+            if icmp && message.len() >= ICMP_HEADER_LEN && message[0] == ICMPV6_ECHO_REQUEST {
+                let mut reply = message.to_vec();
+                reply[0] = ICMPV6_ECHO_REPLY;
+                let macs = (mac_at(frame, 6), interface.mac);
+                let reply = icmpv6_frame(macs, destination, source, DEFAULT_TTL, &reply);
+                self.send_to(interface.port, reply).await?;
+            }
+            return Ok(());
+        }
+        // Link-local addresses do not leave their link.
+        let link_local = |ip: &Ipv6Addr| ip.segments()[0] & 0xffc0 == 0xfe80;
+        if mac_at(frame, 0) != interface.mac
+            || destination.is_multicast()
+            || link_local(&destination)
+            || link_local(&source)
+        {
+            return Ok(());
+        }
+        self.route(interface, frame, destination.into(), now).await
+    }
+
+    async fn receive_ndp(
+        &mut self,
+        interface: &Interface,
+        sender_mac: HwAddr,
+        source: Ipv6Addr,
+        ndp: Ndp,
+        now: Instant,
+    ) -> anyhow::Result<()> {
+        let neighbors = self.forward.control.neighbors();
+        match ndp {
+            // Duplicate address detection solicits from the unspecified
+            // address, and is not answered by routers.
+            Ndp::Solicitation { .. } if source.is_unspecified() => {}
+            Ndp::Solicitation { target, source_mac } => {
+                let source_mac = source_mac.unwrap_or(sender_mac);
+                neighbors.learn(source.into(), source_mac, now);
+                if interface.has_address(target.into()) {
+                    trace!("{source} asks for {target}");
+                    let advertisement =
+                        neighbor_advertisement(interface.mac, target, source_mac, source);
+                    self.send_to(interface.port, advertisement).await?;
+                }
+            }
+            Ndp::Advertisement { target, target_mac } => {
+                if interface.on_link(target.into()) {
+                    neighbors.learn(target.into(), target_mac.unwrap_or(sender_mac), now);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Forward an IPv4 or IPv6 packet that is not for the router, received
+    /// on `interface`.
+    async fn route(
+        &mut self,
+        interface: &Interface,
+        frame: &[u8],
+        destination: IpAddr,
+        now: Instant,
+    ) -> anyhow::Result<()> {
+        let config = self.config;
+        let counters = &self.forward.state.counters;
+        let Some(route) = config.routes.lookup(destination) else {
+            trace!("no route to {destination}");
+            PortCounters::add(&counters.no_route, 1);
+            return self
+                .send_error(interface, frame, IcmpError::NoRoute, now)
+                .await;
+        };
+        let mut data = frame.to_vec();
+        if !decrement_ttl(&mut data[ETHER_HEADER_LEN..]) {
+            trace!("TTL of a packet to {destination} expired");
+            PortCounters::add(&counters.ttl_expired, 1);
+            return self
+                .send_error(interface, frame, IcmpError::TtlExpired, now)
+                .await;
+        }
+        // The router does not fragment, so IPv4 packets that may be
+        // fragmented are dropped all the same, without an error.
+        let packet = &frame[ETHER_HEADER_LEN..];
+        let link_mtu = self.forward.link(interface.port, route.port).params().mtu;
+        let interface_mtu = config.interface(route.port).and_then(|out| out.mtu);
+        let mtu = interface_mtu.into_iter().chain(link_mtu).min();
+        if let Some(mtu) = mtu.filter(|&mtu| packet_len(packet) > mtu) {
+            trace!("packet to {destination} larger than the mtu {mtu}");
+            PortCounters::add(&self.forward.state.counters.too_big, 1);
+            if packet[0] >> 4 == 6 || dont_fragment(packet) {
+                return self
+                    .send_error(interface, frame, IcmpError::TooBig(mtu), now)
+                    .await;
+            }
+            return Ok(());
+        }
+        let next_hop = route.next_hop(destination);
+        self.deliver(interface.port, route.port, next_hop, data, now)
+            .await
+    }
+
+    /// Tell the sender of the packet in `frame`, received on `interface`,
+    /// why it was dropped.
+    async fn send_error(
+        &mut self,
+        interface: &Interface,
+        frame: &[u8],
+        error: IcmpError,
+        now: Instant,
+    ) -> anyhow::Result<()> {
+        let packet = &frame[ETHER_HEADER_LEN..];
+        let Some(message) = icmp_error(error, packet) else {
+            return Ok(());
+        };
+        let Some(destination) = source_address(packet) else {
+            return Ok(());
+        };
+        if !self.forward.icmp_limiter.allow(now) {
+            trace!("ICMP error to {destination} rate limited");
+            return Ok(());
+        }
+        let macs = (mac_at(frame, 6), interface.mac);
+        let reply = match (interface.source_for(destination), destination) {
+            (Some(IpAddr::V4(source)), IpAddr::V4(destination)) => {
+                icmp_frame(macs, source, destination, &message)
+            }
+            (Some(IpAddr::V6(source)), IpAddr::V6(destination)) => {
+                icmpv6_frame(macs, source, destination, DEFAULT_TTL, &message)
+            }
+            _ => {
+                debug!("no address to send an ICMP error to {destination} from");
+                return Ok(());
+            }
+        };
+        trace!("{error:?} error to {destination}");
+        PortCounters::add(&self.forward.state.counters.icmp_errors, 1);
+        self.send_to(interface.port, reply).await
+    }
+
+    /// Send a routed packet to `next_hop` through the link from the node of
+    /// `from` to the one of `port`, once the MAC of the next hop is known.
+    async fn deliver(
+        &mut self,
+        from: HwAddr,
+        port: HwAddr,
+        next_hop: IpAddr,
+        mut data: Vec<u8>,
+        now: Instant,
+    ) -> anyhow::Result<()> {
+        let config = self.config;
+        // Routes always go out through an interface.
+        let Some(interface) = config.interface(port) else {
+            return Ok(());
+        };
+        match self.forward.control.neighbors().lookup(next_hop, now) {
+            Some(mac) => {
+                set_macs(&mut data, mac, interface.mac);
+                self.forward
+                    .forward_data(from, port, Arc::new(data), now)
+                    .await
+            }
+            None if self.pending.len() >= MAX_PENDING => {
+                trace!("too many packets waiting for their next hop");
+                PortCounters::add(&self.forward.state.counters.unresolved, 1);
+                Ok(())
+            }
+            None => {
+                self.pending.push(Pending {
+                    from,
+                    port,
+                    next_hop,
+                    data,
+                    expires: now + RESOLVE_TIMEOUT,
+                });
+                self.solicit(interface, next_hop, now).await
+            }
+        }
+    }
+
+    /// Ask for the MAC of `next_hop` on `interface`, unless it was just
+    /// asked for.
+    async fn solicit(
+        &mut self,
+        interface: &Interface,
+        next_hop: IpAddr,
+        now: Instant,
+    ) -> anyhow::Result<()> {
+        if self
+            .solicited
+            .get(&next_hop)
+            .is_some_and(|&at| now.duration_since(at) < SOLICIT_INTERVAL)
+        {
+            return Ok(());
+        }
+        self.solicited.insert(next_hop, now);
+        let frame = match (interface.source_for(next_hop), next_hop) {
+            (Some(IpAddr::V4(source)), IpAddr::V4(target)) => {
+                arp_request(interface.mac, source, target)
+            }
+            (Some(IpAddr::V6(source)), IpAddr::V6(target)) => {
+                neighbor_solicitation(interface.mac, source, target)
+            }
+            _ => {
+                debug!("no address to solicit {next_hop} from");
+                return Ok(());
+            }
+        };
+        trace!("solicit {next_hop}");
+        self.send_to(interface.port, frame).await
+    }
+
+    /// Send the waiting packets whose next hop got resolved, drop those
+    /// that waited too long and solicit the others again.
+    async fn resolve_pending(&mut self, now: Instant) -> anyhow::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let config = self.config;
+        for pending in std::mem::take(&mut self.pending) {
+            let Some(interface) = config.interface(pending.port) else {
+                continue;
+            };
+            if self
+                .forward
+                .control
+                .neighbors()
+                .lookup(pending.next_hop, now)
+                .is_some()
+            {
+                self.deliver(
+                    pending.from,
+                    pending.port,
+                    pending.next_hop,
+                    pending.data,
+                    now,
+                )
+                .await?;
+            } else if pending.expires <= now {
+                trace!("{} is unreachable", pending.next_hop);
+                PortCounters::add(&self.forward.state.counters.unresolved, 1);
+                if let Some(ingress) = config.interface(pending.from) {
+                    self.send_error(ingress, &pending.data, IcmpError::Unreachable, now)
+                        .await?;
+                }
+            } else {
+                self.solicit(interface, pending.next_hop, now).await?;
+                self.pending.push(pending);
+            }
+        }
+        self.solicited
+            .retain(|_, &mut at| now.duration_since(at) < RESOLVE_TIMEOUT);
+        Ok(())
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        let resolve = (!self.pending.is_empty()).then(|| Instant::now() + RESOLVE_POLL);
+        self.forward
+            .next_deadline()
+            .into_iter()
+            .chain(resolve)
+            .min()
+    }
+}
+
+impl Actor for RouterActor {
+    type C = ControlView;
+
+    fn new(context: ActorContext<Self::C>) -> Self {
+        Self {
+            forward: ForwardActor::new(context),
+            config: router_config(),
+            pending: Vec::new(),
+            solicited: HashMap::new(),
+        }
+    }
+
+    async fn run(&mut self) -> anyhow::Result<()> {
+        // The counters are borrowed while `self` is.
+        let state = self.forward.state.clone();
+        loop {
+            let deadline = self.next_deadline();
+            tokio::select! {
+                frames = self.forward.context.receive_handle.receive_frames() => {
+                    let now = Instant::now();
+                    for frame in frames? {
+                        let data = frame.data_ref();
+                        let counters = &state.counters;
+                        let len = data.as_ref().len() as u64;
+                        PortCounters::add(&counters.rx_frames, 1);
+                        PortCounters::add(&counters.rx_bytes, len);
+                        if let Err(reason) = ether::validate(data.as_ref()) {
+                            debug!("actor {}: dropped a {len} bytes frame, {reason}", self.forward.id);
+                            PortCounters::add(counters.malformed(reason), 1);
+                            continue;
+                        }
+                        self.receive(data.as_ref(), now).await?;
+                    }
+                }
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {}
+            }
+            let now = Instant::now();
+            self.resolve_pending(now).await?;
+            if !self.forward.tick(now).await? {
+                return Ok(());
+            }
+        }
+    }
+}
@@ -0,0 +1,372 @@
+//! Token bucket rate shaper, the equivalent of `tc tbf`.
+//!
+//! Frames that exceed the rate wait in a qdisc until enough tokens have
+//! accumulated, the qdisc decides which of them are dropped. Instead of a
+//! constant rate, the tokens can come from the delivery opportunities of a
+//! trace.
+
+use std::{fmt, str::FromStr, sync::Arc, time::Duration};
+
+use anyhow::{bail, Context};
+use tokio::time::Instant;
+
+use crate::{
+    netem::parse_rate,
+    qdisc::{Qdisc, QdiscConfig, QdiscStats},
+    trace::{Trace, OPPORTUNITY_SIZE},
+};
+
+/// Bytes a frame occupies on an Ethernet wire on top of what AF_XDP sees:
+/// preamble and start frame delimiter (8), frame check sequence (4) and the
+/// inter-frame gap (12).
+pub const ETHERNET_OVERHEAD: usize = 24;
+
+/// Parameters of a shaper. It can be parsed from tbf style options, e.g.
+/// `rate 100mbit burst 32kb limit 1000 overhead 24`, optionally followed by
+/// `qdisc` and the options of the qdisc, e.g. `qdisc codel target 5ms`.
+/// `trace FILE` replaces the rate by the delivery opportunities of a trace.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShaperConfig {
+    /// Rate in bits per second.
+    pub rate: u64,
+    /// Size of the bucket in bytes.
+    pub burst: usize,
+    /// Bytes accounted for each frame on top of its length.
+    pub overhead: usize,
+    /// Queue of the frames waiting for tokens.
+    pub qdisc: QdiscConfig,
+    /// Release frames at the delivery opportunities of the trace rather than
+    /// at `rate`, which is then the mean rate of the trace.
+    pub trace: Option<Arc<Trace>>,
+}
+
+impl ShaperConfig {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            // Enough for about 1ms at the rate, but never less than a full
+            // frame.
+            burst: ((rate / 8 / 1000) as usize).max(1514 + ETHERNET_OVERHEAD),
+            overhead: ETHERNET_OVERHEAD,
+            qdisc: QdiscConfig::default(),
+            trace: None,
+        }
+    }
+
+    /// Follow `trace`, where every opportunity lets out `OPPORTUNITY_SIZE`
+    /// bytes of frames without any overhead.
+    pub fn trace(trace: Arc<Trace>) -> Self {
+        Self {
+            rate: trace.mean_rate(),
+            burst: OPPORTUNITY_SIZE,
+            overhead: 0,
+            qdisc: QdiscConfig::default(),
+            trace: Some(trace),
+        }
+    }
+}
+
+impl FromStr for ShaperConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rate = None;
+        let mut burst = None;
+        let mut overhead = None;
+        let mut limit = None;
+        let mut qdisc = None;
+        let mut trace = None;
+        let mut args = s.split_whitespace();
+        while let Some(arg) = args.next() {
+            match arg {
+                "rate" => rate = Some(parse_rate(args.next().context("rate needs a rate")?)?),
+                "burst" | "buffer" | "maxburst" => {
+                    burst = Some(parse_size(args.next().context("burst needs a size")?)?)
+                }
+                "overhead" => {
+                    overhead = Some(args.next().context("overhead needs a size")?.parse()?)
+                }
+                "limit" => limit = Some(args.next().context("limit needs a size")?.parse()?),
+                "trace" => trace = Some(Trace::load(args.next().context("trace needs a file")?)?),
+                "qdisc" => {
+                    qdisc = Some(args.by_ref().collect::<Vec<_>>().join(" ").parse()?);
+                }
+                _ => bail!("unknown shaper option {arg}"),
+            }
+        }
+        let mut config = match (rate, trace) {
+            (None, Some(trace)) => ShaperConfig::trace(Arc::new(trace)),
+            (Some(rate), None) => ShaperConfig::new(rate),
+            (Some(_), Some(_)) => bail!("rate and trace are exclusive"),
+            (None, None) => bail!("shaper needs a rate or a trace"),
+        };
+        if let Some(burst) = burst {
+            config.burst = burst;
+        }
+        if let Some(overhead) = overhead {
+            config.overhead = overhead;
+        }
+        if let Some(qdisc) = qdisc {
+            config.qdisc = qdisc;
+        }
+        if let Some(limit) = limit {
+            config.qdisc.set_limit(limit);
+        }
+        Ok(config)
+    }
+}
+
+impl fmt::Display for ShaperConfig {
+    /// Write the options in the syntax accepted by `from_str`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.trace.as_ref().and_then(|trace| trace.path()) {
+            Some(path) => write!(f, "trace {}", path.display())?,
+            None => write!(f, "rate {}bit burst {}", self.rate, self.burst)?,
+        }
+        write!(f, " overhead {} qdisc {}", self.overhead, self.qdisc)
+    }
+}
+
+/// Parse a tc style size such as `1514`, `32kb`, `1mb` or `64kbit`.
+pub fn parse_size(s: &str) -> anyhow::Result<usize> {
+    let lower = s.to_ascii_lowercase();
+    let split = lower
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(lower.len());
+    let (value, unit) = lower.split_at(split);
+    let value: usize = value.parse().with_context(|| format!("invalid size {s}"))?;
+    let bytes = match unit {
+        "" | "b" => value,
+        "k" | "kb" => value * 1024,
+        "m" | "mb" => value * 1024 * 1024,
+        "g" | "gb" => value * 1024 * 1024 * 1024,
+        "kbit" => value * 1024 / 8,
+        "mbit" => value * 1024 * 1024 / 8,
+        "gbit" => value * 1024 * 1024 * 1024 / 8,
+        _ => bail!("invalid size unit in {s}"),
+    };
+    Ok(bytes)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ShaperStats {
+    pub sent: u64,
+    pub sent_bytes: u64,
+    pub qdisc: QdiscStats,
+}
+
+pub struct Shaper<T> {
+    config: ShaperConfig,
+    /// Available tokens in bytes, negative after a frame larger than the
+    /// bucket went out.
+    tokens: f64,
+    last_refill: Instant,
+    /// When the trace started, and how many of its opportunities went by
+    /// until `last_refill`.
+    trace_start: Instant,
+    opportunities: u64,
+    qdisc: Box<dyn Qdisc<T>>,
+    /// The head of the qdisc, taken out to know its size like tbf does.
+    peeked: Option<T>,
+    sent: u64,
+    sent_bytes: u64,
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]> + Send + Sync + 'static> Shaper<T> {
+    pub fn new(config: ShaperConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            tokens: initial_tokens(&config),
+            last_refill: now,
+            trace_start: now,
+            opportunities: 0,
+            qdisc: config.qdisc.build(),
+            config,
+            peeked: None,
+            sent: 0,
+            sent_bytes: 0,
+        }
+    }
+
+    pub fn config(&self) -> &ShaperConfig {
+        &self.config
+    }
+
+    /// Replace the parameters. Changing the kind of qdisc moves the queued
+    /// frames to the new one, changing the trace starts the new one over.
+    pub fn set_config(&mut self, config: ShaperConfig, now: Instant) {
+        if config.trace != self.config.trace {
+            self.tokens = initial_tokens(&config);
+            self.last_refill = now;
+            self.trace_start = now;
+            self.opportunities = 0;
+        }
+        if config.trace.is_none() {
+            self.tokens = self.tokens.min(config.burst as f64);
+        }
+        if config.qdisc != self.config.qdisc {
+            let mut qdisc = config.qdisc.build();
+            for item in self.qdisc.drain() {
+                qdisc.enqueue(item, now);
+            }
+            self.qdisc = qdisc;
+        }
+        self.config = config;
+    }
+
+    pub fn stats(&self) -> ShaperStats {
+        let mut qdisc = self.qdisc.stats();
+        if let Some(item) = &self.peeked {
+            qdisc.backlog += 1;
+            qdisc.backlog_bytes += item.as_ref().len();
+        }
+        ShaperStats {
+            sent: self.sent,
+            sent_bytes: self.sent_bytes,
+            qdisc,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peeked.is_none() && self.qdisc.is_empty()
+    }
+
+    pub fn enqueue(&mut self, item: T, now: Instant) {
+        if self.is_empty() {
+            // Account for the time the queue was empty before this frame
+            // arrives, in which opportunities of a trace are lost.
+            self.refill(now);
+        }
+        self.qdisc.enqueue(item, now);
+    }
+
+    /// Pop the head of the queue if the bucket holds enough tokens for it.
+    pub fn dequeue(&mut self, now: Instant) -> Option<T> {
+        self.refill(now);
+        if self.peeked.is_none() {
+            self.peeked = self.qdisc.dequeue(now);
+        }
+        let cost = self.cost(self.peeked.as_ref()?);
+        if self.tokens < self.required(cost) {
+            return None;
+        }
+        self.tokens -= cost;
+        let item = self.peeked.take()?;
+        self.sent += 1;
+        self.sent_bytes += item.as_ref().len() as u64;
+        Some(item)
+    }
+
+    /// When the head of the queue can be sent, if anything is queued.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let Some(item) = &self.peeked else {
+            // The size of the head is unknown until it is taken out.
+            return (!self.qdisc.is_empty()).then_some(self.last_refill);
+        };
+        let missing = self.required(self.cost(item)) - self.tokens;
+        if missing <= 0.0 {
+            return Some(self.last_refill);
+        }
+        if let Some(trace) = &self.config.trace {
+            return Some(self.trace_start + trace.next_opportunity(self.opportunities));
+        }
+        if self.config.rate == 0 {
+            // No token ever comes, parsing rejects such a rate.
+            return None;
+        }
+        let secs = missing * 8.0 / self.config.rate as f64;
+        Some(self.last_refill + Duration::from_secs_f64(secs))
+    }
+
+    /// Take every queued frame out of the shaper.
+    pub fn drain(mut self) -> impl Iterator<Item = T> {
+        self.peeked.take().into_iter().chain(self.qdisc.drain())
+    }
+
+    fn cost(&self, item: &T) -> f64 {
+        (item.as_ref().len() + self.config.overhead) as f64
+    }
+
+    /// Tokens the head of the queue needs before it can go.
+    fn required(&self, cost: f64) -> f64 {
+        if self.config.trace.is_some() {
+            // A frame may span several opportunities, like in Mahimahi.
+            cost
+        } else {
+            // A frame larger than the bucket only needs a full bucket.
+            cost.min(self.config.burst as f64)
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now <= self.last_refill {
+            return;
+        }
+        if let Some(trace) = &self.config.trace {
+            let opportunities = trace.opportunities(now.duration_since(self.trace_start));
+            let new = opportunities - self.opportunities;
+            self.opportunities = opportunities;
+            // Opportunities are lost when no frame is waiting for them.
+            self.tokens = if self.is_empty() {
+                0.0
+            } else {
+                self.tokens + (new * OPPORTUNITY_SIZE as u64) as f64
+            };
+        } else {
+            let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.config.rate as f64 / 8.0)
+                .min(self.config.burst as f64);
+        }
+        self.last_refill = now;
+    }
+}
+
+fn initial_tokens(config: &ShaperConfig) -> f64 {
+    if config.trace.is_some() {
+        0.0
+    } else {
+        config.burst as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_rate_is_rejected() {
+        assert!("rate 0bit".parse::<ShaperConfig>().is_err());
+        assert!("rate 0.1bit".parse::<ShaperConfig>().is_err());
+        assert!("rate 0mbit burst 32kb".parse::<ShaperConfig>().is_err());
+        assert_eq!("rate 1kbit".parse::<ShaperConfig>().unwrap().rate, 1000);
+    }
+
+    #[test]
+    fn zero_rate_never_sends() {
+        let mut config = ShaperConfig::new(0);
+        config.burst = 100;
+        let mut shaper = Shaper::new(config);
+        let now = Instant::now();
+        shaper.enqueue(vec![0u8; 1000], now);
+        shaper.enqueue(vec![0u8; 1000], now);
+        // The full bucket lets the first frame out, the second waits forever.
+        assert!(shaper.dequeue(now).is_some());
+        assert!(shaper.dequeue(now + Duration::from_secs(1)).is_none());
+        assert_eq!(shaper.next_deadline(), None);
+    }
+
+    #[test]
+    fn deadline_follows_rate() {
+        let mut config = ShaperConfig::new(8_000_000);
+        config.burst = 1000;
+        config.overhead = 0;
+        let mut shaper = Shaper::new(config);
+        let now = Instant::now();
+        shaper.enqueue(vec![0u8; 1000], now);
+        shaper.enqueue(vec![0u8; 1000], now);
+        assert!(shaper.dequeue(now).is_some());
+        assert!(shaper.dequeue(now).is_none());
+        // 1000 bytes at 1 MB/s.
+        assert_eq!(shaper.next_deadline(), Some(now + Duration::from_millis(1)));
+    }
+}
@@ -0,0 +1,563 @@
+//! A simplified spanning tree protocol, to run the bridge in topologies with
+//! loops.
+//!
+//! Each port exchanges 802.1D configuration BPDUs with the bridges attached
+//! to it. The bridges elect the root, the one with the lowest bridge id, and
+//! every bridge keeps forwarding on its root port, the best path to the root,
+//! and on the ports it is the designated bridge of. The other ports are
+//! alternate, or backup when two ports of the bridge share a segment, and
+//! discard every frame but the BPDUs. As in RSTP, the information received
+//! on a port expires after three hello times without BPDU, so that a failed
+//! link is noticed within `MAX_AGE` and the tree computed again. There is no
+//! proposal/agreement handshake though: a port becoming root or designated
+//! goes through `FORWARD_DELAY` of discarding and of learning before it
+//! forwards, as in STP.
+//!
+//! Ports are known by the actor that runs them. Towards the flooding code,
+//! they are also known by the MAC their node sends with once the actor found
+//! it, and by the id the runtime gave them.
+
+use std::{any::Any, collections::BTreeMap, fmt, sync::Arc, time::Duration};
+
+use hwaddr::HwAddr;
+use log::info;
+use tokio::time::Instant;
+
+use crate::control::PortIds;
+
+/// Group address of the BPDUs, which bridges never forward.
+pub const BPDU_GROUP: [u8; 6] = [0x01, 0x80, 0xc2, 0x00, 0x00, 0x00];
+
+pub const HELLO_TIME: Duration = Duration::from_secs(2);
+/// Three hello times, as in RSTP.
+pub const MAX_AGE: Duration = Duration::from_secs(6);
+/// The smallest forward delay 802.1D allows with `MAX_AGE`.
+pub const FORWARD_DELAY: Duration = Duration::from_secs(4);
+pub const DEFAULT_PRIORITY: u16 = 0x8000;
+/// Path cost of every port, the one of a 1Gbit/s link in RSTP.
+pub const PORT_PATH_COST: u32 = 20_000;
+
+const PORT_PRIORITY: u16 = 0x80;
+/// How long the BPDUs carry the topology change flag after a change.
+const TOPOLOGY_CHANGE_TIME: Duration = Duration::from_secs(4);
+
+const LLC_HEADER: [u8; 3] = [0x42, 0x42, 0x03];
+const BPDU_OFFSET: usize = 14 + LLC_HEADER.len();
+const CONFIG_BPDU_LEN: usize = 35;
+const BPDU_TYPE_CONFIG: u8 = 0x00;
+const BPDU_TYPE_RST: u8 = 0x02;
+const FLAG_TOPOLOGY_CHANGE: u8 = 0x01;
+const MIN_FRAME_LEN: usize = 60;
+
+/// A bridge id, the priority in the 16 high bits and the MAC of the bridge
+/// in the others, so that ids compare like in 802.1D.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BridgeId(u64);
+
+impl BridgeId {
+    pub fn new(priority: u16, mac: HwAddr) -> Self {
+        let mut bytes = [0; 8];
+        bytes[..2].copy_from_slice(&priority.to_be_bytes());
+        bytes[2..].copy_from_slice(&mac.octets());
+        Self(u64::from_be_bytes(bytes))
+    }
+
+    pub fn priority(&self) -> u16 {
+        (self.0 >> 48) as u16
+    }
+
+    pub fn mac(&self) -> HwAddr {
+        let bytes = self.0.to_be_bytes();
+        HwAddr::from([bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7]])
+    }
+}
+
+/// Written like Linux does, e.g. `8000.aa0000000001`.
+impl fmt::Display for BridgeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04x}.", self.priority())?;
+        self.mac()
+            .octets()
+            .iter()
+            .try_for_each(|byte| write!(f, "{byte:02x}"))
+    }
+}
+
+/// What a BPDU says about the path to the root, compared field by field:
+/// the lower the better.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PriorityVector {
+    pub root: BridgeId,
+    pub cost: u32,
+    /// The bridge that sent the BPDU and the id of its port.
+    pub bridge: BridgeId,
+    pub port: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Bpdu {
+    pub vector: PriorityVector,
+    pub topology_change: bool,
+    pub message_age: Duration,
+    pub max_age: Duration,
+}
+
+impl Bpdu {
+    /// The configuration or RST BPDU carried by `frame`, if any. Topology
+    /// change notifications are ignored.
+    pub fn parse(frame: &[u8]) -> Option<Self> {
+        if frame.get(..6)? != BPDU_GROUP || frame.get(14..BPDU_OFFSET)? != LLC_HEADER {
+            return None;
+        }
+        let bpdu = frame.get(BPDU_OFFSET..BPDU_OFFSET + CONFIG_BPDU_LEN)?;
+        if bpdu[..2] != [0, 0] || !matches!(bpdu[3], BPDU_TYPE_CONFIG | BPDU_TYPE_RST) {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_be_bytes([bpdu[i], bpdu[i + 1]]);
+        let id_at = |i: usize| BridgeId(u64::from_be_bytes(bpdu[i..i + 8].try_into().unwrap()));
+        let time_at = |i: usize| Duration::from_secs_f64(f64::from(u16_at(i)) / 256.0);
+        Some(Self {
+            vector: PriorityVector {
+                root: id_at(5),
+                cost: u32::from_be_bytes(bpdu[13..17].try_into().unwrap()),
+                bridge: id_at(17),
+                port: u16_at(25),
+            },
+            topology_change: bpdu[4] & FLAG_TOPOLOGY_CHANGE != 0,
+            message_age: time_at(27),
+            max_age: time_at(29),
+        })
+    }
+
+    /// A configuration BPDU from `source`, padded to the minimum frame size.
+    pub fn to_frame(&self, source: HwAddr) -> Vec<u8> {
+        let time = |time: Duration| ((time.as_secs_f64() * 256.0) as u16).to_be_bytes();
+        let mut frame = Vec::with_capacity(MIN_FRAME_LEN);
+        frame.extend_from_slice(&BPDU_GROUP);
+        frame.extend_from_slice(&source.octets());
+        // An 802.3 frame, whose type field is the length of its payload.
+        frame.extend_from_slice(&((LLC_HEADER.len() + CONFIG_BPDU_LEN) as u16).to_be_bytes());
+        frame.extend_from_slice(&LLC_HEADER);
+        frame.extend_from_slice(&[0, 0, 0, BPDU_TYPE_CONFIG]);
+        frame.push(if self.topology_change {
+            FLAG_TOPOLOGY_CHANGE
+        } else {
+            0
+        });
+        frame.extend_from_slice(&self.vector.root.0.to_be_bytes());
+        frame.extend_from_slice(&self.vector.cost.to_be_bytes());
+        frame.extend_from_slice(&self.vector.bridge.0.to_be_bytes());
+        frame.extend_from_slice(&self.vector.port.to_be_bytes());
+        frame.extend_from_slice(&time(self.message_age));
+        frame.extend_from_slice(&time(self.max_age));
+        frame.extend_from_slice(&time(HELLO_TIME));
+        frame.extend_from_slice(&time(FORWARD_DELAY));
+        frame.resize(MIN_FRAME_LEN, 0);
+        frame
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PortRole {
+    /// The best path of the bridge to the root.
+    Root,
+    /// The path of its segment to the root.
+    Designated,
+    /// Another path to the root, through another bridge.
+    Alternate,
+    /// Another port of the bridge on the segment of a designated one.
+    Backup,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PortState {
+    /// Drops every frame but the BPDUs.
+    Discarding,
+    /// Learns the sources of the frames it drops.
+    Learning,
+    Forwarding,
+}
+
+impl fmt::Display for PortRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let role = match self {
+            PortRole::Root => "root",
+            PortRole::Designated => "designated",
+            PortRole::Alternate => "alternate",
+            PortRole::Backup => "backup",
+        };
+        f.write_str(role)
+    }
+}
+
+impl fmt::Display for PortState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self {
+            PortState::Discarding => "discarding",
+            PortState::Learning => "learning",
+            PortState::Forwarding => "forwarding",
+        };
+        f.write_str(state)
+    }
+}
+
+/// The best BPDU received on a port.
+#[derive(Clone, Copy, Debug)]
+struct Received {
+    vector: PriorityVector,
+    message_age: Duration,
+    expires: Instant,
+}
+
+struct StpPort {
+    id: u16,
+    /// The MAC the port is registered with, once found.
+    mac: Option<HwAddr>,
+    /// The id the runtime gave the port.
+    port_id: Arc<dyn Any + Send + Sync>,
+    received: Option<Received>,
+    role: PortRole,
+    state: PortState,
+    /// When the port moves on towards forwarding.
+    transition: Option<Instant>,
+    next_hello: Instant,
+}
+
+/// The spanning tree state of the bridge, shared by the ports.
+pub struct Stp {
+    bridge: BridgeId,
+    ports: BTreeMap<u64, StpPort>,
+    /// The best path of the bridge to the root, the bridge itself when it is
+    /// the root.
+    root: PriorityVector,
+    root_port: Option<u64>,
+    /// Message age of the BPDU the root information comes from.
+    root_age: Duration,
+    /// When the BPDUs stop carrying the topology change flag.
+    topology_change: Option<Instant>,
+}
+
+impl Stp {
+    pub fn new(bridge: BridgeId) -> Self {
+        Self {
+            bridge,
+            ports: BTreeMap::new(),
+            root: PriorityVector {
+                root: bridge,
+                cost: 0,
+                bridge,
+                port: 0,
+            },
+            root_port: None,
+            root_age: Duration::ZERO,
+            topology_change: None,
+        }
+    }
+
+    pub fn bridge(&self) -> BridgeId {
+        self.bridge
+    }
+
+    /// Add the port of `actor`, known to the runtime as `port_id`. It starts
+    /// discarding.
+    pub fn add_port<P: Any + Send + Sync>(&mut self, actor: u64, port_id: P, now: Instant) {
+        let port = StpPort {
+            // Port numbers have 12 bits.
+            id: (PORT_PRIORITY << 8) | (actor & 0x0fff) as u16,
+            mac: None,
+            port_id: Arc::new(port_id),
+            received: None,
+            role: PortRole::Designated,
+            state: PortState::Discarding,
+            transition: None,
+            next_hello: now,
+        };
+        self.ports.insert(actor, port);
+        self.update(now);
+    }
+
+    /// Remove the port of `actor`, returning whether the tree changed.
+    pub fn remove_port(&mut self, actor: u64, now: Instant) -> bool {
+        self.ports.remove(&actor);
+        self.update(now)
+    }
+
+    /// Record that the node of the port of `actor` sends with `mac`.
+    pub fn set_mac(&mut self, actor: u64, mac: HwAddr) {
+        if let Some(port) = self.ports.get_mut(&actor) {
+            port.mac = Some(mac);
+        }
+    }
+
+    /// Take a BPDU received on the port of `actor` into account, returning
+    /// whether the learned addresses should be flushed: the tree changed,
+    /// or the root port heard of a change elsewhere.
+    pub fn receive(&mut self, actor: u64, bpdu: &Bpdu, now: Instant) -> bool {
+        let Some(port) = self.ports.get_mut(&actor) else {
+            return false;
+        };
+        if bpdu.message_age >= bpdu.max_age {
+            return false;
+        }
+        // Keep the best information of the segment, or the newer one from
+        // the same sender.
+        let accept = port.received.map_or(true, |received| {
+            bpdu.vector <= received.vector
+                || (bpdu.vector.bridge, bpdu.vector.port)
+                    == (received.vector.bridge, received.vector.port)
+        });
+        if accept {
+            port.received = Some(Received {
+                vector: bpdu.vector,
+                message_age: bpdu.message_age,
+                expires: now + (bpdu.max_age - bpdu.message_age),
+            });
+        }
+        let changed = self.update(now);
+        // Changes are passed down the tree only, so that they do not loop.
+        if bpdu.topology_change && self.root_port == Some(actor) {
+            if self.topology_change.is_none() {
+                self.topology_change = Some(now + TOPOLOGY_CHANGE_TIME);
+                return true;
+            }
+            self.topology_change = Some(now + TOPOLOGY_CHANGE_TIME);
+        }
+        changed
+    }
+
+    /// Forget the information that expired, compute the roles of the ports
+    /// and move them towards forwarding. Returns whether the roles changed,
+    /// in which case the tree is logged.
+    pub fn update(&mut self, now: Instant) -> bool {
+        for port in self.ports.values_mut() {
+            if port
+                .received
+                .is_some_and(|received| received.expires <= now)
+            {
+                port.received = None;
+            }
+        }
+        if self.topology_change.is_some_and(|until| until <= now) {
+            self.topology_change = None;
+        }
+        let bridge = self.bridge;
+        // The best path to the root, ignoring the BPDUs of the bridge itself.
+        let best = self
+            .ports
+            .iter()
+            .filter_map(|(&actor, port)| {
+                let received = port.received.filter(|r| r.vector.bridge != bridge)?;
+                let vector = PriorityVector {
+                    cost: received.vector.cost.saturating_add(PORT_PATH_COST),
+                    ..received.vector
+                };
+                Some(((vector, port.id), actor, received.message_age))
+            })
+            .min_by_key(|(key, ..)| *key);
+        let own = PriorityVector {
+            root: bridge,
+            cost: 0,
+            bridge,
+            port: 0,
+        };
+        let (root, root_port, root_age) = match best {
+            Some(((vector, _), actor, age)) if vector < own => (vector, Some(actor), age),
+            _ => (own, None, Duration::ZERO),
+        };
+        let mut changed = root.root != self.root.root || root_port != self.root_port;
+        self.root = root;
+        self.root_port = root_port;
+        self.root_age = root_age;
+        for (&actor, port) in &mut self.ports {
+            let designated = PriorityVector {
+                root: root.root,
+                cost: root.cost,
+                bridge,
+                port: port.id,
+            };
+            let role = match port.received {
+                _ if root_port == Some(actor) => PortRole::Root,
+                Some(received) if received.vector < designated => {
+                    if received.vector.bridge == bridge {
+                        PortRole::Backup
+                    } else {
+                        PortRole::Alternate
+                    }
+                }
+                _ => PortRole::Designated,
+            };
+            if role != port.role {
+                changed = true;
+                port.role = role;
+            }
+            match role {
+                PortRole::Alternate | PortRole::Backup => {
+                    port.state = PortState::Discarding;
+                    port.transition = None;
+                }
+                PortRole::Root | PortRole::Designated => {
+                    if port.state != PortState::Forwarding && port.transition.is_none() {
+                        port.transition = Some(now + FORWARD_DELAY);
+                    }
+                    while let Some(at) = port.transition.filter(|&at| at <= now) {
+                        port.state = match port.state {
+                            PortState::Discarding => PortState::Learning,
+                            _ => PortState::Forwarding,
+                        };
+                        port.transition =
+                            (port.state != PortState::Forwarding).then(|| at + FORWARD_DELAY);
+                        if port.state == PortState::Forwarding {
+                            self.topology_change = Some(now + TOPOLOGY_CHANGE_TIME);
+                        }
+                    }
+                }
+            }
+        }
+        if changed {
+            // Tell the segments about the new tree right away.
+            for port in self.ports.values_mut() {
+                port.next_hello = now;
+            }
+            self.topology_change = Some(now + TOPOLOGY_CHANGE_TIME);
+            info!("spanning tree changed: {}", self.tree());
+        }
+        changed
+    }
+
+    pub fn state(&self, actor: u64) -> PortState {
+        self.ports
+            .get(&actor)
+            .map_or(PortState::Forwarding, |port| port.state)
+    }
+
+    /// The MACs of the ports that do not forward.
+    pub fn blocked_macs(&self) -> Vec<HwAddr> {
+        self.ports
+            .values()
+            .filter(|port| port.state != PortState::Forwarding)
+            .filter_map(|port| port.mac)
+            .collect()
+    }
+
+    /// The ids the runtime gave the ports that do not forward.
+    pub fn blocked_port_ids(&self) -> PortIds {
+        let mut ids = PortIds::default();
+        for port in self.ports.values() {
+            if port.state != PortState::Forwarding {
+                ids.push(port.port_id.clone());
+            }
+        }
+        ids
+    }
+
+    /// The BPDU the port of `actor` should send now, if it is designated and
+    /// its hello time elapsed.
+    pub fn hello(&mut self, actor: u64, now: Instant) -> Option<Bpdu> {
+        let port = self.ports.get_mut(&actor)?;
+        if port.role != PortRole::Designated || port.next_hello > now {
+            return None;
+        }
+        port.next_hello = now + HELLO_TIME;
+        let message_age = if self.root_port.is_some() {
+            self.root_age + Duration::from_secs(1)
+        } else {
+            Duration::ZERO
+        };
+        Some(Bpdu {
+            vector: PriorityVector {
+                root: self.root.root,
+                cost: self.root.cost,
+                bridge: self.bridge,
+                port: port.id,
+            },
+            topology_change: self.topology_change.is_some(),
+            message_age,
+            max_age: MAX_AGE,
+        })
+    }
+
+    /// When the port of `actor` has something to do next: send a BPDU, move
+    /// on towards forwarding or forget what it received.
+    pub fn next_deadline(&self, actor: u64) -> Option<Instant> {
+        let port = self.ports.get(&actor)?;
+        let hello = (port.role == PortRole::Designated).then_some(port.next_hello);
+        [
+            hello,
+            port.transition,
+            port.received.map(|received| received.expires),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    /// The tree as computed so far.
+    pub fn tree(&self) -> SpanningTree {
+        let ports = self
+            .ports
+            .iter()
+            .map(|(&actor, port)| {
+                let (designated_bridge, designated_port) = match (port.role, port.received) {
+                    (PortRole::Designated, _) | (_, None) => (self.bridge, port.id),
+                    (_, Some(received)) => (received.vector.bridge, received.vector.port),
+                };
+                TreePort {
+                    actor,
+                    id: port.id,
+                    mac: port.mac,
+                    role: port.role,
+                    state: port.state,
+                    designated_bridge,
+                    designated_port,
+                }
+            })
+            .collect();
+        SpanningTree {
+            bridge: self.bridge,
+            root: self.root.root,
+            root_cost: self.root.cost,
+            root_port: self.root_port,
+            ports,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SpanningTree {
+    pub bridge: BridgeId,
+    pub root: BridgeId,
+    pub root_cost: u32,
+    /// Actor of the root port, `None` on the root bridge.
+    pub root_port: Option<u64>,
+    pub ports: Vec<TreePort>,
+}
+
+#[derive(Clone, Debug)]
+pub struct TreePort {
+    pub actor: u64,
+    pub id: u16,
+    pub mac: Option<HwAddr>,
+    pub role: PortRole,
+    pub state: PortState,
+    /// The bridge and the port the segment of the port goes to the root
+    /// through.
+    pub designated_bridge: BridgeId,
+    pub designated_port: u16,
+}
+
+impl fmt::Display for SpanningTree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "bridge {} root {}", self.bridge, self.root)?;
+        if let Some(actor) = self.root_port {
+            write!(f, " cost {} through actor {actor}", self.root_cost)?;
+        }
+        for port in &self.ports {
+            write!(f, ", actor {} ", port.actor)?;
+            if let Some(mac) = port.mac {
+                write!(f, "({mac}) ")?;
+            }
+            write!(f, "{} {}", port.role, port.state)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,347 @@
+//! The test environment of the `host` array of the topology file, for the
+//! `testenv` command: for each host, a network namespace holding one end of
+//! a veth pair, with the MAC of its node, its addresses and the checksum
+//! offload off, the other end being the port of the node.
+//!
+//! Everything is done through rtnetlink. Bringing hosts up is idempotent: a
+//! host that is up is only configured again, and the leftovers of a host
+//! that is half there, e.g. after a crash, are deleted before it is
+//! created. When creating a host fails, every host created until then is
+//! deleted again.
+
+use std::{
+    fs::File,
+    io, mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, bail, Context};
+use futures::TryStreamExt;
+use hwaddr::HwAddr;
+use log::{error, info, warn};
+use netlink_packet_route::link::nlas::{Info, InfoKind, Nla};
+use nix::sched::{setns, CloneFlags};
+use rtnetlink::{Handle, NetworkNamespace};
+
+use crate::route::Prefix;
+
+/// A host behind the port of a node, as given by `Topology::hosts`.
+#[derive(Clone, Debug)]
+pub struct Host {
+    pub node: String,
+    /// The runtime of the node, in remote mode.
+    pub runtime: Option<String>,
+    /// The end of the veth pair the runtime reads, in the namespace of the
+    /// runtime.
+    pub port: String,
+    pub netns: String,
+    /// The end of the veth pair in `netns`.
+    pub if_name: String,
+    pub mac: HwAddr,
+    pub addresses: Vec<Prefix>,
+}
+
+/// What is there of a host.
+#[derive(Debug, PartialEq, Eq)]
+pub enum HostState {
+    Absent,
+    Up,
+    /// Some of the namespace and interfaces, named in the list.
+    Partial(Vec<String>),
+}
+
+/// Something created by `up`, to delete again if it fails.
+enum Created {
+    Netns(String),
+    Link(String),
+}
+
+fn netns_path(netns: &str) -> PathBuf {
+    PathBuf::from(rtnetlink::NETNS_PATH).join(netns)
+}
+
+/// Run `f` in the network namespace `netns`. The sockets it opens stay in
+/// the namespace.
+fn in_netns<T>(netns: &str, f: impl FnOnce() -> anyhow::Result<T>) -> anyhow::Result<T> {
+    // Only the current thread changes namespace, and it goes back before
+    // anything else may run on it.
+    let own = File::open("/proc/thread-self/ns/net")?;
+    let target =
+        File::open(netns_path(netns)).with_context(|| format!("failed to open netns {netns}"))?;
+    setns(target.as_raw_fd(), CloneFlags::CLONE_NEWNET)
+        .with_context(|| format!("failed to enter netns {netns}"))?;
+    let result = f();
+    setns(own.as_raw_fd(), CloneFlags::CLONE_NEWNET).expect("failed to return to the own netns");
+    result
+}
+
+fn connect() -> anyhow::Result<Handle> {
+    let (connection, handle, _) = rtnetlink::new_connection()?;
+    tokio::spawn(connection);
+    Ok(handle)
+}
+
+fn netns_handle(netns: &str) -> anyhow::Result<Handle> {
+    in_netns(netns, connect)
+}
+
+fn error_code(e: &rtnetlink::Error) -> Option<i32> {
+    match e {
+        rtnetlink::Error::NetlinkError(message) => message.code.map(|code| -code.get()),
+        _ => None,
+    }
+}
+
+/// The index of the veth `name`, if there is one. Any other interface of
+/// that name is an error, so that it is never taken for a leftover.
+async fn link_index(handle: &Handle, name: &str) -> anyhow::Result<Option<u32>> {
+    let mut links = handle.link().get().match_name(name.to_string()).execute();
+    let link = match links.try_next().await {
+        Ok(Some(link)) => link,
+        Ok(None) => return Ok(None),
+        Err(e) if error_code(&e) == Some(libc::ENODEV) => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("failed to get {name}")),
+    };
+    let is_veth = link.nlas.iter().any(|nla| match nla {
+        Nla::Info(info) => info.contains(&Info::Kind(InfoKind::Veth)),
+        _ => false,
+    });
+    if !is_veth {
+        bail!("{name} exists and is not a veth");
+    }
+    Ok(Some(link.header.index))
+}
+
+async fn delete_link(handle: &Handle, name: &str) -> anyhow::Result<()> {
+    if let Some(index) = link_index(handle, name).await? {
+        handle
+            .link()
+            .del(index)
+            .execute()
+            .await
+            .with_context(|| format!("failed to delete {name}"))?;
+        info!("deleted {name}");
+    }
+    Ok(())
+}
+
+async fn delete_netns(netns: &str) -> anyhow::Result<()> {
+    if netns_path(netns).exists() {
+        NetworkNamespace::del(netns.to_string())
+            .await
+            .with_context(|| format!("failed to delete netns {netns}"))?;
+        info!("deleted netns {netns}");
+    }
+    Ok(())
+}
+
+/// Turn off the checksum offload of `if_name` in both directions, like
+/// `ethtool --offload IF rx off tx off`, so that the frames the runtime
+/// reads carry their checksums.
+fn disable_checksum_offload(if_name: &str) -> anyhow::Result<()> {
+    const ETHTOOL_SRXCSUM: u32 = 0x15;
+    const ETHTOOL_STXCSUM: u32 = 0x17;
+
+    #[repr(C)]
+    struct EthtoolValue {
+        cmd: u32,
+        data: u32,
+    }
+
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    let mut request: libc::ifreq = unsafe { mem::zeroed() };
+    if if_name.len() >= request.ifr_name.len() {
+        bail!("interface name {if_name} too long");
+    }
+    for (i, byte) in if_name.bytes().enumerate() {
+        request.ifr_name[i] = byte as libc::c_char;
+    }
+    for cmd in [ETHTOOL_SRXCSUM, ETHTOOL_STXCSUM] {
+        let mut value = EthtoolValue { cmd, data: 0 };
+        request.ifr_ifru.ifru_data = &mut value as *mut EthtoolValue as *mut libc::c_char;
+        if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCETHTOOL, &mut request) } < 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("failed to turn off the offload of {if_name}"));
+        }
+    }
+    Ok(())
+}
+
+/// What is there of `host`.
+pub async fn state(root: &Handle, host: &Host) -> anyhow::Result<HostState> {
+    let mut present = Vec::new();
+    let netns = netns_path(&host.netns).exists();
+    if netns {
+        present.push(format!("netns {}", host.netns));
+    }
+    let port = link_index(root, &host.port).await?.is_some();
+    if port {
+        present.push(host.port.clone());
+    }
+    // The end of the host before it moved to the namespace.
+    if link_index(root, &host.if_name).await?.is_some() {
+        present.push(host.if_name.clone());
+    }
+    let mut inside = false;
+    if netns {
+        let handle = netns_handle(&host.netns)?;
+        inside = link_index(&handle, &host.if_name).await?.is_some();
+        if inside {
+            present.push(format!("{} in {}", host.if_name, host.netns));
+        }
+    }
+    Ok(match present.len() {
+        0 => HostState::Absent,
+        3 if netns && port && inside => HostState::Up,
+        _ => HostState::Partial(present),
+    })
+}
+
+/// Give `host` its MAC and addresses, turn its offload off and bring both
+/// ends up. Nothing changes for a host that already is so.
+async fn configure(root: &Handle, host: &Host) -> anyhow::Result<()> {
+    let handle = netns_handle(&host.netns)?;
+    let index = link_index(&handle, &host.if_name)
+        .await?
+        .ok_or_else(|| anyhow!("no {} in netns {}", host.if_name, host.netns))?;
+    handle
+        .link()
+        .set(index)
+        .address(host.mac.octets().to_vec())
+        .up()
+        .execute()
+        .await
+        .with_context(|| format!("failed to set up {}", host.if_name))?;
+    for address in &host.addresses {
+        match handle
+            .address()
+            .add(index, address.addr, address.len)
+            .execute()
+            .await
+        {
+            Err(e) if error_code(&e) != Some(libc::EEXIST) => {
+                return Err(e).with_context(|| format!("failed to add {address}"));
+            }
+            _ => {}
+        }
+    }
+    in_netns(&host.netns, || disable_checksum_offload(&host.if_name))?;
+    let port = link_index(root, &host.port)
+        .await?
+        .ok_or_else(|| anyhow!("no port {}", host.port))?;
+    root.link()
+        .set(port)
+        .up()
+        .execute()
+        .await
+        .with_context(|| format!("failed to set up {}", host.port))?;
+    Ok(())
+}
+
+/// Create `host` from nothing, recording what was created in `created`.
+async fn create(root: &Handle, host: &Host, created: &mut Vec<Created>) -> anyhow::Result<()> {
+    NetworkNamespace::add(host.netns.clone())
+        .await
+        .with_context(|| format!("failed to add netns {}", host.netns))?;
+    created.push(Created::Netns(host.netns.clone()));
+    root.link()
+        .add()
+        .veth(host.port.clone(), host.if_name.clone())
+        .execute()
+        .await
+        .with_context(|| format!("failed to add veth {} - {}", host.port, host.if_name))?;
+    // Deleting either end deletes both, wherever the other is.
+    created.push(Created::Link(host.port.clone()));
+    let index = link_index(root, &host.if_name)
+        .await?
+        .ok_or_else(|| anyhow!("no {} after adding it", host.if_name))?;
+    let netns = File::open(netns_path(&host.netns))?;
+    root.link()
+        .set(index)
+        .setns_by_fd(netns.as_raw_fd())
+        .execute()
+        .await
+        .with_context(|| format!("failed to move {} to {}", host.if_name, host.netns))?;
+    configure(root, host).await
+}
+
+/// Delete whatever there is of `host`.
+async fn remove(root: &Handle, host: &Host) -> anyhow::Result<()> {
+    delete_link(root, &host.port).await?;
+    delete_link(root, &host.if_name).await?;
+    delete_netns(&host.netns).await
+}
+
+async fn undo(root: &Handle, created: Vec<Created>) {
+    for created in created.into_iter().rev() {
+        let result = match &created {
+            Created::Link(name) => delete_link(root, name).await,
+            Created::Netns(netns) => delete_netns(netns).await,
+        };
+        if let Err(e) = result {
+            error!("failed to roll back: {e:#}");
+        }
+    }
+}
+
+/// Bring every host up, deleting what was created if any fails.
+pub async fn up(hosts: &[Host]) -> anyhow::Result<()> {
+    let root = connect()?;
+    let mut created = Vec::new();
+    let result = async {
+        for host in hosts {
+            match state(&root, host).await? {
+                HostState::Up => configure(&root, host).await?,
+                HostState::Partial(leftovers) => {
+                    warn!(
+                        "deleting the leftovers of the host of {}: {}",
+                        host.node,
+                        leftovers.join(", ")
+                    );
+                    remove(&root, host).await?;
+                    create(&root, host, &mut created).await?;
+                }
+                HostState::Absent => create(&root, host, &mut created).await?,
+            }
+            info!("host of {} up in netns {}", host.node, host.netns);
+        }
+        Ok(())
+    }
+    .await;
+    if result.is_err() {
+        warn!("rolling back");
+        undo(&root, created).await;
+    }
+    result
+}
+
+/// Delete every host, going on with the others when one fails.
+pub async fn down(hosts: &[Host]) -> anyhow::Result<()> {
+    let root = connect()?;
+    let mut failed = Vec::new();
+    for host in hosts {
+        if let Err(e) = remove(&root, host).await {
+            error!("failed to delete the host of {}: {e:#}", host.node);
+            failed.push(host.node.as_str());
+        }
+    }
+    if !failed.is_empty() {
+        bail!("failed to delete the hosts of {}", failed.join(", "));
+    }
+    Ok(())
+}
+
+/// What is there of every host.
+pub async fn status(hosts: &[Host]) -> anyhow::Result<Vec<HostState>> {
+    let root = connect()?;
+    let mut states = Vec::new();
+    for host in hosts {
+        states.push(state(&root, host).await?);
+    }
+    Ok(states)
+}
@@ -0,0 +1,982 @@
+//! The topology file of local mode.
+//!
+//! Every table is a node, except for the `link` array which describes the
+//! link between two of the nodes, the `event` array of the scenario, the
+//! `static_mac` and `port` arrays of the bridge, the `interface` and `route`
+//! arrays of the router, the `runtime` table of remote mode and the `host`
+//! array of `testenv`. The runtime reads the file given with `-t` as node
+//! tables only, so it is given the file of [`Topology::nodes_toml`] instead:
+//!
+//! ```toml
+//! [node1]
+//! port_type="xdp"
+//! if_name="veth1"
+//! queue_id=0
+//! mac_addr="aa:00:00:00:00:00"
+//!
+//! [[link]]
+//! endpoints=["node1", "node2"]
+//! bandwidth="100mbit"
+//! burst="32kb"
+//! delay="10ms"
+//! jitter="1ms"
+//! loss="0.1%"
+//! reorder="1%"
+//! qdisc="fq_codel"
+//! queue_size=1000
+//!
+//! [[static_mac]]
+//! mac="bb:00:00:00:00:01"
+//! node="node1"
+//!
+//! [[port]]
+//! node="node1"
+//! mode="access"
+//! vlan=10
+//!
+//! [[port]]
+//! node="node2"
+//! mode="trunk"
+//! vlans=[10, 20]
+//! native_vlan=1
+//!
+//! [[link]]
+//! endpoints=["node1", "node3"]
+//! uplink_trace="traces/lte.up"
+//! downlink_trace="traces/lte.down"
+//!
+//! [[interface]]
+//! node="node1"
+//! addresses=["10.0.1.254/24", "fd00:1::1/64"]
+//!
+//! [[interface]]
+//! node="node3"
+//! mac="02:00:00:00:fe:03"
+//! addresses=["10.0.3.254/24"]
+//! mtu=1400
+//!
+//! [[route]]
+//! prefix="10.0.4.0/24"
+//! via="10.0.3.1"
+//!
+//! [[host]]
+//! node="node1"
+//! netns="vnet0"
+//! if_name="veth0"
+//! addresses=["10.0.0.1/24"]
+//! ```
+//!
+//! In remote mode, the nodes are spread over runtimes. Each node names the
+//! runtime that runs its actor, and the `runtime` table gives their
+//! addresses, for `deploy`:
+//!
+//! ```toml
+//! [runtime.host1]
+//! address="10.0.0.44"
+//!
+//! [runtime.host2]
+//! address="10.0.0.45:10000"
+//! control="10.0.0.45:10001"
+//!
+//! [node1]
+//! runtime="host1"
+//! port_type="xdp"
+//! if_name="veth1"
+//! queue_id=0
+//! mac_addr="aa:00:00:00:00:00"
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context};
+use hwaddr::HwAddr;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ip::{MIN_IPV4_MTU, MIN_IPV6_MTU},
+    link::LinkParams,
+    loss::LossModel,
+    netem::{parse_duration, parse_percent, parse_rate},
+    qdisc::Size,
+    route::{Prefix, Route, RouteTable},
+    router::{Interface, RouterConfig},
+    shaper::{parse_size, ShaperConfig},
+    testenv::Host,
+    trace::Trace,
+    vlan::{PortMode, Vid, VlanConfig, MAX_VID},
+};
+
+/// Size of the name of an interface, with its terminating nul.
+const IFNAMSIZ: usize = 16;
+
+#[derive(Debug, Deserialize)]
+pub struct Topology {
+    #[serde(default, rename = "link")]
+    pub links: Vec<LinkConfig>,
+    /// Changes of the links during the run, see `scenario`.
+    #[serde(default, rename = "event")]
+    pub events: Vec<EventConfig>,
+    /// Addresses known to be behind a node, which the bridge never ages out.
+    #[serde(default, rename = "static_mac")]
+    pub static_macs: Vec<StaticMacConfig>,
+    /// VLAN modes of the ports of the nodes.
+    #[serde(default, rename = "port")]
+    pub ports: Vec<PortConfig>,
+    /// Interfaces of the router on the ports of the nodes. The nodes are
+    /// routed instead of bridged when there is any.
+    #[serde(default, rename = "interface")]
+    pub interfaces: Vec<InterfaceConfig>,
+    /// Static routes of the router.
+    #[serde(default, rename = "route")]
+    pub routes: Vec<RouteConfig>,
+    /// Runtimes of remote mode, by name.
+    #[serde(default, rename = "runtime")]
+    pub runtimes: BTreeMap<String, RuntimeConfig>,
+    /// Hosts behind the ports of the nodes, which `testenv` creates.
+    #[serde(default, rename = "host")]
+    pub hosts: Vec<HostConfig>,
+    #[serde(flatten)]
+    pub nodes: BTreeMap<String, NodeConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NodeConfig {
+    pub port_type: String,
+    pub if_name: String,
+    pub queue_id: u32,
+    pub mac_addr: String,
+    /// The runtime running the actor of the node, in remote mode.
+    pub runtime: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuntimeConfig {
+    /// The `ActorService` of the runtime, as `host` or `host:port`.
+    pub address: String,
+    /// Its `ControlService`, on the same host by default.
+    pub control: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StaticMacConfig {
+    pub mac: String,
+    /// The node the address is behind.
+    pub node: String,
+}
+
+/// The VLAN mode of the port of a node: an access port of `vlan`, or a
+/// trunk port of `vlans` whose untagged frames are in `native_vlan`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PortConfig {
+    pub node: String,
+    pub mode: PortModeConfig,
+    pub vlan: Option<Vid>,
+    #[serde(default)]
+    pub vlans: Vec<Vid>,
+    pub native_vlan: Option<Vid>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PortModeConfig {
+    Access,
+    Trunk,
+}
+
+impl PortConfig {
+    pub fn mode(&self) -> anyhow::Result<PortMode> {
+        let check = |vid: Vid| {
+            if !(1..=MAX_VID).contains(&vid) {
+                bail!("invalid vlan {vid}, must be between 1 and {MAX_VID}");
+            }
+            Ok(vid)
+        };
+        match self.mode {
+            PortModeConfig::Access => {
+                if !self.vlans.is_empty() || self.native_vlan.is_some() {
+                    bail!("vlans and native_vlan are for trunk ports");
+                }
+                let vlan = self.vlan.context("an access port needs a vlan")?;
+                Ok(PortMode::Access(check(vlan)?))
+            }
+            PortModeConfig::Trunk => {
+                if self.vlan.is_some() {
+                    bail!("vlan is for access ports, use vlans or native_vlan");
+                }
+                let allowed = self
+                    .vlans
+                    .iter()
+                    .map(|&vid| check(vid))
+                    .collect::<anyhow::Result<_>>()?;
+                let native = self.native_vlan.map(check).transpose()?;
+                Ok(PortMode::Trunk { allowed, native })
+            }
+        }
+    }
+}
+
+/// An interface of the router on the port of `node`, with its addresses and
+/// the length of their subnet, such as `10.0.1.254/24`. Its MAC is
+/// `02:00:00:00:fe:NN` by default, `NN` being its rank in the file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InterfaceConfig {
+    pub node: String,
+    pub mac: Option<String>,
+    pub addresses: Vec<String>,
+    /// Largest IP packet the router sends out of the interface, unlimited by
+    /// default.
+    pub mtu: Option<usize>,
+}
+
+/// A host behind the port of `node`: a network namespace holding the other
+/// end of the veth pair of the node, `if_name`, which has the MAC of the
+/// node.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostConfig {
+    pub node: String,
+    pub netns: String,
+    pub if_name: String,
+    #[serde(default)]
+    pub addresses: Vec<String>,
+}
+
+/// A static route to `prefix`, through the gateway `via` or straight out
+/// of the interface on the port of `node`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    pub prefix: String,
+    pub via: Option<String>,
+    pub node: Option<String>,
+}
+
+/// Parameters of a link, applied to both of its directions. Times, rates and
+/// probabilities are written like in `tc netem`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LinkConfig {
+    pub endpoints: [String; 2],
+    /// Rate of the token bucket shaping the egress of the link.
+    pub bandwidth: Option<String>,
+    /// Size of the token bucket, about 1ms worth of `bandwidth` by default.
+    pub burst: Option<String>,
+    /// Bytes accounted per frame on top of its length, 24 by default for the
+    /// Ethernet preamble, FCS and inter-frame gap.
+    pub overhead: Option<usize>,
+    pub delay: Option<String>,
+    pub jitter: Option<String>,
+    pub distribution: Option<String>,
+    /// Random loss like `1%`, or any loss model of `tc netem loss` such as
+    /// `gemodel 1% 30%`.
+    pub loss: Option<String>,
+    /// A loss model given by a Markov chain of any number of states.
+    pub markov_loss: Option<MarkovLossConfig>,
+    pub duplicate: Option<String>,
+    pub corrupt: Option<String>,
+    pub reorder: Option<String>,
+    /// Queueing discipline of the frames waiting for the shaper, written like
+    /// the options of `tc qdisc`, e.g. `fq_codel target 5ms`. A tail-drop
+    /// FIFO by default.
+    pub qdisc: Option<String>,
+    /// Maximum number of frames waiting for the shaper, or bytes when it has
+    /// a unit such as `kb`. Without `bandwidth`, the number of frames held in
+    /// the netem stage.
+    pub queue_size: Option<Size>,
+    /// Mahimahi trace of the delivery opportunities from the first endpoint
+    /// to the second, replacing `bandwidth`. The path is relative to the
+    /// working directory.
+    pub uplink_trace: Option<String>,
+    /// Mahimahi trace of the delivery opportunities from the second endpoint
+    /// to the first.
+    pub downlink_trace: Option<String>,
+    /// Whether the link drops every frame.
+    pub down: Option<bool>,
+    /// Largest payload of the frames the link carries, like the MTU of an
+    /// interface. Larger frames are dropped.
+    pub mtu: Option<usize>,
+    /// Addresses to answer the IPv4 packets with DF set and the IPv6
+    /// packets dropped for `mtu` from, with ICMP fragmentation needed or
+    /// ICMPv6 packet too big, as a router at the ingress of the link would.
+    pub icmp_from: Option<Vec<String>>,
+}
+
+/// `transitions[i][j]` is the probability of going from state `i` to state
+/// `j` before each frame and `loss[j]` the probability of losing the frame in
+/// state `j`. The chain starts in state 0.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MarkovLossConfig {
+    pub transitions: Vec<Vec<f64>>,
+    pub loss: Vec<f64>,
+}
+
+impl LinkConfig {
+    /// Override the parameters that `changes` sets.
+    pub fn update(&mut self, changes: &LinkConfig) {
+        macro_rules! update {
+            ($($field:ident),*) => {
+                $(
+                    if changes.$field.is_some() {
+                        self.$field = changes.$field.clone();
+                    }
+                )*
+            };
+        }
+        update!(
+            bandwidth,
+            burst,
+            overhead,
+            delay,
+            jitter,
+            distribution,
+            loss,
+            markov_loss,
+            duplicate,
+            corrupt,
+            reorder,
+            qdisc,
+            queue_size,
+            uplink_trace,
+            downlink_trace,
+            down,
+            mtu,
+            icmp_from
+        );
+        // The two ways of describing loss replace each other.
+        if changes.loss.is_some() {
+            self.markov_loss = None;
+        } else if changes.markov_loss.is_some() {
+            self.loss = None;
+        }
+        // And so do the two ways of shaping.
+        if changes.bandwidth.is_some() {
+            self.uplink_trace = None;
+            self.downlink_trace = None;
+        } else if changes.uplink_trace.is_some() || changes.downlink_trace.is_some() {
+            self.bandwidth = None;
+        }
+    }
+
+    /// Parameters of the direction from the first endpoint to the second and
+    /// of the way back.
+    pub fn directions(&self) -> anyhow::Result<[LinkParams; 2]> {
+        let has_trace = self.uplink_trace.is_some() || self.downlink_trace.is_some();
+        if has_trace && self.bandwidth.is_some() {
+            bail!("bandwidth and traces are exclusive");
+        }
+        Ok([
+            self.params(self.uplink_trace.as_deref())?,
+            self.params(self.downlink_trace.as_deref())?,
+        ])
+    }
+
+    fn params(&self, trace: Option<&str>) -> anyhow::Result<LinkParams> {
+        let mut params = LinkParams {
+            down: self.down.unwrap_or(false),
+            mtu: self.mtu,
+            ..Default::default()
+        };
+        if let Some(mtu) = self.mtu {
+            if mtu < MIN_IPV4_MTU {
+                bail!("mtu below {MIN_IPV4_MTU}");
+            }
+        }
+        if let Some(addresses) = &self.icmp_from {
+            if self.mtu.is_none() {
+                bail!("icmp_from needs an mtu");
+            }
+            params.icmp_from = addresses
+                .iter()
+                .map(|address| {
+                    address
+                        .parse()
+                        .map_err(|_| anyhow!("invalid icmp_from address {address}"))
+                })
+                .collect::<anyhow::Result<_>>()?;
+        }
+        let config = &mut params.netem;
+        if let Some(delay) = &self.delay {
+            config.latency = parse_duration(delay)?;
+        }
+        if let Some(jitter) = &self.jitter {
+            config.jitter = parse_duration(jitter)?;
+        }
+        if let Some(distribution) = &self.distribution {
+            config.distribution = distribution.parse()?;
+        }
+        if let Some(loss) = &self.loss {
+            config.loss = loss.parse()?;
+        }
+        if let Some(markov) = &self.markov_loss {
+            if self.loss.is_some() {
+                bail!("loss and markov_loss are exclusive");
+            }
+            config.loss = LossModel::Markov {
+                transitions: markov.transitions.clone(),
+                loss: markov.loss.clone(),
+            };
+            config.loss.validate()?;
+        }
+        if let Some(duplicate) = &self.duplicate {
+            config.duplicate = parse_percent(duplicate)?;
+        }
+        if let Some(corrupt) = &self.corrupt {
+            config.corrupt = parse_percent(corrupt)?;
+        }
+        if let Some(reorder) = &self.reorder {
+            config.reorder = parse_percent(reorder)?;
+            config.gap = 1;
+        }
+        let shaper = match (&self.bandwidth, trace) {
+            (Some(bandwidth), _) => {
+                let mut shaper = ShaperConfig::new(parse_rate(bandwidth)?);
+                if let Some(burst) = &self.burst {
+                    shaper.burst = parse_size(burst)?;
+                }
+                Some(shaper)
+            }
+            (None, Some(trace)) => Some(ShaperConfig::trace(Arc::new(Trace::load(trace)?))),
+            (None, None) => None,
+        };
+        if let Some(mut shaper) = shaper {
+            if let Some(overhead) = self.overhead {
+                shaper.overhead = overhead;
+            }
+            if let Some(qdisc) = &self.qdisc {
+                shaper.qdisc = qdisc.parse()?;
+            }
+            if let Some(queue_size) = self.queue_size {
+                shaper.qdisc.set_limit(queue_size);
+            }
+            params.shaper = Some(shaper);
+        } else if let Some(queue_size) = self.queue_size {
+            match queue_size {
+                Size::Packets(limit) => config.limit = limit,
+                Size::Bytes(_) => bail!("queue_size in bytes needs a bandwidth"),
+            }
+        } else if self.qdisc.is_some()
+            && self.uplink_trace.is_none()
+            && self.downlink_trace.is_none()
+        {
+            bail!("qdisc needs a bandwidth or a trace");
+        }
+        Ok(params)
+    }
+}
+
+impl Topology {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("failed to parse {}", path.display()))
+    }
+
+    /// The node tables of the topology, as the runtime reads them.
+    pub fn nodes_toml(&self) -> anyhow::Result<String> {
+        Ok(toml::to_string(&self.nodes)?)
+    }
+
+    pub fn node_mac(&self, name: &str) -> anyhow::Result<HwAddr> {
+        let node = self
+            .nodes
+            .get(name)
+            .ok_or_else(|| anyhow!("unknown node {name}"))?;
+        node.mac_addr
+            .parse()
+            .map_err(|_| anyhow!("invalid mac_addr {} of {name}", node.mac_addr))
+    }
+
+    pub fn node_macs(&self) -> anyhow::Result<Vec<HwAddr>> {
+        self.nodes.keys().map(|name| self.node_mac(name)).collect()
+    }
+
+    /// The names of the nodes of every runtime.
+    pub fn runtime_nodes(&self) -> anyhow::Result<BTreeMap<&str, Vec<&str>>> {
+        if self.runtimes.is_empty() {
+            bail!("no runtime");
+        }
+        let mut nodes: BTreeMap<&str, Vec<&str>> = self
+            .runtimes
+            .keys()
+            .map(|name| (name.as_str(), Vec::new()))
+            .collect();
+        for (name, node) in &self.nodes {
+            let runtime = node
+                .runtime
+                .as_deref()
+                .ok_or_else(|| anyhow!("node {name} has no runtime"))?;
+            nodes
+                .get_mut(runtime)
+                .ok_or_else(|| anyhow!("unknown runtime {runtime} of {name}"))?
+                .push(name);
+        }
+        Ok(nodes)
+    }
+
+    /// The hosts of the nodes of `runtime`, or of every node without.
+    pub fn hosts(&self, runtime: Option<&str>) -> anyhow::Result<Vec<Host>> {
+        let mut hosts: Vec<Host> = Vec::new();
+        for config in &self.hosts {
+            let node = self
+                .nodes
+                .get(&config.node)
+                .ok_or_else(|| anyhow!("unknown node {}", config.node))?;
+            if runtime.is_some_and(|runtime| node.runtime.as_deref() != Some(runtime)) {
+                continue;
+            }
+            for name in [&node.if_name, &config.if_name] {
+                if name.is_empty() || name.len() >= IFNAMSIZ {
+                    bail!("invalid interface name {name} of {}", config.node);
+                }
+            }
+            if config.if_name == node.if_name {
+                bail!(
+                    "host of {} named like its port {}",
+                    config.node,
+                    node.if_name
+                );
+            }
+            if hosts.iter().any(|host| host.node == config.node) {
+                bail!("duplicate host of {}", config.node);
+            }
+            // Hosts of different runtimes are on different machines. On the
+            // same one, leftovers are cleaned up by deleting the namespace
+            // and interfaces of a host, which must not be another's.
+            for other in hosts.iter().filter(|host| host.runtime == node.runtime) {
+                let names = [&other.netns, &other.port, &other.if_name];
+                if other.netns == config.netns
+                    || names.contains(&&node.if_name)
+                    || names.contains(&&config.if_name)
+                {
+                    bail!(
+                        "hosts of {} and {} share a netns or interface",
+                        other.node,
+                        config.node
+                    );
+                }
+            }
+            let addresses = config
+                .addresses
+                .iter()
+                .map(|address| address.parse())
+                .collect::<anyhow::Result<Vec<Prefix>>>()
+                .with_context(|| format!("invalid host of {}", config.node))?;
+            hosts.push(Host {
+                node: config.node.clone(),
+                runtime: node.runtime.clone(),
+                port: node.if_name.clone(),
+                netns: config.netns.clone(),
+                if_name: config.if_name.clone(),
+                mac: self.node_mac(&config.node)?,
+                addresses,
+            });
+        }
+        Ok(hosts)
+    }
+
+    /// The port of every static address, as the MAC of its node.
+    pub fn static_macs(&self) -> anyhow::Result<HashMap<HwAddr, HwAddr>> {
+        let mut macs = HashMap::new();
+        for entry in &self.static_macs {
+            let mac = entry
+                .mac
+                .parse()
+                .map_err(|_| anyhow!("invalid static mac {}", entry.mac))?;
+            if macs.insert(mac, self.node_mac(&entry.node)?).is_some() {
+                bail!("duplicate static mac {}", entry.mac);
+            }
+        }
+        Ok(macs)
+    }
+
+    /// The VLAN mode of the ports, keyed by the MAC of their node.
+    pub fn vlan_config(&self) -> anyhow::Result<VlanConfig> {
+        let mut ports = HashMap::new();
+        for port in &self.ports {
+            let mode = port
+                .mode()
+                .with_context(|| format!("invalid port of {}", port.node))?;
+            if ports.insert(self.node_mac(&port.node)?, mode).is_some() {
+                bail!("duplicate port of {}", port.node);
+            }
+        }
+        Ok(VlanConfig { ports })
+    }
+
+    /// The interfaces of the router and its routing table: a route to the
+    /// subnet of every address of the interfaces, and the static routes.
+    pub fn router_config(&self) -> anyhow::Result<RouterConfig> {
+        let mut interfaces: Vec<Interface> = Vec::new();
+        let mut routes = RouteTable::default();
+        for (i, config) in self.interfaces.iter().enumerate() {
+            let port = self.node_mac(&config.node)?;
+            if interfaces.iter().any(|interface| interface.port == port) {
+                bail!("duplicate interface of {}", config.node);
+            }
+            let mac = match &config.mac {
+                Some(mac) => mac
+                    .parse()
+                    .map_err(|_| anyhow!("invalid interface mac {mac}"))?,
+                None => HwAddr::from([0x02, 0, 0, 0, 0xfe, i as u8 + 1]),
+            };
+            let addresses = config
+                .addresses
+                .iter()
+                .map(|address| address.parse())
+                .collect::<anyhow::Result<Vec<Prefix>>>()
+                .with_context(|| format!("invalid interface of {}", config.node))?;
+            if let Some(mtu) = config.mtu {
+                let ipv6 = addresses.iter().any(|address| address.addr.is_ipv6());
+                let min = if ipv6 { MIN_IPV6_MTU } else { MIN_IPV4_MTU };
+                if mtu < min {
+                    bail!("mtu of the interface of {} below {min}", config.node);
+                }
+            }
+            for address in &addresses {
+                routes.insert(Route {
+                    prefix: address.network(),
+                    via: None,
+                    port,
+                });
+            }
+            interfaces.push(Interface {
+                port,
+                mac,
+                addresses,
+                mtu: config.mtu,
+            });
+        }
+        for config in &self.routes {
+            let prefix: Prefix = config.prefix.parse()?;
+            let route = match (&config.via, &config.node) {
+                (Some(via), None) => {
+                    let via: IpAddr = via.parse().map_err(|_| anyhow!("invalid gateway {via}"))?;
+                    let interface = interfaces
+                        .iter()
+                        .find(|interface| interface.on_link(via))
+                        .ok_or_else(|| {
+                            anyhow!("gateway {via} is not on the link of an interface")
+                        })?;
+                    Route {
+                        prefix: prefix.network(),
+                        via: Some(via),
+                        port: interface.port,
+                    }
+                }
+                (None, Some(node)) => {
+                    let port = self.node_mac(node)?;
+                    if !interfaces.iter().any(|interface| interface.port == port) {
+                        bail!("no interface on {node} for the route to {prefix}");
+                    }
+                    Route {
+                        prefix: prefix.network(),
+                        via: None,
+                        port,
+                    }
+                }
+                _ => bail!("the route to {prefix} needs either via or node"),
+            };
+            routes.insert(route);
+        }
+        Ok(RouterConfig { interfaces, routes })
+    }
+
+    /// Parameters of both directions of every link, keyed by the MAC of the
+    /// source and destination node.
+    pub fn link_params(&self) -> anyhow::Result<HashMap<(HwAddr, HwAddr), LinkParams>> {
+        let mut links = HashMap::new();
+        for link in &self.links {
+            let [a, b] = &link.endpoints;
+            if a == b {
+                bail!("link from {a} to itself");
+            }
+            let [forward, backward] = link
+                .directions()
+                .with_context(|| format!("invalid link {a} - {b}"))?;
+            let (a, b) = (self.node_mac(a)?, self.node_mac(b)?);
+            if links.insert((a, b), forward).is_some() {
+                bail!(
+                    "duplicate link {} - {}",
+                    link.endpoints[0],
+                    link.endpoints[1]
+                );
+            }
+            links.insert((b, a), backward);
+        }
+        Ok(links)
+    }
+
+    /// The changes of both directions of the links made by `events`, in the
+    /// order they happen. Each event updates the link as it was after the
+    /// previous ones.
+    pub fn link_changes(&self, events: &[EventConfig]) -> anyhow::Result<Vec<LinkChange>> {
+        let mut events = events
+            .iter()
+            .map(|event| Ok((parse_duration(&event.at)?, event)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        events.sort_by_key(|(at, _)| *at);
+        let mut links = self.links.clone();
+        let mut changes = Vec::new();
+        for (at, event) in events {
+            let [a, b] = &event.link.endpoints;
+            let link = links
+                .iter_mut()
+                .find(|link| {
+                    let [x, y] = &link.endpoints;
+                    (x, y) == (a, b) || (x, y) == (b, a)
+                })
+                .ok_or_else(|| anyhow!("event at {} on unknown link {a} - {b}", event.at))?;
+            let mut update = event.link.clone();
+            if link.endpoints[0] != *a {
+                // Uplink and downlink follow the endpoints of the link.
+                std::mem::swap(&mut update.uplink_trace, &mut update.downlink_trace);
+            }
+            link.update(&update);
+            let [forward, backward] = link
+                .directions()
+                .with_context(|| format!("invalid event at {} on {a} - {b}", event.at))?;
+            let [x, y] = &link.endpoints;
+            let (x, y) = (self.node_mac(x)?, self.node_mac(y)?);
+            changes.push(LinkChange {
+                at,
+                source: x,
+                destination: y,
+                params: forward,
+            });
+            changes.push(LinkChange {
+                at,
+                source: y,
+                destination: x,
+                params: backward,
+            });
+        }
+        Ok(changes)
+    }
+}
+
+/// New parameters of the direction from `source` to `destination`, `at` a
+/// time since the start of the run.
+#[derive(Clone, Debug)]
+pub struct LinkChange {
+    pub at: Duration,
+    pub source: HwAddr,
+    pub destination: HwAddr,
+    pub params: LinkParams,
+}
+
+/// A change of the parameters of a link during the run.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EventConfig {
+    /// Time since the start of the run, e.g. `10s` or `1500ms`.
+    pub at: String,
+    /// The endpoints of the link and the parameters that change, any others
+    /// keep their value.
+    pub link: LinkConfig,
+}
+
+/// A scenario kept apart from the topology, whose file is given by the
+/// `SCENARIO` environment variable.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    #[serde(default, rename = "event")]
+    pub events: Vec<EventConfig>,
+}
+
+impl Scenario {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("failed to parse {}", path.display()))
+    }
+}
+
+/// Path of the topology file passed to the runtime with `-t`.
+pub fn topology_path() -> Option<String> {
+    let mut args = std::env::args();
+    while let Some(arg) = args.next() {
+        if arg == "-t" {
+            return args.next();
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::IpAddr, path::PathBuf};
+
+    use super::*;
+    use crate::qdisc::QdiscConfig;
+
+    fn example(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join(name)
+    }
+
+    const FULL: &str = r#"
+        [node1]
+        port_type="xdp"
+        if_name="veth1"
+        queue_id=0
+        mac_addr="aa:00:00:00:00:00"
+
+        [node2]
+        port_type="xdp"
+        if_name="veth2"
+        queue_id=0
+        mac_addr="aa:00:00:00:00:01"
+
+        [node3]
+        port_type="xdp"
+        if_name="veth4"
+        queue_id=0
+        mac_addr="aa:00:00:00:00:02"
+
+        [[link]]
+        endpoints=["node1", "node2"]
+        bandwidth="100mbit"
+        burst="32kb"
+        qdisc="fq_codel target 5ms"
+        delay="10ms"
+        jitter="1ms"
+        loss="gemodel 1% 30%"
+        reorder="1%"
+        queue_size=1000
+
+        [[link]]
+        endpoints=["node1", "node3"]
+        duplicate="0.1%"
+        corrupt="0.1%"
+        markov_loss={ transitions=[[0.99, 0.01], [0.3, 0.7]], loss=[0, 1] }
+        mtu=1400
+        icmp_from=["10.0.0.254"]
+
+        [[event]]
+        at="10s"
+        link={ endpoints=["node2", "node1"], bandwidth="5mbit" }
+
+        [[static_mac]]
+        mac="bb:00:00:00:00:01"
+        node="node1"
+
+        [[port]]
+        node="node1"
+        mode="access"
+        vlan=10
+
+        [[port]]
+        node="node2"
+        mode="trunk"
+        vlans=[10, 20]
+        native_vlan=1
+
+        [[interface]]
+        node="node1"
+        addresses=["10.0.1.254/24", "fd00:1::1/64"]
+
+        [[interface]]
+        node="node3"
+        addresses=["10.0.3.254/24"]
+
+        [[route]]
+        prefix="10.0.4.0/24"
+        via="10.0.3.1"
+
+        [[host]]
+        node="node1"
+        netns="vnet0"
+        if_name="veth0"
+        addresses=["10.0.0.1/24"]
+    "#;
+
+    #[test]
+    fn full_topology() {
+        let topology: Topology = toml::from_str(FULL).unwrap();
+        assert_eq!(topology.nodes.len(), 3);
+        let links = topology.link_params().unwrap();
+        assert_eq!(links.len(), 4);
+        let node1 = topology.node_mac("node1").unwrap();
+        let node2 = topology.node_mac("node2").unwrap();
+        let shaper = links[&(node2, node1)].shaper.as_ref().unwrap();
+        assert!(matches!(shaper.qdisc, QdiscConfig::FqCodel(_)));
+        assert_eq!(
+            links[&(node1, node2)].netem.loss,
+            LossModel::gilbert_elliott(0.01, 0.3, 1.0, 0.0)
+        );
+        let node3 = topology.node_mac("node3").unwrap();
+        assert!(matches!(
+            links[&(node1, node3)].netem.loss,
+            LossModel::Markov { .. }
+        ));
+        assert_eq!(links[&(node3, node1)].mtu, Some(1400));
+        assert_eq!(
+            links[&(node3, node1)].icmp_from,
+            ["10.0.0.254".parse::<IpAddr>().unwrap()]
+        );
+        // The event only changes the bandwidth, in both directions.
+        let changes = topology.link_changes(&topology.events).unwrap();
+        assert_eq!(changes.len(), 2);
+        for change in changes {
+            assert_eq!(change.at, Duration::from_secs(10));
+            assert_eq!(change.params.netem.latency, Duration::from_millis(10));
+            assert!(change.params.shaper.is_some());
+        }
+        let static_macs = topology.static_macs().unwrap();
+        assert_eq!(static_macs.len(), 1);
+        assert_eq!(
+            static_macs[&"bb:00:00:00:00:01".parse::<HwAddr>().unwrap()],
+            node1
+        );
+        let vlans = topology.vlan_config().unwrap();
+        assert_eq!(vlans.mode(node1), &PortMode::Access(10));
+        assert_eq!(vlans.mode(node2).egress_tag(10), Some(10));
+        assert_eq!(vlans.mode(node2).egress_tag(1), None);
+        assert!(!vlans.mode(node3).carries(10));
+        // The connected networks and the static route, which goes through
+        // the interface of its gateway.
+        let router = topology.router_config().unwrap();
+        assert_eq!(router.interfaces.len(), 2);
+        let route = router.routes.lookup("10.0.4.1".parse().unwrap()).unwrap();
+        assert_eq!(route.port, node3);
+        let route = router.routes.lookup("fd00:1::2".parse().unwrap()).unwrap();
+        assert_eq!(route.port, node1);
+        assert_eq!(topology.hosts(None).unwrap().len(), 1);
+        // The other tables are not nodes to the runtime, which is given
+        // the nodes alone.
+        assert!(toml::from_str::<BTreeMap<String, NodeConfig>>(FULL).is_err());
+        let nodes: BTreeMap<String, NodeConfig> =
+            toml::from_str(&topology.nodes_toml().unwrap()).unwrap();
+        assert!(nodes.keys().eq(topology.nodes.keys()));
+    }
+
+    #[test]
+    fn local_env() {
+        let topology = Topology::load(example("local_env.toml")).unwrap();
+        assert!(topology.nodes.keys().eq(["node1", "node2"]));
+        assert_eq!(topology.link_params().unwrap().len(), 2);
+        assert_eq!(topology.hosts(None).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn remote_file() {
+        let topology = Topology::load(example("remote_env.toml")).unwrap();
+        let runtimes = topology.runtime_nodes().unwrap();
+        assert_eq!(runtimes["host1"], ["node1"]);
+        assert_eq!(runtimes["host2"], ["node2"]);
+        assert_eq!(topology.hosts(Some("host1")).unwrap().len(), 1);
+    }
+}
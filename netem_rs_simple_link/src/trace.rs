@@ -0,0 +1,155 @@
+//! Delivery opportunity traces in the format of Mahimahi.
+//!
+//! Every line of a trace is a timestamp in milliseconds at which one
+//! MTU-sized frame can leave the link. A timestamp may repeat to allow several
+//! frames in the same millisecond. The trace loops once its last timestamp is
+//! reached, so its last timestamp is its period.
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+
+/// Bytes that can leave the link at each delivery opportunity, a full
+/// Ethernet frame of a 1500 bytes MTU.
+pub const OPPORTUNITY_SIZE: usize = 1514;
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Trace {
+    /// Sorted timestamps of the opportunities in milliseconds.
+    opportunities: Vec<u64>,
+    period: u64,
+    /// The file the trace was loaded from.
+    path: Option<PathBuf>,
+}
+
+impl fmt::Debug for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Trace")
+            .field("path", &self.path)
+            .field("opportunities", &self.opportunities.len())
+            .field("period", &self.period)
+            .finish()
+    }
+}
+
+impl Trace {
+    pub fn new(opportunities: Vec<u64>) -> anyhow::Result<Self> {
+        if opportunities.windows(2).any(|w| w[0] > w[1]) {
+            bail!("timestamps of a trace must not decrease");
+        }
+        let period = match opportunities.last() {
+            Some(&period) if period > 0 => period,
+            _ => bail!("a trace needs a timestamp after 0ms"),
+        };
+        Ok(Self {
+            opportunities,
+            period,
+            path: None,
+        })
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let opportunities = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .enumerate()
+            .map(|(i, line)| {
+                line.parse()
+                    .with_context(|| format!("invalid timestamp {line} on line {}", i + 1))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let mut trace = Self::new(opportunities)
+            .with_context(|| format!("invalid trace {}", path.display()))?;
+        trace.path = Some(path.to_owned());
+        Ok(trace)
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn period(&self) -> Duration {
+        Duration::from_millis(self.period)
+    }
+
+    /// The average rate of the trace in bits per second.
+    pub fn mean_rate(&self) -> u64 {
+        self.opportunities.len() as u64 * OPPORTUNITY_SIZE as u64 * 8 * 1000 / self.period
+    }
+
+    /// Number of opportunities up to `elapsed` since the start of the trace.
+    pub fn opportunities(&self, elapsed: Duration) -> u64 {
+        let ms = elapsed.as_millis() as u64;
+        let loops = ms / self.period;
+        let offset = ms % self.period;
+        let within = self.opportunities.partition_point(|&t| t <= offset);
+        loops * self.opportunities.len() as u64 + within as u64
+    }
+
+    /// Time since the start of the trace of the opportunity following the
+    /// first `n`.
+    pub fn next_opportunity(&self, n: u64) -> Duration {
+        let len = self.opportunities.len() as u64;
+        let ms = n / len * self.period + self.opportunities[(n % len) as usize];
+        Duration::from_millis(ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_and_opportunities() {
+        // Two frames at 1ms, one at 2ms and 5ms, looping every 5ms.
+        let trace = Trace::new(vec![1, 1, 2, 5]).unwrap();
+        assert_eq!(trace.period(), Duration::from_millis(5));
+        assert_eq!(trace.mean_rate(), 4 * 1514 * 8 * 1000 / 5);
+        let opportunities = |ms| trace.opportunities(Duration::from_millis(ms));
+        assert_eq!(opportunities(0), 0);
+        assert_eq!(opportunities(1), 2);
+        assert_eq!(opportunities(4), 3);
+        assert_eq!(opportunities(5), 4);
+        assert_eq!(opportunities(6), 6);
+        assert_eq!(opportunities(10), 8);
+        let next = |n| trace.next_opportunity(n).as_millis();
+        assert_eq!([next(0), next(1), next(2), next(3)], [1, 1, 2, 5]);
+        assert_eq!([next(4), next(7), next(8)], [6, 10, 11]);
+    }
+
+    #[test]
+    fn every_millisecond() {
+        // One frame per millisecond is 12.112 Mbit/s.
+        let trace = Trace::new((1..=1000).collect()).unwrap();
+        assert_eq!(trace.mean_rate(), 12_112_000);
+        assert_eq!(trace.opportunities(Duration::from_micros(2500)), 2);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Trace::new(vec![]).is_err());
+        assert!(Trace::new(vec![0, 0]).is_err());
+        assert!(Trace::new(vec![2, 1]).is_err());
+    }
+
+    #[test]
+    fn load() {
+        let path = std::env::temp_dir().join(format!("trace-{}.up", std::process::id()));
+        std::fs::write(&path, "1\n\n 2 \n4\n").unwrap();
+        let trace = Trace::load(&path).unwrap();
+        assert_eq!(trace.path(), Some(path.as_path()));
+        assert_eq!(trace.opportunities(Duration::from_millis(4)), 3);
+        std::fs::write(&path, "1\nx\n").unwrap();
+        let error = format!("{:#}", Trace::load(&path).unwrap_err());
+        assert!(error.contains("line 2"), "{error}");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
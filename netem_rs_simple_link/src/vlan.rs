@@ -0,0 +1,157 @@
+//! 802.1Q VLANs of the bridge.
+//!
+//! Once some port of the topology has a VLAN mode, the bridge is VLAN-aware:
+//! every frame belongs to the VLAN its ingress port gives it, addresses are
+//! learned and frames flooded within that VLAN only, and frames are tagged
+//! or untagged on their way out as the egress port wants them. Ports without
+//! a mode are access ports of VLAN 1. Otherwise tags are left alone and
+//! every frame is in VLAN 0.
+
+use std::collections::HashMap;
+
+use hwaddr::HwAddr;
+
+use crate::ecn::ETHERTYPE_VLAN;
+
+/// A VLAN identifier, 0 when the bridge is not VLAN-aware.
+pub type Vid = u16;
+
+/// VLAN of the ports without a mode.
+pub const DEFAULT_VLAN: Vid = 1;
+pub const MAX_VID: Vid = 4094;
+
+const TAG_LEN: usize = 4;
+const VID_MASK: u16 = 0x0fff;
+
+static DEFAULT_MODE: PortMode = PortMode::Access(DEFAULT_VLAN);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PortMode {
+    /// Untagged frames of one VLAN.
+    Access(Vid),
+    /// Tagged frames of the `allowed` VLANs, and untagged frames of the
+    /// `native` one if any.
+    Trunk {
+        allowed: Vec<Vid>,
+        native: Option<Vid>,
+    },
+}
+
+impl PortMode {
+    /// The VLAN of a frame received with the VLAN tag `tag`, `None` if the
+    /// port does not admit it. Priority-tagged frames, whose VID is 0, count
+    /// as untagged.
+    pub fn ingress(&self, tag: Option<Vid>) -> Option<Vid> {
+        match (self, tag) {
+            (PortMode::Access(vid), None | Some(0)) => Some(*vid),
+            (PortMode::Access(vid), Some(tag)) => (tag == *vid).then_some(tag),
+            (PortMode::Trunk { native, .. }, None | Some(0)) => *native,
+            (PortMode::Trunk { allowed, .. }, Some(tag)) => allowed.contains(&tag).then_some(tag),
+        }
+    }
+
+    /// Whether frames of `vid` go out through the port.
+    pub fn carries(&self, vid: Vid) -> bool {
+        match self {
+            PortMode::Access(access) => *access == vid,
+            PortMode::Trunk { allowed, native } => *native == Some(vid) || allowed.contains(&vid),
+        }
+    }
+
+    /// The tag frames of `vid` go out with, `None` for untagged.
+    pub fn egress_tag(&self, vid: Vid) -> Option<Vid> {
+        match self {
+            PortMode::Access(_) => None,
+            PortMode::Trunk { native, .. } => (*native != Some(vid)).then_some(vid),
+        }
+    }
+}
+
+/// The VLAN mode of the ports, keyed by the MAC of their node.
+#[derive(Clone, Debug, Default)]
+pub struct VlanConfig {
+    pub ports: HashMap<HwAddr, PortMode>,
+}
+
+impl VlanConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.ports.is_empty()
+    }
+
+    pub fn mode(&self, port: HwAddr) -> &PortMode {
+        self.ports.get(&port).unwrap_or(&DEFAULT_MODE)
+    }
+
+    /// The ports of `ports` that carry `vid`, with the tag the frames of
+    /// `vid` go out through them with.
+    pub fn members<'a>(
+        &'a self,
+        ports: &'a [HwAddr],
+        vid: Vid,
+    ) -> impl Iterator<Item = (HwAddr, Option<Vid>)> + 'a {
+        ports.iter().filter_map(move |&port| {
+            let mode = self.mode(port);
+            mode.carries(vid).then(|| (port, mode.egress_tag(vid)))
+        })
+    }
+}
+
+/// The VID of the outer 802.1Q tag of `frame`, if it has one.
+pub fn tag(frame: &[u8]) -> Option<Vid> {
+    let tag = frame.get(12..16)?;
+    if u16::from_be_bytes([tag[0], tag[1]]) != ETHERTYPE_VLAN {
+        return None;
+    }
+    Some(u16::from_be_bytes([tag[2], tag[3]]) & VID_MASK)
+}
+
+/// A copy of `frame` with the VLAN tag `vid`, or without its tag for `None`.
+/// The priority of the tag the frame had is kept.
+pub fn retag(frame: &[u8], vid: Option<Vid>) -> Vec<u8> {
+    let (priority, payload) = match tag(frame) {
+        Some(_) => (
+            u16::from_be_bytes([frame[14], frame[15]]) & !VID_MASK,
+            &frame[12 + TAG_LEN..],
+        ),
+        None => (0, &frame[12..]),
+    };
+    let mut data = Vec::with_capacity(frame.len() + TAG_LEN);
+    data.extend_from_slice(&frame[..12]);
+    if let Some(vid) = vid {
+        data.extend_from_slice(&ETHERTYPE_VLAN.to_be_bytes());
+        data.extend_from_slice(&(priority | vid).to_be_bytes());
+    }
+    data.extend_from_slice(payload);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn members_of_a_vlan() {
+        let port = |n| HwAddr::from([0xaa, 0, 0, 0, 0, n]);
+        let vlans = VlanConfig {
+            ports: HashMap::from([
+                (port(1), PortMode::Access(10)),
+                (port(2), PortMode::Access(20)),
+                (
+                    port(3),
+                    PortMode::Trunk {
+                        allowed: vec![10, 20],
+                        native: Some(DEFAULT_VLAN),
+                    },
+                ),
+            ]),
+        };
+        let ports: Vec<_> = (1..=4).map(port).collect();
+        let members = |vid| vlans.members(&ports, vid).collect::<Vec<_>>();
+        // A frame of one VLAN never reaches the access port of another.
+        assert_eq!(members(10), [(port(1), None), (port(3), Some(10))]);
+        assert_eq!(members(20), [(port(2), None), (port(3), Some(20))]);
+        // Ports without a mode are in the default VLAN.
+        assert_eq!(members(DEFAULT_VLAN), [(port(3), None), (port(4), None)]);
+        assert!(members(30).is_empty());
+    }
+}
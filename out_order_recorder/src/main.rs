@@ -1,4 +1,11 @@
-use std::{process::exit, sync::Mutex, vec};
+use std::{
+    process::exit,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    vec,
+};
 
 use async_xdp::{
     config::{LibxdpFlags, SocketConfig, UmemConfig},
@@ -66,9 +73,45 @@ fn create_cxt(if_name: &str, queue: u32, custom_xdp_prog: bool) -> XdpContext {
 
 static PKT_RECORD: Lazy<Mutex<Vec<usize>>> = Lazy::new(|| Mutex::new(Vec::new()));
 
+/// Ethertype of the frames sent by the client.
+const PROTOCOL: u16 = 5401;
+/// Size of the id at the start of the payload.
+const ID_LEN: usize = 4;
+
+/// Frames the server dropped, by reason: shorter than an Ethernet header,
+/// not sent by the client, or with a payload shorter than an id.
+static TOO_SHORT: AtomicU64 = AtomicU64::new(0);
+static BAD_ETHERTYPE: AtomicU64 = AtomicU64::new(0);
+static TRUNCATED: AtomicU64 = AtomicU64::new(0);
+
+/// The id carried by a frame of the client.
+fn parse_id(data: &[u8]) -> Option<u32> {
+    let Ok(pkt) = Packet::new(data) else {
+        TOO_SHORT.fetch_add(1, Ordering::Relaxed);
+        return None;
+    };
+    if pkt.protocol() != Protocol::Unknown(PROTOCOL) {
+        BAD_ETHERTYPE.fetch_add(1, Ordering::Relaxed);
+        return None;
+    }
+    match pkt.payload().get(..ID_LEN) {
+        Some(id) => Some(u32::from_be_bytes(id.try_into().unwrap())),
+        None => {
+            TRUNCATED.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+    }
+}
+
 async fn server(_count: u32, _pkt_size: u32) {
     ctrlc::set_handler(|| {
         println!("Receive record len: {}", PKT_RECORD.lock().unwrap().len());
+        println!(
+            "Dropped frames: {} too short, {} bad ethertype, {} truncated payload",
+            TOO_SHORT.load(Ordering::Relaxed),
+            BAD_ETHERTYPE.load(Ordering::Relaxed),
+            TRUNCATED.load(Ordering::Relaxed)
+        );
         println!(
             "Receive record: {:?}",
             PKT_RECORD
@@ -89,9 +132,10 @@ async fn server(_count: u32, _pkt_size: u32) {
         let frames = recv_handle.receive().await.unwrap();
         for frame in frames {
             let data = frame.data_ref();
-            let pkt = Packet::new(data).unwrap();
-            println!("Receive pkt: {:?}", pkt);
-            let id = u32::from_be_bytes(pkt.payload().to_vec()[0..4].try_into().unwrap());
+            let Some(id) = parse_id(data.as_ref()) else {
+                continue;
+            };
+            println!("Receive pkt: {:?}", Packet::new(data.as_ref()).unwrap());
             PKT_RECORD.lock().unwrap().push(id as usize);
         }
     }
@@ -105,14 +149,14 @@ async fn client(count: u32, pkt_size: u32, conf: ini::Ini) {
     let context = create_cxt("ens2f1", 0, true);
     let send_handle = context.send_handle();
     for i in 0..count {
-        let mut payload = vec![0u8; pkt_size as usize];
-        payload[0..4].copy_from_slice(&i.to_be_bytes());
+        let mut payload = vec![0u8; (pkt_size as usize).max(ID_LEN)];
+        payload[0..ID_LEN].copy_from_slice(&i.to_be_bytes());
         let pkt = ether::Builder::default()
             .source(self_mac.parse::<HwAddr>().unwrap())
             .unwrap()
             .destination(dst_mac.parse::<HwAddr>().unwrap())
             .unwrap()
-            .protocol(Protocol::Unknown(5401))
+            .protocol(Protocol::Unknown(PROTOCOL))
             .unwrap()
             .payload(payload.as_slice())
             .unwrap()
@@ -2,6 +2,8 @@
 #
 #/bin/bash
 
+SIMPLE_LINK=../netem_rs_simple_link
+
 _print_help() {
     echo "This is a script to setup/tear down the test enviroment"
     echo "env up --- set up the env"
@@ -9,28 +11,14 @@ _print_help() {
 }
 
 up() {
-    sudo ip link add veth0 type veth peer name veth1
-    sudo ip netns add vnet0
-    sudo ifconfig veth0 hw ether aa:00:00:00:00:00
-    sudo ip link set veth0 netns vnet0
-    sudo ip -n vnet0 link set veth0 up
-    sudo ip link set veth1 up
-    sudo ip -n vnet0 addr add 10.0.0.1/24 dev veth0
-
-    # off the rx check
-    sudo ip netns exec vnet0 ethtool --offload veth0 rx off tx off
+    # the same env as the remote scripts of netem_rs_simple_link, see the
+    # host array of its remote_env.toml
+    cargo build --release --manifest-path "$SIMPLE_LINK/Cargo.toml" --bin testenv
+    sudo "$SIMPLE_LINK/target/release/testenv" up "$SIMPLE_LINK/remote_env.toml" --runtime host1
 }
 
 down() {
-    # down env
-    sudo ip -n vnet0 link set veth0 down
-    sudo ip link set veth1 down
-
-    # rm interface
-    sudo ip link del veth1
-
-    # rm netns
-    sudo ip netns del vnet0
+    sudo "$SIMPLE_LINK/target/release/testenv" down "$SIMPLE_LINK/remote_env.toml" --runtime host1
 }
 
 
@@ -10,6 +10,28 @@ use packet::{
 };
 use std::{convert::TryInto, str};
 
+/// Ethertype of the frames carrying a frame of the veth over the NIC.
+const TUNNEL_PROTOCOL: u16 = 5401;
+const ETHER_HEADER_LEN: usize = 14;
+
+/// Frames received from the NIC that could not be passed to the veth, by
+/// reason.
+#[derive(Debug, Default)]
+struct Drops {
+    /// Shorter than an Ethernet header.
+    too_short: u64,
+    /// Not a tunnel frame.
+    bad_ethertype: u64,
+    /// A tunnel frame whose payload is shorter than an Ethernet header.
+    truncated: u64,
+}
+
+impl Drops {
+    fn total(&self) -> u64 {
+        self.too_short + self.bad_ethertype + self.truncated
+    }
+}
+
 fn create_cxt(
     if_name: &str,
     queue: u32,
@@ -61,7 +83,7 @@ async fn veth_to_eth(
             .unwrap()
             .destination(dst_addr)
             .unwrap()
-            .protocol(Protocol::Unknown(5401))
+            .protocol(Protocol::Unknown(TUNNEL_PROTOCOL))
             .unwrap()
             .payload(origin_pkt)
             .unwrap()
@@ -76,12 +98,24 @@ async fn veth_to_eth(
 async fn eth_to_veth(
     eth_recev_handle: &mut XdpReceiveHandle,
     veth_send_handle: &XdpSendHandle,
+    drops: &mut Drops,
 ) -> Result<usize, String> {
     let mut total_bytes = 0;
     let frames = eth_recev_handle.receive().await.unwrap();
     for frame in frames {
         let data = frame.data_ref();
-        let pkt = Packet::new(data.as_ref()).unwrap();
+        let Ok(pkt) = Packet::new(data.as_ref()) else {
+            drops.too_short += 1;
+            continue;
+        };
+        if pkt.protocol() != Protocol::Unknown(TUNNEL_PROTOCOL) {
+            drops.bad_ethertype += 1;
+            continue;
+        }
+        if pkt.payload().len() < ETHER_HEADER_LEN {
+            drops.truncated += 1;
+            continue;
+        }
         let ori_pkt = pkt.payload().to_vec();
         total_bytes += ori_pkt.len();
         veth_send_handle.send(ori_pkt).unwrap();
@@ -147,9 +181,10 @@ async fn main() {
 
     let join2 = tokio::spawn(async move {
         let mut total_bytes = 0;
+        let mut drops = Drops::default();
         let mut last_time = std::time::Instant::now();
         loop {
-            total_bytes += eth_to_veth(&mut eth_receive_handle, &veth_send_handle)
+            total_bytes += eth_to_veth(&mut eth_receive_handle, &veth_send_handle, &mut drops)
                 .await
                 .unwrap();
             let now = std::time::Instant::now();
@@ -159,6 +194,10 @@ async fn main() {
                     "eth -> veth total_speed: {} mbytes/s",
                     (total_bytes as u64) / elaspe / 1000 / 1000
                 );
+                if drops.total() > 0 {
+                    log::warn!("eth -> veth dropped malformed frames: {:?}", drops);
+                    drops = Drops::default();
+                }
                 total_bytes = 0;
                 last_time = now;
             }
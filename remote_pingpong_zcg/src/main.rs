@@ -7,6 +7,28 @@ use hwaddr::HwAddr;
 use packet::ether::{Packet, Protocol};
 use std::{convert::TryInto, str};
 
+/// Ethertype of the frames carrying a frame of the veth over the NIC.
+const TUNNEL_PROTOCOL: u16 = 5401;
+const ETHER_HEADER_LEN: usize = 14;
+
+/// Frames received from the NIC that could not be passed to the veth, by
+/// reason.
+#[derive(Debug, Default)]
+struct Drops {
+    /// Shorter than an Ethernet header.
+    too_short: u64,
+    /// Not a tunnel frame.
+    bad_ethertype: u64,
+    /// A tunnel frame whose payload is shorter than an Ethernet header.
+    truncated: u64,
+}
+
+impl Drops {
+    fn total(&self) -> u64 {
+        self.too_short + self.bad_ethertype + self.truncated
+    }
+}
+
 fn create_umem() -> (Umem, SlabManager) {
     let umem_config = UmemConfig::builder()
         .fill_queue_size((4096).try_into().unwrap())
@@ -63,7 +85,7 @@ async fn veth_to_eth(
             .unwrap()
             .set_source(self_addr)
             .unwrap()
-            .set_protocol(Protocol::Unknown(5401))
+            .set_protocol(Protocol::Unknown(TUNNEL_PROTOCOL))
             .unwrap();
 
         total_bytes += data.len();
@@ -75,9 +97,26 @@ async fn veth_to_eth(
 async fn eth_to_veth(
     eth_recev_handle: &mut XdpReceiveHandle,
     veth_send_handle: &XdpSendHandle,
+    drops: &mut Drops,
 ) -> Result<usize, String> {
     let mut total_bytes = 0;
     let mut frames = eth_recev_handle.receive().await.unwrap();
+    frames.retain(|frame| {
+        let data = frame.data_ref();
+        let Ok(pkt) = Packet::new(data.as_ref()) else {
+            drops.too_short += 1;
+            return false;
+        };
+        if pkt.protocol() != Protocol::Unknown(TUNNEL_PROTOCOL) {
+            drops.bad_ethertype += 1;
+            return false;
+        }
+        if pkt.payload().len() < ETHER_HEADER_LEN {
+            drops.truncated += 1;
+            return false;
+        }
+        true
+    });
     for frame in &mut frames {
         frame.adjust_head(14);
         total_bytes += frame.data_ref().len();
@@ -160,9 +199,10 @@ async fn main() {
 
     let join2 = tokio::spawn(async move {
         let mut total_bytes = 0;
+        let mut drops = Drops::default();
         let mut last_time = std::time::Instant::now();
         loop {
-            total_bytes += eth_to_veth(&mut eth_receive_handle, &veth_send_handle)
+            total_bytes += eth_to_veth(&mut eth_receive_handle, &veth_send_handle, &mut drops)
                 .await
                 .unwrap();
             let now = std::time::Instant::now();
@@ -172,6 +212,10 @@ async fn main() {
                     "eth -> veth total_speed: {} mbytes/s",
                     (total_bytes as u64) / elaspe / 1000 / 1000
                 );
+                if drops.total() > 0 {
+                    log::warn!("eth -> veth dropped malformed frames: {:?}", drops);
+                    drops = Drops::default();
+                }
                 total_bytes = 0;
                 last_time = now;
             }